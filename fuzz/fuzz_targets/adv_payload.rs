@@ -0,0 +1,23 @@
+#![no_main]
+
+use heapless::Vec;
+use libfuzzer_sys::fuzz_target;
+use trawm::adv::fix_adv_payload;
+
+fuzz_target!(|payload: &[u8]| {
+    let mut fixed = Vec::<u8, 256>::new();
+    if fix_adv_payload(payload, &mut fixed).is_err() {
+        return;
+    }
+    // The result is made of complete AD structures
+    let mut pos = 0;
+    while pos < fixed.len() {
+        pos += 1 + fixed[pos] as usize;
+    }
+    assert_eq!(pos, fixed.len());
+
+    // Fixed payloads are left untouched
+    let mut refixed = Vec::<u8, 256>::new();
+    fix_adv_payload(&fixed, &mut refixed).unwrap();
+    assert_eq!(fixed, refixed);
+});
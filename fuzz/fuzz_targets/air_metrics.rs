@@ -0,0 +1,15 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use trawm::metrics::AirMetrics;
+
+fuzz_target!(|bytes: &[u8]| {
+    let Ok(metrics) = AirMetrics::from_bytes(bytes) else {
+        return;
+    };
+    // Valid packets survive the round trip, except for the padding
+    let mut expected = [0; AirMetrics::PACKET_LEN];
+    expected.copy_from_slice(&bytes[..AirMetrics::PACKET_LEN]);
+    expected[3] = 0;
+    assert_eq!(metrics.to_bytes(), expected);
+});
@@ -0,0 +1,20 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use trawm::alert::{AlertRule, AlertStates};
+
+fuzz_target!(|bytes: &[u8]| {
+    // Whatever is stored, the states load and round trip
+    let states = AlertStates::from_bytes(bytes);
+    assert_eq!(AlertStates::from_bytes(&states.to_bytes()), states);
+    if let Some(rule) = AlertRule::from_bytes(bytes) {
+        assert_eq!(AlertRule::from_bytes(&rule.to_bytes()), Some(rule));
+    }
+    if let Ok(text) = core::str::from_utf8(bytes) {
+        if let Ok(rule) = AlertRule::parse(text) {
+            let mut shown = heapless::String::<160>::new();
+            let _ = core::fmt::write(&mut shown, format_args!("{}", rule));
+            assert!(AlertRule::parse(&shown).is_ok());
+        }
+    }
+});
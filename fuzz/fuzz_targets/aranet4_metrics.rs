@@ -0,0 +1,15 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use trawm::metrics::AirMetrics;
+
+fuzz_target!(|bytes: &[u8]| {
+    let Ok(metrics) = AirMetrics::from_aranet4_bytes(bytes) else {
+        return;
+    };
+    // Every packet long enough parses, and is kept in the history without the Airthings fields
+    assert!(bytes.len() >= AirMetrics::ARANET4_PACKET_LEN);
+    let stored = AirMetrics::from_bytes(&metrics.to_bytes()).unwrap();
+    assert!(stored.radon_short.is_none() && stored.voc_level.is_none());
+    assert_eq!(stored.co2_level, metrics.co2_level);
+});
@@ -0,0 +1,23 @@
+#![no_main]
+
+use core::convert::Infallible;
+use libfuzzer_sys::fuzz_target;
+use trawm::particulates::{parse_pmsa003i_frame, parse_sen5x_pm_values, PmReading};
+
+fuzz_target!(|bytes: &[u8]| {
+    let check = |reading: PmReading| {
+        // Unsigned words, scaled down
+        for value in [reading.pm1, reading.pm2_5, reading.pm10] {
+            assert!((0.0..=u16::MAX as f32).contains(&value));
+        }
+        assert!(reading.counts.iter().all(|c| c.per_cm3 >= 0.0));
+    };
+    if let Ok(reading) = parse_pmsa003i_frame::<Infallible>(bytes) {
+        assert_eq!(reading.counts.len(), 6);
+        check(reading);
+    }
+    if let Ok(reading) = parse_sen5x_pm_values::<Infallible>(bytes) {
+        assert_eq!(reading.counts.len(), 5);
+        check(reading);
+    }
+});
@@ -0,0 +1,22 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use trawm::ruuvi::RuuviData;
+
+fuzz_target!(|bytes: &[u8]| {
+    let Ok(data) = RuuviData::from_bytes(bytes) else {
+        return;
+    };
+    // The values stay in the ranges of the format, the sentinels are never decoded as readings
+    assert!(data
+        .temperature
+        .map_or(true, |t| (-163.835..=163.835).contains(&t)));
+    assert!(data.humidity.map_or(true, |h| (0.0..=163.835).contains(&h)));
+    assert!(data
+        .pressure
+        .map_or(true, |p| (500.0..=1155.34).contains(&p)));
+    assert!(data.battery_mv.map_or(true, |v| (1600..=3646).contains(&v)));
+    assert!(data.tx_power.map_or(true, |p| (-40..=20).contains(&p)));
+    assert_ne!(data.movement_counter, Some(u8::MAX));
+    assert_ne!(data.sequence, Some(u16::MAX));
+});
@@ -0,0 +1,20 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use trawm::profile::{find_sensor, PROFILES};
+
+fuzz_target!(|payload: &[u8]| {
+    // Every profile copes with any advertisement and anything read from a characteristic
+    for profile in PROFILES {
+        if let Some(data) = profile.match_adv(payload) {
+            let _ = profile.decode_adv(data);
+            let _ = profile.serial(data);
+        }
+        let _ = profile.decode_read(payload);
+    }
+    // The first match wins
+    if let Some((profile, _)) = find_sensor(payload) {
+        let first = PROFILES.iter().find(|p| p.match_adv(payload).is_some());
+        assert_eq!(first.map(|p| p.name()), Some(profile.name()));
+    }
+});
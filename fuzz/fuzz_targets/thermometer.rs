@@ -0,0 +1,15 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use trawm::xiaomi::{ThermometerData, ATC_PACKET_LEN};
+
+fuzz_target!(|bytes: &[u8]| {
+    // The BTHome objects are walked without reading past the end
+    let _ = ThermometerData::from_bthome_bytes(bytes);
+    let Ok(data) = ThermometerData::from_service_data(bytes) else {
+        return;
+    };
+    // Both custom formats report everything
+    assert!(bytes.len() >= ATC_PACKET_LEN);
+    assert!(data.battery.is_some() && data.battery_mv.is_some() && data.counter.is_some());
+});
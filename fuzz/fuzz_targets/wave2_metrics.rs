@@ -0,0 +1,18 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use trawm::metrics::AirMetrics;
+
+fuzz_target!(|bytes: &[u8]| {
+    let Ok(metrics) = AirMetrics::from_wave2_bytes(bytes) else {
+        return;
+    };
+    // Only the fields shared with the Wave Plus are decoded, and they survive the round trip
+    assert!(
+        metrics.pressure.is_none() && metrics.co2_level.is_none() && metrics.voc_level.is_none()
+    );
+    let mut expected = [0; 10];
+    expected.copy_from_slice(&bytes[..10]);
+    expected[3] = 0;
+    assert_eq!(metrics.to_bytes()[..10], expected);
+});
@@ -0,0 +1,138 @@
+//! Advertisement payload handling that doesn't depend on the BLE stack
+use heapless::Vec;
+
+/// Bluetooth SIG company identifier of Airthings
+pub const AIRTHINGS_COMPANY_ID: u16 = 0x0334;
+/// Bluetooth SIG company identifier of SAF Tehnika, the maker of the Aranet4
+pub const SAF_TEHNIKA_COMPANY_ID: u16 = 0x0702;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum AdvPayloadError {
+    /// The fixed payload doesn't fit into the result buffer
+    Overflow,
+}
+
+/// Copies AD structures from `payload` into `result` (which is expected to be empty),
+/// zero-padding the last structure if it's truncated.
+/// Stops at the first zero-length structure.
+pub fn fix_adv_payload<const N: usize>(
+    payload: &[u8],
+    result: &mut Vec<u8, N>,
+) -> Result<(), AdvPayloadError> {
+    // Workaround for bug https://github.com/embassy-rs/trouble/issues/137
+    let mut pos: usize = 0;
+    while let Some(&len_byte) = payload.get(pos) {
+        let chunk_len = len_byte as usize;
+        if chunk_len == 0 {
+            break;
+        }
+        result
+            .push(len_byte)
+            .map_err(|_| AdvPayloadError::Overflow)?;
+        pos += 1;
+
+        let chunk = &payload[pos..payload.len().min(pos + chunk_len)];
+        result
+            .extend_from_slice(chunk)
+            .map_err(|_| AdvPayloadError::Overflow)?;
+        if chunk.len() < chunk_len {
+            // Probably at the end
+            for _ in chunk.len()..chunk_len {
+                result.push(0).map_err(|_| AdvPayloadError::Overflow)?;
+            }
+            break;
+        }
+        pos += chunk_len;
+    }
+    Ok(())
+}
+
+/// Payload of the manufacturer specific data (AD type 0xFF) of the given company
+pub fn manufacturer_data(payload: &[u8], company_identifier: u16) -> Option<&[u8]> {
+    manufacturer_structures(payload)
+        .find(|(company, _)| *company == company_identifier)
+        .map(|(_, company_data)| company_data)
+}
+
+/// Payload of the service data (AD type 0x16) of the given 16 bit service UUID
+pub fn service_data(payload: &[u8], uuid: u16) -> Option<&[u8]> {
+    service_structures(payload)
+        .find(|(service, _)| *service == uuid)
+        .map(|(_, service_data)| service_data)
+}
+
+/// Company identifiers and payloads of the manufacturer specific data structures
+fn manufacturer_structures(payload: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    ad_structures(payload).filter_map(|structure| match structure {
+        [0xff, lo, hi, company_data @ ..] => Some((u16::from_le_bytes([*lo, *hi]), company_data)),
+        _ => None,
+    })
+}
+
+/// 16 bit UUIDs and payloads of the service data structures (AD type 0x16)
+fn service_structures(payload: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    ad_structures(payload).filter_map(|structure| match structure {
+        [0x16, lo, hi, service_data @ ..] => Some((u16::from_le_bytes([*lo, *hi]), service_data)),
+        _ => None,
+    })
+}
+
+/// AD type followed by the data of each structure, up to the first truncated one
+fn ad_structures(payload: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut rest = payload;
+    core::iter::from_fn(move || {
+        let [len, data @ ..] = rest else {
+            return None;
+        };
+        let len = *len as usize;
+        let structure = data.get(..len)?;
+        rest = &data[len..];
+        Some(structure)
+    })
+}
+
+/// Airthings devices advertise their serial number as the first 4 bytes of the manufacturer data
+pub fn airthings_serial(company_data: &[u8]) -> Option<u32> {
+    match company_data {
+        [a, b, c, d, ..] => Some(u32::from_le_bytes([*a, *b, *c, *d])),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    proptest! {
+        #[test]
+        fn fixed_payload_is_well_formed(payload in prop::collection::vec(any::<u8>(), 0..64)) {
+            let mut result = Vec::<u8, 64>::new();
+            if fix_adv_payload(&payload, &mut result).is_ok() {
+                // Every structure is complete and none is empty
+                let mut pos = 0;
+                while pos < result.len() {
+                    prop_assert_ne!(result[pos], 0);
+                    pos += 1 + result[pos] as usize;
+                }
+                prop_assert_eq!(pos, result.len());
+                // Copied from the payload, then zero padded
+                let copied = result.len().min(payload.len());
+                prop_assert_eq!(&result[..copied], &payload[..copied]);
+                prop_assert!(result[copied..].iter().all(|b| *b == 0));
+            }
+        }
+
+        #[test]
+        fn well_formed_payload_is_kept(structures in prop::collection::vec(prop::collection::vec(any::<u8>(), 1..8), 0..4)) {
+            let payload: std::vec::Vec<u8> = structures
+                .iter()
+                .flat_map(|s| core::iter::once(s.len() as u8).chain(s.iter().copied()))
+                .collect();
+            let mut result = Vec::<u8, 64>::new();
+            fix_adv_payload(&payload, &mut result).unwrap();
+            prop_assert_eq!(&result[..], &payload[..]);
+        }
+    }
+}
@@ -0,0 +1,421 @@
+//! Threshold rules turning the readings into nudges, e.g. "CO2 1450 ppm - ventilate".
+//!
+//! A rule fires once its [`Quantity`] has been past the level for its duration, and clears once
+//! the value is back past the level by the hysteresis. The levels are in the units of
+//! [`Quantity::unit`] whatever the display units. Their state is kept in the storage with the
+//! time the value got past the level, from the [`Measurement::timestamp`]s, so the durations carry
+//! over the power-offs between the wake cycles. It's only written when a rule starts or stops
+//! pending or firing, which is rare.
+use core::fmt;
+use core::time::Duration;
+use heapless::Vec;
+
+use crate::measurement::{Measurement, MeasurementSet, Quantity};
+use crate::platform::{Slot, Storage};
+
+/// Rules that can be configured, see [`crate::config::ConfigKey::Alert1`]
+pub const ALERT_RULES: usize = 4;
+
+const STATE_VERSION: u8 = 2;
+/// Not pending
+const NO_PENDING: u32 = u32::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Comparator {
+    Above = 0,
+    Below = 1,
+}
+
+impl Comparator {
+    pub fn symbol(self) -> &'static str {
+        match self {
+            Self::Above => ">",
+            Self::Below => "<",
+        }
+    }
+
+    fn from_u8(comparator: u8) -> Option<Self> {
+        [Self::Above, Self::Below]
+            .into_iter()
+            .find(|c| *c as u8 == comparator)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+pub struct AlertRule {
+    pub quantity: Quantity,
+    pub comparator: Comparator,
+    /// In the unit of the quantity, see [`Quantity::unit`]
+    pub level: f32,
+    /// How far back from the level the value has to get to clear the alert
+    pub hysteresis: f32,
+    /// How long the value has to be past the level to fire
+    pub duration: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct ParseRuleError;
+
+impl AlertRule {
+    pub const PACKET_LEN: usize = 14;
+
+    /// Parses the text form `<quantity> <'>' or '<'> <level> [hysteresis] [seconds]`,
+    /// e.g. `co2 > 1400 100 600`
+    pub fn parse(text: &str) -> Result<Self, ParseRuleError> {
+        let mut words = text.split_whitespace();
+        let mut next = || words.next().ok_or(ParseRuleError);
+        let quantity = next()?;
+        let quantity = Quantity::ALL
+            .into_iter()
+            .find(|q| q.name() == quantity)
+            .ok_or(ParseRuleError)?;
+        let comparator = match next()? {
+            ">" => Comparator::Above,
+            "<" => Comparator::Below,
+            _ => return Err(ParseRuleError),
+        };
+        let number = |word: &str| word.parse::<f32>().ok().filter(|n| n.is_finite());
+        let level = number(next()?).ok_or(ParseRuleError)?;
+        let hysteresis = match words.next() {
+            Some(word) => number(word).filter(|h| *h >= 0.0).ok_or(ParseRuleError)?,
+            None => 0.0,
+        };
+        let duration = match words.next() {
+            Some(word) => Duration::from_secs(word.parse().map_err(|_| ParseRuleError)?),
+            None => Duration::ZERO,
+        };
+        if words.next().is_some() {
+            return Err(ParseRuleError);
+        }
+        Ok(Self {
+            quantity,
+            comparator,
+            level,
+            hysteresis,
+            duration,
+        })
+    }
+
+    /// Quantity index in [`Quantity::ALL`], comparator, then the little endian level,
+    /// hysteresis and duration in seconds
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let [quantity, comparator, rest @ ..] = bytes else {
+            return None;
+        };
+        let word = |i: usize| rest.get(i..i + 4)?.try_into().ok();
+        let level = f32::from_le_bytes(word(0)?);
+        let hysteresis = f32::from_le_bytes(word(4)?);
+        if !(level.is_finite() && hysteresis.is_finite() && hysteresis >= 0.0) {
+            return None;
+        }
+        Some(Self {
+            quantity: *Quantity::ALL.get(*quantity as usize)?,
+            comparator: Comparator::from_u8(*comparator)?,
+            level,
+            hysteresis,
+            duration: Duration::from_secs(u32::from_le_bytes(word(8)?) as u64),
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; Self::PACKET_LEN] {
+        let mut bytes = [0; Self::PACKET_LEN];
+        // Always in ALL
+        bytes[0] = Quantity::ALL
+            .iter()
+            .position(|q| *q == self.quantity)
+            .unwrap_or_default() as u8;
+        bytes[1] = self.comparator as u8;
+        bytes[2..6].copy_from_slice(&self.level.to_le_bytes());
+        bytes[6..10].copy_from_slice(&self.hysteresis.to_le_bytes());
+        let seconds = self.duration.as_secs().min(u32::MAX as u64) as u32;
+        bytes[10..].copy_from_slice(&seconds.to_le_bytes());
+        bytes
+    }
+
+    /// Whether the `value` is past the level
+    fn exceeded(&self, value: f32) -> bool {
+        match self.comparator {
+            Comparator::Above => value > self.level,
+            Comparator::Below => value < self.level,
+        }
+    }
+
+    /// Whether the `value` is back by the hysteresis
+    fn cleared(&self, value: f32) -> bool {
+        match self.comparator {
+            Comparator::Above => value < self.level - self.hysteresis,
+            Comparator::Below => value > self.level + self.hysteresis,
+        }
+    }
+
+    /// What to do about it, shown after the value
+    pub fn advice(&self) -> &'static str {
+        use Comparator::*;
+        use Quantity::*;
+        match (self.quantity, self.comparator) {
+            (Temperature | HeatIndex, Above) => "cool down",
+            (Temperature | HeatIndex, Below) => "heat up",
+            (Humidity, Above) => "dehumidify",
+            (Humidity, Below) => "humidify",
+            (Battery, Below) => "replace battery",
+            (Pm1 | Pm2_5 | Pm10, Above) => "filter the air",
+            (Co2 | Voc | RadonShortTerm | RadonLongTerm, Above) => "ventilate",
+            _ => "check",
+        }
+    }
+}
+
+/// The text form, see [`AlertRule::parse`]
+impl fmt::Display for AlertRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} {} {}",
+            self.quantity.name(),
+            self.comparator.symbol(),
+            self.level,
+            self.hysteresis,
+            self.duration.as_secs()
+        )
+    }
+}
+
+/// A firing rule with the measurement that fired it
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+pub struct Alert {
+    pub rule: AlertRule,
+    pub measurement: Measurement,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+struct RuleState {
+    firing: bool,
+    /// Since when the value has been past the level, before firing
+    pending_since: Option<Duration>,
+}
+
+/// State of each rule, kept with the rule it's about so it starts over when the rule is changed
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct AlertStates {
+    states: [(Option<AlertRule>, RuleState); ALERT_RULES],
+    /// Changed since loaded or saved
+    dirty: bool,
+}
+
+impl AlertStates {
+    const ENTRY_LEN: usize = 1 + AlertRule::PACKET_LEN + 1 + 4;
+    pub const MAX_SIZE: usize = 1 + ALERT_RULES * Self::ENTRY_LEN;
+
+    /// Reads the states from the storage. Starts over if they are missing or invalid
+    pub async fn load<S: Storage>(storage: &mut S) -> Self {
+        let mut buf = [0; Self::MAX_SIZE];
+        match storage.load(Slot::Alerts, &mut buf).await {
+            Ok(len) => Self::from_bytes(&buf[..len]),
+            Err(e) => {
+                defmt::error!("Alert states load failed: {:?}", defmt::Debug2Format(&e));
+                Self::default()
+            }
+        }
+    }
+
+    /// Writes the states if they changed
+    pub async fn save<S: Storage>(&mut self, storage: &mut S) -> Result<(), S::Error> {
+        if !self.dirty {
+            return Ok(());
+        }
+        storage.store(Slot::Alerts, &self.to_bytes()).await?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Version byte followed by an entry per rule: whether there's a rule, the rule,
+    /// whether it's firing and since when it's pending in seconds
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let mut states = Self::default();
+        let Some((&STATE_VERSION, entries)) = bytes.split_first() else {
+            return states;
+        };
+        for (state, entry) in states
+            .states
+            .iter_mut()
+            .zip(entries.chunks_exact(Self::ENTRY_LEN))
+        {
+            let (rule, rest) = entry.split_at(1 + AlertRule::PACKET_LEN);
+            let since = u32::from_le_bytes([rest[1], rest[2], rest[3], rest[4]]);
+            *state = (
+                (rule[0] != 0)
+                    .then(|| AlertRule::from_bytes(&rule[1..]))
+                    .flatten(),
+                RuleState {
+                    firing: rest[0] != 0,
+                    pending_since: (since != NO_PENDING).then(|| Duration::from_secs(since as u64)),
+                },
+            );
+        }
+        states
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8, { Self::MAX_SIZE }> {
+        let mut bytes = Vec::new();
+        // Always fits, MAX_SIZE is computed from the number of rules
+        let _ = bytes.push(STATE_VERSION);
+        for (rule, state) in &self.states {
+            let _ = bytes.push(rule.is_some() as u8);
+            let _ = bytes.extend_from_slice(&rule.map(|r| r.to_bytes()).unwrap_or_default());
+            let _ = bytes.push(state.firing as u8);
+            // Times that don't fit start pending over, rather than firing right away
+            let since = state
+                .pending_since
+                .and_then(|since| u32::try_from(since.as_secs()).ok())
+                .unwrap_or(NO_PENDING);
+            let _ = bytes.extend_from_slice(&since.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Evaluates the `rules` on the new `measurements`, returning the firing alerts. The rules
+    /// of the quantities not measured keep their state, those with a duration only start
+    /// pending on the measurements with a timestamp
+    pub fn update(
+        &mut self,
+        rules: &[Option<AlertRule>; ALERT_RULES],
+        measurements: &MeasurementSet,
+    ) -> Vec<Alert, ALERT_RULES> {
+        let mut alerts = Vec::new();
+        for (rule, (stored_rule, state)) in rules.iter().zip(self.states.iter_mut()) {
+            let before = (*stored_rule, *state);
+            if stored_rule != rule {
+                *stored_rule = *rule;
+                *state = RuleState::default();
+            }
+            let measurement = rule.and_then(|r| measurements.get(r.quantity));
+            if let (Some(rule), Some(measurement)) = (rule, measurement) {
+                let value = measurement.value;
+                if state.firing {
+                    state.firing = !rule.cleared(value);
+                } else if rule.exceeded(value) {
+                    let now = measurement.timestamp;
+                    // Starts over when the clock went back, e.g. after the RTC lost its power
+                    let since = state
+                        .pending_since
+                        .filter(|since| now.is_none_or(|now| *since <= now))
+                        .or(now);
+                    let pending = now
+                        .zip(since)
+                        .map_or(Duration::ZERO, |(now, since)| now - since);
+                    state.firing = pending >= rule.duration;
+                    state.pending_since = if state.firing { None } else { since };
+                } else {
+                    state.pending_since = None;
+                }
+                if state.firing {
+                    let _ = alerts.push(Alert {
+                        rule: *rule,
+                        measurement: *measurement,
+                    });
+                }
+            }
+            self.dirty |= before != (*stored_rule, *state);
+        }
+        alerts
+    }
+}
+
+/// Updates the alert states kept in the `storage` with the new `measurements`, see
+/// [`AlertStates::update`]
+pub async fn evaluate<S: Storage>(
+    storage: &mut S,
+    rules: &[Option<AlertRule>; ALERT_RULES],
+    measurements: &MeasurementSet,
+) -> Vec<Alert, ALERT_RULES> {
+    let mut states = AlertStates::load(storage).await;
+    let alerts = states.update(rules, measurements);
+    if let Err(e) = states.save(storage).await {
+        defmt::error!("Alert states save failed: {:?}", defmt::Debug2Format(&e));
+    }
+    alerts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(rule: &str) -> [Option<AlertRule>; ALERT_RULES] {
+        [None, Some(AlertRule::parse(rule).unwrap()), None, None]
+    }
+
+    /// CO2 measured at `seconds`
+    fn co2(ppm: f32, seconds: u64) -> MeasurementSet {
+        let mut set = MeasurementSet::new();
+        set.insert(Measurement::new(Quantity::Co2, ppm));
+        set.with_timestamp(Duration::from_secs(seconds))
+    }
+
+    #[test]
+    fn fires_once_past_the_level_for_the_duration() {
+        let rules = rules("co2 > 1400 100 600");
+        let mut states = AlertStates::default();
+        assert!(states.update(&rules, &co2(1450.0, 1000)).is_empty());
+        assert!(states.update(&rules, &co2(1500.0, 1400)).is_empty());
+        let alerts = states.update(&rules, &co2(1480.0, 1600));
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].measurement.value, 1480.0);
+        // Dropping below the level starts over
+        let mut states = AlertStates::default();
+        states.update(&rules, &co2(1450.0, 1000));
+        states.update(&rules, &co2(1300.0, 1300));
+        assert!(states.update(&rules, &co2(1450.0, 1700)).is_empty());
+    }
+
+    #[test]
+    fn clears_past_the_hysteresis() {
+        let rules = rules("co2 > 1400 100");
+        let mut states = AlertStates::default();
+        assert_eq!(states.update(&rules, &co2(1450.0, 0)).len(), 1);
+        assert_eq!(states.update(&rules, &co2(1350.0, 90)).len(), 1);
+        assert!(states.update(&rules, &co2(1299.0, 180)).is_empty());
+        assert!(states.update(&rules, &co2(1350.0, 270)).is_empty());
+    }
+
+    #[test]
+    fn changed_rules_start_over() {
+        let mut states = AlertStates::default();
+        assert_eq!(
+            states.update(&rules("co2 > 1400"), &co2(1450.0, 0)).len(),
+            1
+        );
+        let alerts = states.update(&rules("co2 > 1400 0 600"), &co2(1450.0, 90));
+        assert!(alerts.is_empty());
+    }
+
+    /// The pending state is only written when it starts, not at each wake
+    #[test]
+    fn pending_rules_are_saved_once() {
+        let rules = rules("co2 > 1400 100 600");
+        let mut states = AlertStates::default();
+        states.update(&rules, &co2(1450.0, 1000));
+        assert!(states.dirty);
+        let saved = states.to_bytes();
+        let mut states = AlertStates::from_bytes(&saved);
+        states.update(&rules, &co2(1460.0, 1090));
+        assert!(!states.dirty);
+        assert_eq!(states.update(&rules, &co2(1470.0, 1600)).len(), 1);
+        assert!(states.dirty);
+    }
+
+    #[test]
+    fn rtc_times_survive_a_reload() {
+        // 2026-10-19, as told by the RTC
+        const NOW: u64 = 1_792_368_000;
+        let rules = rules("co2 > 1400 100 600");
+        let mut states = AlertStates::default();
+        states.update(&rules, &co2(1450.0, NOW));
+        let mut states = AlertStates::from_bytes(&states.to_bytes());
+        assert!(states.update(&rules, &co2(1460.0, NOW + 90)).is_empty());
+        let (_, state) = &states.states[1];
+        assert_eq!(state.pending_since, Some(Duration::from_secs(NOW)));
+        assert!(!state.firing);
+        let mut states = AlertStates::from_bytes(&states.to_bytes());
+        assert_eq!(states.update(&rules, &co2(1470.0, NOW + 600)).len(), 1);
+    }
+}
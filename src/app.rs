@@ -0,0 +1,506 @@
+//! Wake-fetch-render-sleep cycle, independent from the hardware
+use core::time::Duration;
+
+use crate::alert::{self, AlertRule, ALERT_RULES};
+use crate::comfort::ComfortMetrics;
+use crate::led::{self, BlinkCode, LedSignal};
+use crate::link::LinkQuality;
+use crate::measurement::{Measurement, Quantity};
+use crate::platform::{Button, Clock, Display, MetricsSource, StatusLed, Storage, WakeAlarm};
+use crate::timing::{Phase, Stopwatch};
+use crate::units::UnitProfile;
+use crate::{history, screens};
+
+/// How long to sleep after a successful or a failed fetch and how to show the metrics
+#[derive(Debug, Clone, Copy)]
+pub struct CycleConfig {
+    pub interval: Duration,
+    pub retry_interval: Duration,
+    pub units: UnitProfile,
+    /// Meters above the sea level, see [`crate::config::Config::altitude`]
+    pub altitude: Option<i16>,
+    /// See [`crate::config::Config::alerts`]
+    pub alerts: [Option<AlertRule>; ALERT_RULES],
+    /// Append the fetched metrics to the history log
+    pub record_history: bool,
+}
+
+impl Default for CycleConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(90),
+            retry_interval: Duration::from_secs(10),
+            units: UnitProfile::default(),
+            altitude: None,
+            alerts: [None; ALERT_RULES],
+            record_history: true,
+        }
+    }
+}
+
+/// What a button press in the desk mode asks for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum DeskAction {
+    Refresh,
+    /// Switch between the metric and US units until the next reboot
+    ToggleUnits,
+}
+
+/// Always-on mode used while on USB power: short refresh interval adjusted with
+/// the Up/Down buttons, A and C refresh right away, B toggles the units
+#[derive(Debug, Clone, Copy)]
+pub struct DeskMode {
+    interval: usize,
+    /// Since the last cycle recording the history
+    unrecorded: Duration,
+}
+
+impl DeskMode {
+    pub const INTERVALS: [Duration; 5] = [
+        Duration::from_secs(10),
+        Duration::from_secs(30),
+        Duration::from_secs(60),
+        Duration::from_secs(120),
+        Duration::from_secs(300),
+    ];
+
+    pub fn new() -> Self {
+        Self {
+            interval: 1,
+            unrecorded: Duration::MAX,
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        Self::INTERVALS[self.interval]
+    }
+
+    /// Config of the next cycle, `base` is the one used on battery. The history is recorded
+    /// about as often as on battery, not on every refresh
+    pub fn cycle_config(&mut self, base: &CycleConfig) -> CycleConfig {
+        let record_history = self.unrecorded >= base.interval;
+        self.unrecorded = if record_history {
+            self.interval()
+        } else {
+            self.unrecorded + self.interval()
+        };
+        CycleConfig {
+            interval: self.interval(),
+            retry_interval: base.retry_interval.min(self.interval()),
+            record_history: record_history && base.record_history,
+            ..*base
+        }
+    }
+
+    pub fn press(&mut self, button: Button) -> DeskAction {
+        match button {
+            Button::Up => self.interval = self.interval.saturating_sub(1),
+            Button::Down => self.interval = (self.interval + 1).min(Self::INTERVALS.len() - 1),
+            Button::B => return DeskAction::ToggleUnits,
+            Button::A | Button::C => (),
+        }
+        DeskAction::Refresh
+    }
+}
+
+impl Default for DeskMode {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug)]
+pub enum CycleError<D, R, A> {
+    Draw(D),
+    Refresh(R),
+    Alarm(A),
+}
+
+impl<D, R, A> CycleError<D, R, A> {
+    pub fn blink_code(&self) -> BlinkCode {
+        match self {
+            Self::Draw(_) | Self::Refresh(_) => BlinkCode::Display,
+            Self::Alarm(_) => BlinkCode::Alarm,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct CycleOutcome {
+    /// Requested from the wake up alarm
+    pub sleep_for: Duration,
+    /// Alerts were shown instead of the dashboard, see [`crate::alert`]
+    pub alerting: bool,
+}
+
+/// Runs one cycle: fetches metrics, records them, checks the alert rules, shows the alerts or
+/// the metrics (or the error), sets the wake up alarm and blinks the `led` with the outcome.
+/// The fetch and display update phases are timed with the `stopwatch`.
+pub async fn run_cycle<S, D, A, St, L, C>(
+    config: &CycleConfig,
+    source: &mut S,
+    display: &mut D,
+    alarm: &mut A,
+    storage: &mut St,
+    led: &mut L,
+    stopwatch: &mut Stopwatch<C>,
+) -> Result<CycleOutcome, CycleError<D::Error, D::RefreshError, A::Error>>
+where
+    S: MetricsSource,
+    D: Display,
+    A: WakeAlarm,
+    St: Storage,
+    L: StatusLed,
+    C: Clock,
+{
+    let updated = update(config, source, display, alarm, storage, stopwatch).await;
+    let signal = match &updated {
+        Ok((_, signal)) => *signal,
+        Err(e) => LedSignal::Error(e.blink_code()),
+    };
+    led::play(led, signal).await;
+    updated.map(|(outcome, _)| outcome)
+}
+
+/// [`run_cycle`] up to the LED signal
+async fn update<S, D, A, St, C>(
+    config: &CycleConfig,
+    source: &mut S,
+    display: &mut D,
+    alarm: &mut A,
+    storage: &mut St,
+    stopwatch: &mut Stopwatch<C>,
+) -> Result<(CycleOutcome, LedSignal), CycleError<D::Error, D::RefreshError, A::Error>>
+where
+    S: MetricsSource,
+    D: Display,
+    A: WakeAlarm,
+    St: Storage,
+    C: Clock,
+{
+    let fetched = source.fetch().await;
+    let fetched_at = alarm.now().await;
+    source.fill_timings(&mut stopwatch.timings);
+    let mut alerting = false;
+    let (sleep_for, signal) = match fetched {
+        Ok(mut measurements) => {
+            let link = source.link_quality();
+            if let Some(link) = link.filter(LinkQuality::is_marginal) {
+                defmt::warn!("Marginal link: {}", defmt::Display2Format(&link));
+            }
+            let comfort = ComfortMetrics::from_measurements(&measurements, config.altitude);
+            defmt::info!("Derived metrics: {:?}", comfort);
+            if let Some(comfort) = &comfort {
+                measurements.insert(Measurement::new(Quantity::HeatIndex, comfort.heat_index));
+            }
+            let outdoor = source.outdoor();
+            if let Some(outdoor) = &outdoor {
+                defmt::info!("Outdoor: {:?}", defmt::Debug2Format(outdoor));
+            }
+            if let Some(particulates) = source.particulates() {
+                defmt::info!("Particulates: {:?}", defmt::Debug2Format(&particulates));
+            }
+            if config.record_history {
+                let reading = history::Reading {
+                    measurements: measurements.clone(),
+                    rssi: link.map(|l| l.rssi()),
+                };
+                if let Err(e) = history::record(storage, &reading).await {
+                    defmt::error!("History record failed: {:?}", defmt::Debug2Format(&e));
+                }
+            }
+            let measurements = match fetched_at {
+                Ok(now) => measurements.with_timestamp(now),
+                Err(e) => {
+                    defmt::warn!("Clock read failed: {:?}", defmt::Debug2Format(&e));
+                    measurements
+                }
+            };
+            let alerts = alert::evaluate(storage, &config.alerts, &measurements).await;
+            alerting = !alerts.is_empty();
+            if alerting {
+                defmt::info!("Alerts: {:?}", alerts.as_slice());
+                screens::draw_alerts(display, &alerts, &config.units)
+            } else {
+                screens::draw_dashboard(
+                    display,
+                    &measurements,
+                    comfort.as_ref(),
+                    &config.units,
+                    link.as_ref(),
+                    outdoor.as_ref(),
+                )
+            }
+            .map_err(CycleError::Draw)?;
+            (config.interval, LedSignal::Success)
+        }
+        Err(e) => {
+            defmt::warn!("Fetch failed: {}", defmt::Display2Format(&e));
+            screens::draw_error(display, &e).map_err(CycleError::Draw)?;
+            (config.retry_interval, LedSignal::Error(BlinkCode::Fetch))
+        }
+    };
+    let started = stopwatch.now();
+    display.refresh().await.map_err(CycleError::Refresh)?;
+    stopwatch.stop(Phase::DisplayUpdate, started);
+    alarm
+        .wake_up_in(sleep_for)
+        .await
+        .map_err(CycleError::Alarm)?;
+    let outcome = CycleOutcome {
+        sleep_for,
+        alerting,
+    };
+    Ok((outcome, signal))
+}
+
+#[cfg(test)]
+mod tests {
+    use core::convert::Infallible;
+    use core::fmt;
+    use embassy_futures::block_on;
+    use embedded_graphics::{pixelcolor::BinaryColor, prelude::*};
+
+    use super::*;
+    use crate::framebuffer::Framebuffer;
+    use crate::measurement::MeasurementSet;
+    use crate::metrics::AirMetrics;
+    use crate::mock::{MemoryStorage, RecordingAlarm, RecordingLed, ScriptedSource, SteppingClock};
+
+    const METRICS: AirMetrics = AirMetrics {
+        version: 1,
+        humidity: 41.5,
+        illuminance: Some(12.0),
+        radon_short: Some(48),
+        radon_long: Some(61),
+        temperature: 22.37,
+        pressure: Some(1003.2),
+        co2_level: Some(812),
+        voc_level: Some(95),
+        battery: None,
+        update_interval: None,
+    };
+
+    #[derive(Debug, Clone)]
+    struct TimedOut;
+
+    impl fmt::Display for TimedOut {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("Timed out")
+        }
+    }
+
+    /// Fails every drawing
+    struct BrokenDisplay;
+
+    #[derive(Debug, PartialEq)]
+    struct Broken;
+
+    impl OriginDimensions for BrokenDisplay {
+        fn size(&self) -> Size {
+            Size::new(screens::WIDTH, screens::HEIGHT)
+        }
+    }
+
+    impl DrawTarget for BrokenDisplay {
+        type Color = BinaryColor;
+        type Error = Broken;
+
+        fn draw_iter<I>(&mut self, _pixels: I) -> Result<(), Broken>
+        where
+            I: IntoIterator<Item = Pixel<BinaryColor>>,
+        {
+            Err(Broken)
+        }
+    }
+
+    impl Display for BrokenDisplay {
+        type RefreshError = Infallible;
+
+        async fn refresh(&mut self) -> Result<(), Infallible> {
+            Ok(())
+        }
+    }
+
+    struct Run<D: Display> {
+        result: Result<CycleOutcome, CycleError<D::Error, Infallible, Infallible>>,
+        display: D,
+        alarm: RecordingAlarm,
+        storage: MemoryStorage<128>,
+        led: RecordingLed,
+        stopwatch: Stopwatch<SteppingClock>,
+    }
+
+    fn run<D>(fetched: Result<MeasurementSet, TimedOut>, mut display: D) -> Run<D>
+    where
+        D: Display<RefreshError = Infallible>,
+    {
+        let responses = [fetched];
+        let mut source = ScriptedSource::new(&responses);
+        let mut alarm = RecordingAlarm::default();
+        let mut storage = MemoryStorage::new();
+        let mut led = RecordingLed::default();
+        let mut stopwatch = Stopwatch::new(SteppingClock::new(Duration::from_millis(5)));
+        let result = block_on(run_cycle(
+            &CycleConfig::default(),
+            &mut source,
+            &mut display,
+            &mut alarm,
+            &mut storage,
+            &mut led,
+            &mut stopwatch,
+        ));
+        Run {
+            result,
+            display,
+            alarm,
+            storage,
+            led,
+            stopwatch,
+        }
+    }
+
+    fn metrics() -> MeasurementSet {
+        MeasurementSet::from(&METRICS)
+    }
+
+    fn co2_only() -> MeasurementSet {
+        let mut measurements = MeasurementSet::new();
+        measurements.insert(Measurement::new(Quantity::Co2, 812.0));
+        measurements
+    }
+
+    /// With the heat index when it can be derived, as the cycle records and shows them
+    fn shown(fetched: &MeasurementSet) -> MeasurementSet {
+        let mut measurements = fetched.clone();
+        if let Some(comfort) = ComfortMetrics::from_measurements(fetched, None) {
+            measurements.insert(Measurement::new(Quantity::HeatIndex, comfort.heat_index));
+        }
+        measurements
+    }
+
+    /// Of every quantity, the history keeps them in the order of [`Quantity::ALL`]
+    fn values(measurements: &MeasurementSet) -> [Option<f32>; Quantity::COUNT] {
+        Quantity::ALL.map(|quantity| measurements.value(quantity))
+    }
+
+    /// The dashboard of the measurements as drawn by the screens, without the link and outdoor
+    /// parts
+    fn dashboard(fetched: &MeasurementSet) -> Framebuffer {
+        let comfort = ComfortMetrics::from_measurements(fetched, None);
+        let mut fb = Framebuffer::new();
+        let units = CycleConfig::default().units;
+        screens::draw_dashboard(
+            &mut fb,
+            &shown(fetched),
+            comfort.as_ref(),
+            &units,
+            None,
+            None,
+        )
+        .unwrap();
+        fb
+    }
+
+    fn history_len(storage: &mut MemoryStorage<128>) -> usize {
+        (0..)
+            .take_while(|i| block_on(history::read(storage, *i)).unwrap().is_some())
+            .count()
+    }
+
+    #[test]
+    fn success_sleeps_for_the_interval_and_records_the_metrics() {
+        let mut run = run(Ok(metrics()), Framebuffer::new());
+        let config = CycleConfig::default();
+        assert_eq!(
+            run.result.unwrap(),
+            CycleOutcome {
+                sleep_for: config.interval,
+                alerting: false
+            }
+        );
+        assert_eq!(run.alarm.requested, Some(config.interval));
+        let reading = block_on(history::read(&mut run.storage, 0))
+            .unwrap()
+            .unwrap();
+        assert_eq!(values(&reading.measurements), values(&shown(&metrics())));
+        assert_eq!(history_len(&mut run.storage), 1);
+        assert_eq!(
+            run.stopwatch.timings.get(Phase::DisplayUpdate),
+            Duration::from_millis(5).as_millis() as u32
+        );
+        assert_eq!(run.led.brightness, 0);
+        assert_eq!(
+            run.display.as_pbm_data(),
+            dashboard(&metrics()).as_pbm_data()
+        );
+        assert_ne!(run.display.as_pbm_data(), Framebuffer::new().as_pbm_data());
+    }
+
+    #[test]
+    fn partial_sets_are_shown_and_recorded() {
+        let mut run = run(Ok(co2_only()), Framebuffer::new());
+        assert!(run.result.is_ok());
+        let reading = block_on(history::read(&mut run.storage, 0))
+            .unwrap()
+            .unwrap();
+        assert_eq!(reading.measurements, co2_only());
+        assert_eq!(
+            run.display.as_pbm_data(),
+            dashboard(&co2_only()).as_pbm_data()
+        );
+        assert_ne!(run.display.as_pbm_data(), Framebuffer::new().as_pbm_data());
+    }
+
+    #[test]
+    fn fetch_error_retries_without_recording() {
+        let mut run = run(Err(TimedOut), Framebuffer::new());
+        let config = CycleConfig::default();
+        assert_eq!(run.result.unwrap().sleep_for, config.retry_interval);
+        let mut error = Framebuffer::new();
+        screens::draw_error(&mut error, &TimedOut).unwrap();
+        assert_eq!(run.display.as_pbm_data(), error.as_pbm_data());
+        assert_ne!(
+            run.display.as_pbm_data(),
+            dashboard(&metrics()).as_pbm_data()
+        );
+        assert_eq!(run.alarm.requested, Some(config.retry_interval));
+        assert_eq!(history_len(&mut run.storage), 0);
+        let expected = LedSignal::Error(BlinkCode::Fetch).steps();
+        assert!(run.led.steps.ends_with(&led_steps(&expected)));
+    }
+
+    #[test]
+    fn draw_error_leaves_the_alarm_to_the_caller() {
+        let mut run = run(Ok(metrics()), BrokenDisplay);
+        assert!(matches!(run.result, Err(CycleError::Draw(Broken))));
+        assert_eq!(run.alarm.requested, None);
+        // Recorded before drawing
+        assert_eq!(history_len(&mut run.storage), 1);
+        let expected = LedSignal::Error(BlinkCode::Display).steps();
+        assert!(run.led.steps.ends_with(&led_steps(&expected)));
+    }
+
+    #[test]
+    fn desk_mode_records_once_per_interval() {
+        let base = CycleConfig::default();
+        let mut desk = DeskMode::new();
+        let recorded: heapless::Vec<bool, 8> = (0..8)
+            .map(|_| desk.cycle_config(&base).record_history)
+            .collect();
+        // 30 s refreshes, 90 s on battery
+        assert_eq!(
+            recorded,
+            [true, false, false, true, false, false, true, false]
+        );
+        let base = CycleConfig {
+            record_history: false,
+            ..base
+        };
+        assert!(!DeskMode::new().cycle_config(&base).record_history);
+    }
+
+    fn led_steps(steps: &[led::LedStep]) -> heapless::Vec<(u8, Duration), 16> {
+        steps.iter().map(|s| (s.brightness, s.duration)).collect()
+    }
+}
@@ -1,21 +1,35 @@
 use core::ops::Add;
 use core::time::Duration;
+use embassy_embedded_hal::shared_bus::asynch::i2c::I2cDevice;
+use embassy_embedded_hal::shared_bus::I2cDeviceError;
+use embassy_rp::flash::{Blocking, Error as FlashError, Flash, ERASE_SIZE};
 use embassy_rp::gpio::{Input, Level, Output, Pull};
 use embassy_rp::i2c::I2c;
 use embassy_rp::i2c::InterruptHandler as I2CInterruptHandler;
 use embassy_rp::peripherals::*;
+use embassy_rp::pwm::{self, Pwm};
 use embassy_rp::spi::Spi;
 use embassy_rp::{i2c, spi};
-use embassy_time::Delay;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_time::{with_timeout, Delay, Timer};
 use embedded_hal_bus::spi::{ExclusiveDevice, NoDelay};
 use pcf85063a::{Control, Error as PCFError, PCF85063};
-use time::PrimitiveDateTime;
+use static_cell::StaticCell;
 use uc8151::asynch::Uc8151;
+use uc8151::Instruction;
+
+use crate::platform::{Button, Buttons, Clock, Display, Log, Slot, StatusLed, Storage, WakeAlarm};
+use crate::scd4x::Scd4x;
 
 embassy_rp::bind_interrupts!(struct Irqs {
     I2C0_IRQ => I2CInterruptHandler<I2C0>;
 });
 
+const FLASH_SIZE: usize = 2 * 1024 * 1024;
+/// Last sectors of the flash, excluded from the FLASH region in `memory.x`
+const STORAGE_OFFSET: u32 = (FLASH_SIZE - 64 * 1024) as u32;
+
 #[allow(non_snake_case)]
 pub struct Badger2040wParams {
     pub I2C0: I2C0,
@@ -33,35 +47,54 @@ pub struct Badger2040wParams {
     pub DMA_CH2: DMA_CH2,
     pub PIN_10: PIN_10,
     pub PIN_22: PIN_22,
+    pub PWM_SLICE3: PWM_SLICE3,
     pub PIN_15: PIN_15,
     pub PIN_11: PIN_11,
     pub PIN_12: PIN_12,
     pub PIN_13: PIN_13,
     pub PIN_14: PIN_14,
+    pub FLASH: FLASH,
 }
 
+pub type BadgerDisplay<'a> = Uc8151<
+    ExclusiveDevice<Spi<'a, SPI0, spi::Async>, Output<'a>, NoDelay>,
+    Output<'a>,
+    Input<'a>,
+    Output<'a>,
+    Delay,
+>;
+
+/// I2C0, shared by the RTC and the sensors on the Qw/ST connector
+pub type I2c0Bus = Mutex<NoopRawMutex, I2c<'static, I2C0, i2c::Async>>;
+
+/// One device on [`I2c0Bus`], locking the bus for each transaction
+pub type I2c0Device = I2cDevice<'static, NoopRawMutex, I2c<'static, I2C0, i2c::Async>>;
+
+/// SCD4x on the Qw/ST connector, see [`crate::scd4x`]
+pub type BadgerScd4x = Scd4x<I2c0Device, Delay>;
+
 pub struct Badger2040wIO<'a> {
     pub power: Output<'a>,
-    pub led: Output<'a>,
-    pub btn_up: Input<'a>,
-    pub btn_down: Input<'a>,
-    pub btn_a: Input<'a>,
-    pub btn_b: Input<'a>,
-    pub btn_c: Input<'a>,
-    pub display: Uc8151<
-        ExclusiveDevice<Spi<'a, SPI0, spi::Async>, Output<'a>, NoDelay>,
-        Output<'a>,
-        Input<'a>,
-        Output<'a>,
-        Delay,
-    >,
-    rtc: PCF85063<I2c<'a, I2C0, i2c::Async>>,
+    pub led: BadgerLed<'a>,
+    pub buttons: BadgerButtons<'a>,
+    pub display: BadgerDisplay<'a>,
+    pub rtc: Rtc,
+    /// See [`Badger2040wIO::i2c_device`]
+    pub i2c: &'static I2c0Bus,
+    pub storage: FlashStorage<'a>,
 }
 
 impl<'a> Badger2040wIO<'a> {
     pub async fn init(p: Badger2040wParams) -> Badger2040wIO<'a> {
-        // I2C for RTC
-        let i2c = I2c::new_async(p.I2C0, p.PIN_5, p.PIN_4, Irqs, i2c::Config::default());
+        // I2C for RTC and the Qw/ST connector
+        static I2C0_BUS: StaticCell<I2c0Bus> = StaticCell::new();
+        let i2c = I2C0_BUS.init(Mutex::new(I2c::new_async(
+            p.I2C0,
+            p.PIN_5,
+            p.PIN_4,
+            Irqs,
+            i2c::Config::default(),
+        )));
 
         // SPI for display
         let cs = Output::new(p.PIN_17, Level::High);
@@ -78,35 +111,367 @@ impl<'a> Badger2040wIO<'a> {
             spi::Config::default(),
         );
         let spi_dev = ExclusiveDevice::new_no_delay(spi, cs);
-        let mut rtc = PCF85063::new(i2c);
+        let mut rtc = PCF85063::new(I2cDevice::new(i2c));
         rtc.clear_alarm_flag().await.unwrap();
         Badger2040wIO {
             power: Output::new(p.PIN_10, Level::Low),
-            led: Output::new(p.PIN_22, Level::Low),
-            btn_up: Input::new(p.PIN_15, Pull::Down),
-            btn_down: Input::new(p.PIN_11, Pull::Down),
-            btn_a: Input::new(p.PIN_12, Pull::Down),
-            btn_b: Input::new(p.PIN_13, Pull::Down),
-            btn_c: Input::new(p.PIN_14, Pull::Down),
+            led: BadgerLed::new(p.PWM_SLICE3, p.PIN_22),
+            buttons: BadgerButtons {
+                up: Input::new(p.PIN_15, Pull::Down),
+                down: Input::new(p.PIN_11, Pull::Down),
+                a: Input::new(p.PIN_12, Pull::Down),
+                b: Input::new(p.PIN_13, Pull::Down),
+                c: Input::new(p.PIN_14, Pull::Down),
+            },
             display: Uc8151::new(spi_dev, dc, busy, reset, Delay),
-            rtc,
+            rtc: Rtc(rtc),
+            i2c,
+            storage: FlashStorage::new(Flash::new_blocking(p.FLASH)),
+        }
+    }
+
+    /// Handle for a sensor on the Qw/ST connector, sharing the bus with the RTC
+    pub fn i2c_device(&self) -> I2c0Device {
+        I2cDevice::new(self.i2c)
+    }
+}
+
+pub struct BadgerButtons<'a> {
+    pub up: Input<'a>,
+    pub down: Input<'a>,
+    pub a: Input<'a>,
+    pub b: Input<'a>,
+    pub c: Input<'a>,
+}
+
+impl Buttons for BadgerButtons<'_> {
+    fn is_pressed(&self, button: Button) -> bool {
+        let input = match button {
+            Button::Up => &self.up,
+            Button::Down => &self.down,
+            Button::A => &self.a,
+            Button::B => &self.b,
+            Button::C => &self.c,
+        };
+        input.is_high()
+    }
+}
+
+/// Checked by the deep sleep command, so it isn't entered by accident
+const DEEP_SLEEP_CHECK: u8 = 0xa5;
+/// The charge pump takes a few tens of ms to turn off
+const POWER_OFF_TIMEOUT: embassy_time::Duration = embassy_time::Duration::from_secs(1);
+
+impl Display for BadgerDisplay<'_> {
+    type RefreshError = <Self as embedded_graphics::draw_target::DrawTarget>::Error;
+
+    async fn refresh(&mut self) -> Result<(), Self::RefreshError> {
+        self.update().await
+    }
+
+    /// Turns the charge pump off, then enters the deep sleep once the panel isn't busy,
+    /// the controller ignores the deep sleep command before that. Only a reset wakes it up.
+    /// Gives up on the deep sleep when the panel stays busy, the power is cut anyway
+    async fn power_down(&mut self) -> Result<(), Self::RefreshError> {
+        self.command(Instruction::POF, &[]).await?;
+        let idle = async {
+            while self.is_busy() {
+                Timer::after_millis(10).await;
+            }
+        };
+        if with_timeout(POWER_OFF_TIMEOUT, idle).await.is_err() {
+            defmt::warn!("Display still busy, skipping its deep sleep");
+            return Ok(());
+        }
+        self.command(Instruction::DSLP, &[DEEP_SLEEP_CHECK]).await
+    }
+}
+
+/// PWM period in clock cycles, about 12.5 kHz at the default system clock
+const LED_PWM_TOP: u16 = 9_999;
+
+/// White LED on GPIO22, dimmed by its PWM slice
+pub struct BadgerLed<'a> {
+    pwm: Pwm<'a>,
+    config: pwm::Config,
+}
+
+impl<'a> BadgerLed<'a> {
+    pub fn new(slice: PWM_SLICE3, pin: PIN_22) -> Self {
+        let mut config = pwm::Config::default();
+        config.top = LED_PWM_TOP;
+        config.compare_a = 0;
+        Self {
+            pwm: Pwm::new_output_a(slice, pin, config.clone()),
+            config,
         }
     }
-    pub async fn wake_up_in(
-        self: &mut Self,
-        duration: Duration,
-    ) -> Result<(), PCFError<i2c::Error>> {
-        self.rtc.clear_alarm_flag().await?;
-        self.rtc
-            .set_datetime(&PrimitiveDateTime::MIN)
-            .await
-            .unwrap();
-        self.rtc
-            .set_alarm_time(PrimitiveDateTime::MIN.time().add(duration))
-            .await?;
-        self.rtc.control_alarm_seconds(Control::On).await?;
-        self.rtc.control_alarm_minutes(Control::On).await?;
-        self.rtc.control_alarm_interrupt(Control::On).await?;
+}
+
+impl StatusLed for BadgerLed<'_> {
+    /// The perceived brightness grows about with the square of the duty cycle
+    fn set_brightness(&mut self, percent: u8) {
+        let percent = percent.min(100) as u32;
+        self.config.compare_a = (LED_PWM_TOP as u32 * percent * percent / 10_000) as u16;
+        self.pwm.set_config(&self.config);
+    }
+
+    async fn hold(&mut self, duration: Duration) {
+        Timer::after_micros(duration.as_micros() as u64).await;
+    }
+}
+
+/// Time since the boot from the embassy time driver
+pub struct BootClock;
+
+impl Clock for BootClock {
+    fn now(&self) -> Duration {
+        Duration::from_micros(embassy_time::Instant::now().as_micros())
+    }
+}
+
+/// PCF85063 real time clock. Its alarm powers the Badger back on.
+///
+/// Left running from whatever date it has, the alarm matches the minutes and seconds only, so
+/// it wakes the Badger up within the hour
+pub struct Rtc(PCF85063<I2c0Device>);
+
+impl WakeAlarm for Rtc {
+    type Error = PCFError<I2cDeviceError<i2c::Error>>;
+
+    async fn wake_up_in(&mut self, duration: Duration) -> Result<(), Self::Error> {
+        let rtc = &mut self.0;
+        rtc.clear_alarm_flag().await?;
+        let now = rtc.get_datetime().await?;
+        rtc.set_alarm_time(now.time().add(duration)).await?;
+        rtc.control_alarm_seconds(Control::On).await?;
+        rtc.control_alarm_minutes(Control::On).await?;
+        rtc.control_alarm_interrupt(Control::On).await?;
         Ok(())
     }
+
+    async fn now(&mut self) -> Result<Duration, Self::Error> {
+        // Taking it as UTC, it only has to count from a recent enough epoch
+        let now = self.0.get_datetime().await?.assume_utc();
+        Ok(Duration::from_secs(now.unix_timestamp().max(0) as u64))
+    }
+}
+
+/// Persistent storage in the reserved flash area.
+///
+/// Some of the first sectors hold one [`Slot`] each. Each slot record is prefixed with its length,
+/// erased flash reads as an empty record.
+/// [`Log`]s are rings of sectors filled with `sequence number, checksum, record` entries,
+/// so appending only erases a sector once it's reused.
+pub struct FlashStorage<'a> {
+    flash: Flash<'a, FLASH, Blocking, FLASH_SIZE>,
+    /// Found by scanning each log on its first use, then kept up to date by the appends.
+    /// `Some(None)` for an empty log
+    heads: [Option<Option<LogHead>>; Log::COUNT],
+}
+
+const RECORD_HEADER: usize = 2;
+const ENTRY_HEADER: usize = 5;
+const EMPTY_ENTRY: u32 = u32::MAX;
+
+struct LogGeometry {
+    offset: u32,
+    entry_size: usize,
+    per_sector: usize,
+    entries: usize,
+}
+
+impl LogGeometry {
+    fn new(log: Log) -> Self {
+        let (first_sector, sectors) = match log {
+            Log::Timings => (2, 2),
+            Log::History => (8, 8),
+        };
+        let entry_size = ENTRY_HEADER + log.record_size();
+        let per_sector = ERASE_SIZE / entry_size;
+        Self {
+            offset: STORAGE_OFFSET + (first_sector * ERASE_SIZE) as u32,
+            entry_size,
+            per_sector,
+            entries: per_sector * sectors,
+        }
+    }
+
+    fn entry_offset(&self, position: usize) -> u32 {
+        let sector = position / self.per_sector;
+        let entry = position % self.per_sector;
+        self.offset + (sector * ERASE_SIZE + entry * self.entry_size) as u32
+    }
+}
+
+/// Newest entry of a log
+#[derive(Clone, Copy)]
+struct LogHead {
+    position: usize,
+    sequence: u32,
+    count: usize,
+}
+
+fn entry_checksum(sequence: u32, record: &[u8]) -> u8 {
+    sequence
+        .to_le_bytes()
+        .iter()
+        .chain(record)
+        .fold(0xA5, |acc, b| acc.rotate_left(1) ^ b)
+}
+
+impl<'a> FlashStorage<'a> {
+    pub fn new(flash: Flash<'a, FLASH, Blocking, FLASH_SIZE>) -> Self {
+        Self {
+            flash,
+            heads: [None; Log::COUNT],
+        }
+    }
+
+    fn slot_offset(slot: Slot) -> u32 {
+        // Sectors 2 and 3 were taken by the timings log before the alert states came
+        let sector = match slot {
+            Slot::Config => 0,
+            Slot::GattCache => 1,
+            Slot::Alerts => 4,
+        };
+        STORAGE_OFFSET + sector * ERASE_SIZE as u32
+    }
+
+    fn read_entry(
+        &mut self,
+        geometry: &LogGeometry,
+        position: usize,
+        record: &mut [u8],
+    ) -> Result<Option<u32>, FlashError> {
+        let offset = geometry.entry_offset(position);
+        let mut header = [0u8; ENTRY_HEADER];
+        self.flash.blocking_read(offset, &mut header)?;
+        let sequence = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        if sequence == EMPTY_ENTRY {
+            return Ok(None);
+        }
+        self.flash
+            .blocking_read(offset + ENTRY_HEADER as u32, record)?;
+        // Leftovers from the previous firmware images are skipped as well
+        Ok((entry_checksum(sequence, record) == header[4]).then_some(sequence))
+    }
+
+    fn log_head(&mut self, log: Log) -> Result<Option<LogHead>, FlashError> {
+        if let Some(head) = self.heads[log as usize] {
+            return Ok(head);
+        }
+        let head = self.scan_log(log)?;
+        self.heads[log as usize] = Some(head);
+        Ok(head)
+    }
+
+    fn scan_log(&mut self, log: Log) -> Result<Option<LogHead>, FlashError> {
+        let geometry = LogGeometry::new(log);
+        let mut record = [0u8; 64];
+        let record = &mut record[..log.record_size()];
+        let mut head: Option<LogHead> = None;
+        let mut count = 0;
+        for position in 0..geometry.entries {
+            let Some(sequence) = self.read_entry(&geometry, position, record)? else {
+                continue;
+            };
+            count += 1;
+            if head.as_ref().map_or(true, |h| sequence > h.sequence) {
+                head = Some(LogHead {
+                    position,
+                    sequence,
+                    count: 0,
+                });
+            }
+        }
+        Ok(head.map(|h| LogHead { count, ..h }))
+    }
+}
+
+impl Storage for FlashStorage<'_> {
+    type Error = FlashError;
+
+    async fn load(&mut self, slot: Slot, buf: &mut [u8]) -> Result<usize, FlashError> {
+        let offset = Self::slot_offset(slot);
+        let mut header = [0u8; RECORD_HEADER];
+        self.flash.blocking_read(offset, &mut header)?;
+        let len = u16::from_le_bytes(header) as usize;
+        if len > ERASE_SIZE - RECORD_HEADER || len > buf.len() {
+            return Ok(0);
+        }
+        self.flash
+            .blocking_read(offset + RECORD_HEADER as u32, &mut buf[..len])?;
+        Ok(len)
+    }
+
+    async fn store(&mut self, slot: Slot, data: &[u8]) -> Result<(), FlashError> {
+        if data.len() > ERASE_SIZE - RECORD_HEADER {
+            return Err(FlashError::OutOfBounds);
+        }
+        let offset = Self::slot_offset(slot);
+        self.flash
+            .blocking_erase(offset, offset + ERASE_SIZE as u32)?;
+        self.flash
+            .blocking_write(offset, &(data.len() as u16).to_le_bytes())?;
+        self.flash
+            .blocking_write(offset + RECORD_HEADER as u32, data)?;
+        Ok(())
+    }
+
+    async fn append(&mut self, log: Log, record: &[u8]) -> Result<(), FlashError> {
+        if record.len() != log.record_size() {
+            return Err(FlashError::OutOfBounds);
+        }
+        let geometry = LogGeometry::new(log);
+        let head = self.log_head(log)?;
+        let (position, sequence) = match head {
+            Some(head) => ((head.position + 1) % geometry.entries, head.sequence + 1),
+            None => (0, 0),
+        };
+        // Scanned again on the next use after an erase or a failure
+        self.heads[log as usize] = None;
+        let erased = position % geometry.per_sector == 0;
+        if erased {
+            // Starting a new sector, dropping the oldest entries if it's in use
+            let offset = geometry.entry_offset(position);
+            self.flash
+                .blocking_erase(offset, offset + ERASE_SIZE as u32)?;
+        }
+        let mut entry: heapless::Vec<u8, 64> = heapless::Vec::new();
+        let _ = entry.extend_from_slice(&sequence.to_le_bytes());
+        let _ = entry.push(entry_checksum(sequence, record));
+        let _ = entry.extend_from_slice(record);
+        self.flash
+            .blocking_write(geometry.entry_offset(position), &entry)?;
+        if !erased {
+            let count = head.map_or(0, |h| h.count) + 1;
+            self.heads[log as usize] = Some(Some(LogHead {
+                position,
+                sequence,
+                count,
+            }));
+        }
+        Ok(())
+    }
+
+    async fn read_record(
+        &mut self,
+        log: Log,
+        index: usize,
+        buf: &mut [u8],
+    ) -> Result<bool, FlashError> {
+        let Some(head) = self.log_head(log)? else {
+            return Ok(false);
+        };
+        if index >= head.count || buf.len() < log.record_size() {
+            return Ok(false);
+        }
+        // Entries are written one after another, so the valid ones are contiguous
+        let geometry = LogGeometry::new(log);
+        let oldest = head.position + geometry.entries + 1 - head.count;
+        let position = (oldest + index) % geometry.entries;
+        let found = self.read_entry(&geometry, position, &mut buf[..log.record_size()])?;
+        Ok(found.is_some())
+    }
 }
@@ -0,0 +1,55 @@
+//! Renders every screen into PNG (or PBM) images without a badge attached.
+//!
+//! `cargo run --no-default-features --features simulator --bin simulator --target <host triple> -- [out dir] [png|pbm]`
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+use trawm::framebuffer::Framebuffer;
+use trawm::samples::SCREENS;
+use trawm::screens::{HEIGHT, WIDTH};
+
+fn main() -> io::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let out_dir = args.next().unwrap_or_else(|| "screens".into());
+    let format = args.next().unwrap_or_else(|| "png".into());
+    let write = match format.as_str() {
+        "png" => write_png,
+        "pbm" => write_pbm,
+        other => {
+            eprintln!("Unknown format '{}', use png or pbm", other);
+            std::process::exit(2);
+        }
+    };
+    let out_dir = Path::new(&out_dir);
+    fs::create_dir_all(out_dir)?;
+
+    for screen in SCREENS {
+        let mut fb = Framebuffer::new();
+        let Ok(()) = (screen.draw)(&mut fb);
+        let path = out_dir.join(screen.name).with_extension(&format);
+        write(&fb, &path)?;
+        println!("{}", path.display());
+    }
+    Ok(())
+}
+
+fn write_pbm(fb: &Framebuffer, path: &Path) -> io::Result<()> {
+    let mut file = fs::File::create(path)?;
+    write!(file, "P4\n{} {}\n", WIDTH, HEIGHT)?;
+    file.write_all(fb.as_pbm_data())
+}
+
+/// 1 bit grayscale, where `1` is white, so the PBM raster is inverted
+fn write_png(fb: &Framebuffer, path: &Path) -> io::Result<()> {
+    let file = io::BufWriter::new(fs::File::create(path)?);
+    let mut encoder = png::Encoder::new(file, WIDTH, HEIGHT);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::One);
+    let raster: Vec<u8> = fb.as_pbm_data().iter().map(|b| !b).collect();
+    encoder
+        .write_header()
+        .and_then(|mut writer| writer.write_image_data(&raster))
+        .map_err(io::Error::other)
+}
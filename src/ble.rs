@@ -1,25 +1,47 @@
 use bt_hci::controller::ExternalController;
+use bt_hci::param::BdAddr;
 use bt_hci::param::LeAdvEventKind::AdvInd;
-use core::iter;
+use core::fmt;
+use core::marker::PhantomData;
+use cyw43::bluetooth::BtDriver;
+use cyw43::Control;
 use cyw43_pio::PioSpi;
 use embassy_executor::Spawner;
-use embassy_futures::select::select;
+use embassy_futures::select::{select, Either};
 use embassy_rp::gpio::{Level, Output};
 use embassy_rp::peripherals::*;
 use embassy_rp::pio::{InterruptHandler as PIOInterruptHandler, Pio};
-use embassy_time::{with_timeout, Duration as EmbassyDuration};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_time::{with_timeout, Duration as EmbassyDuration, Instant};
 
 use heapless::Vec;
 use static_cell::StaticCell;
-use trouble_host::advertise::AdStructure;
 use trouble_host::gatt::GattClient;
-use trouble_host::prelude::{ConnectConfig, Uuid};
+use trouble_host::prelude::{
+    Central, Characteristic, ConnectConfig, Connection, Controller, Peripheral, Runner, Stack, Uuid,
+};
 use trouble_host::scan::ScanConfig;
-use trouble_host::{Address, HostResources, PacketQos};
+use trouble_host::{Address, BleHostError, Error, HostResources, PacketQos};
 
+use crate::adv::fix_adv_payload;
+use crate::gatt_cache::{DatabaseHash, GattCache};
+use crate::link::LinkQuality;
+use crate::measurement::MeasurementSet;
 use crate::metrics::{AirMetrics, ParseMetricsError};
+use crate::platform::MetricsSource;
+use crate::profile::{find_sensor, GattUuid, SensorProfile};
+use crate::timing::{Phase, PhaseTimings};
 
 type BleResources<C> = HostResources<C, 1, 3, 27>;
+pub(crate) type BleController = ExternalController<BtDriver<'static>, 10>;
+
+const GENERIC_ATTRIBUTE_SERVICE: u16 = 0x1801;
+const DATABASE_HASH: u16 = 0x2b2a;
+
+/// RuuviTags broadcast about every second, older readings mean the outdoor sensor is gone or out
+/// of range
+const OUTDOOR_MAX_AGE: EmbassyDuration = EmbassyDuration::from_secs(10 * 60);
 
 embassy_rp::bind_interrupts!(struct Irqs {
     PIO0_IRQ_0 => PIOInterruptHandler<PIO0>;
@@ -31,25 +53,126 @@ async fn cyw43_task(
 ) -> ! {
     runner.run().await
 }
-// b42e1c08-ade7-11e4-89d3-123b93f75cba
-const SERVICE_UUID: Uuid = Uuid::new_long([
-    0xba, 0x5c, 0xf7, 0x93, 0x3b, 0x12, 0xd3, 0x89, 0xe4, 0x11, 0xe7, 0xad, 0x08, 0x1c, 0x2e, 0xb4,
-]);
 
-// b42e2a68-ade7-11e4-89d3-123b93f75cba
-const CHAR_UUID: Uuid = Uuid::new_long([
-    0xba, 0x5c, 0xf7, 0x93, 0x3b, 0x12, 0xd3, 0x89, 0xe4, 0x11, 0xe7, 0xad, 0x68, 0x2a, 0x2e, 0xb4,
-]);
+#[embassy_executor::task]
+async fn host_task(mut runner: Runner<'static, BleController>) {
+    if let Err(e) = runner.run().await {
+        defmt::error!("BLE host stopped: {:?}", e);
+    }
+}
+
+/// Step of a connection that failed, see [`BLEError::ConnectionProblem`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Stage {
+    Connect,
+    GattClient,
+    /// The link dropped while talking to the device
+    Link,
+}
 
-#[derive(Debug, Clone, Copy)]
+impl Stage {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Connect => "connect",
+            Self::GattClient => "GATT client",
+            Self::Link => "link",
+        }
+    }
+}
+
+/// Status returned for a failed connection step
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum ErrorCode {
+    /// Error response of the device
+    Att(u8),
+    /// Status of an HCI command or event of the controller, e.g. 0x3E when the connection
+    /// couldn't be established
+    Hci(u8),
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Att(code) => write!(f, "ATT error 0x{:02X}", code),
+            Self::Hci(code) => write!(f, "HCI error 0x{:02X}", code),
+        }
+    }
+}
+
+/// `code` is the ATT error code returned by the device, if it sent one. Connection problems can
+/// have the controller status instead
+#[derive(Debug, Clone, Copy, defmt::Format)]
 pub enum BLEError {
-    ConnectionProblem,
-    ServiceNotFound,
-    CharacteristicsNotFound,
+    ConnectionProblem {
+        stage: Stage,
+        code: Option<ErrorCode>,
+    },
+    ServiceNotFound {
+        code: Option<u8>,
+    },
+    CharacteristicsNotFound {
+        code: Option<u8>,
+    },
+    ReadFailed {
+        code: Option<u8>,
+    },
     ParseMetricsProblem(ParseMetricsError),
     TimedOut,
 }
 
+impl fmt::Display for BLEError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let code = match *self {
+            Self::ConnectionProblem { stage, code } => {
+                write!(f, "Connection problem ({})", stage.name())?;
+                return match code {
+                    Some(code) => write!(f, ", {}", code),
+                    None => Ok(()),
+                };
+            }
+            Self::ServiceNotFound { code } => {
+                f.write_str("Metrics service not found")?;
+                code
+            }
+            Self::CharacteristicsNotFound { code } => {
+                f.write_str("Metrics characteristic not found")?;
+                code
+            }
+            Self::ReadFailed { code } => {
+                f.write_str("Metrics read failed")?;
+                code
+            }
+            Self::ParseMetricsProblem(e) => return write!(f, "Invalid metrics: {}", e),
+            Self::TimedOut => return f.write_str("Timed out"),
+        };
+        match code {
+            Some(code) => write!(f, ", ATT error 0x{:02X}", code),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Sensor picked by [`BleSession::find`]
+#[derive(Debug, Clone)]
+pub struct Advertiser {
+    pub address: Address,
+    pub rssi: i8,
+    pub profile: &'static dyn SensorProfile,
+    /// Readings it broadcast, see [`SensorProfile::decode_adv`]
+    pub metrics: Option<MeasurementSet>,
+}
+
+/// Supported sensor seen while scanning
+#[derive(Debug, Clone, Copy)]
+pub struct FoundDevice {
+    /// Little endian, as reported by the controller
+    pub addr: [u8; 6],
+    pub rssi: i8,
+    /// Advertised by the Airthings devices only
+    pub serial: Option<u32>,
+    pub profile: &'static dyn SensorProfile,
+}
+
 #[allow(non_snake_case)]
 pub struct BLE {
     pub PIN_25: PIN_25,
@@ -59,31 +182,46 @@ pub struct BLE {
     pub PIN_29: PIN_29,
     pub PIN_23: PIN_23,
 }
-impl BLE {
-    pub async fn get_metrics(
-        self: Self,
-        spawner: &Spawner,
-        operation_timeout: EmbassyDuration,
-    ) -> Result<AirMetrics, BLEError> {
+
+/// The cyw43 with its firmware loaded and a running host stack.
+///
+/// Started once per boot, then used for any number of scans and reads.
+pub struct BleSession {
+    stack: Stack<'static, BleController>,
+    central: Central<'static, BleController>,
+    peripheral: Peripheral<'static, BleController>,
+    control: Control<'static>,
+    /// Phases of the last [`Self::get_metrics`]
+    timings: PhaseTimings,
+    /// Of the last successful [`Self::get_metrics`]
+    link: Option<LinkQuality>,
+    /// Last outdoor sensor broadcast seen while scanning, and when
+    outdoor: Option<(Instant, MeasurementSet)>,
+    gatt_cache: GattCache,
+}
+
+impl BleSession {
+    /// Powers the cyw43 up, loads its firmware and spawns the tasks driving it
+    pub async fn start(ble: BLE, spawner: &Spawner) -> Self {
         // Loading wireless firmware
         let fw = include_bytes!("../cyw43-firmware/43439A0.bin");
         let clm = include_bytes!("../cyw43-firmware/43439A0_clm.bin");
         let btfw = include_bytes!("../cyw43-firmware/43439A0_btfw.bin");
 
         // IO
-        let pwr = Output::new(self.PIN_23, Level::Low);
-        let cs = Output::new(self.PIN_25, Level::High);
-        let mut pio = Pio::new(self.PIO0, Irqs);
+        let pwr = Output::new(ble.PIN_23, Level::Low);
+        let cs = Output::new(ble.PIN_25, Level::High);
+        let mut pio = Pio::new(ble.PIO0, Irqs);
         let spi = PioSpi::new(
             &mut pio.common,
             pio.sm0,
             pio.irq0,
             cs,
-            self.PIN_24,
-            self.PIN_29,
-            self.DMA_CH0,
+            ble.PIN_24,
+            ble.PIN_29,
+            ble.DMA_CH0,
         );
-        // BLE
+        // BLE. `BLE` owns the pins, so this runs at most once
         static STATE: StaticCell<cyw43::State> = StaticCell::new();
         let state = STATE.init(cyw43::State::new());
         let (_net_device, bt_device, mut control, runner) =
@@ -91,183 +229,516 @@ impl BLE {
         spawner.spawn(cyw43_task(runner)).unwrap();
         control.init(clm).await;
 
-        let controller: ExternalController<_, 10> = ExternalController::new(bt_device);
-        let mut resources = BleResources::new(PacketQos::None);
-        let (stack, _, mut central, mut trouble_runner) =
-            trouble_host::new(controller, &mut resources).build();
+        static RESOURCES: StaticCell<BleResources<BleController>> = StaticCell::new();
+        let resources = RESOURCES.init(BleResources::new(PacketQos::None));
+        let (stack, peripheral, central, runner) =
+            trouble_host::new(ExternalController::new(bt_device), resources).build();
+        spawner.spawn(host_task(runner)).unwrap();
 
-        // Results
-        let mut found_addr: Option<Address> = None;
-        let mut raw_metrics = [0; 256];
-        let mut scan_and_fetch_error: Option<BLEError> = None;
-        let scan_and_fetch = async {
-            defmt::info!("Scan start");
+        Self {
+            stack,
+            central,
+            peripheral,
+            control,
+            timings: PhaseTimings::default(),
+            link: None,
+            outdoor: None,
+            gatt_cache: GattCache::default(),
+        }
+    }
+
+    /// Handles remembered across connections. Empty on start, load and save it
+    /// with [`GattCache::load`] and [`GattCache::save`] to keep it across boots
+    pub fn gatt_cache(&mut self) -> &mut GattCache {
+        &mut self.gatt_cache
+    }
+
+    /// Phases of the last [`Self::get_metrics`]. Those it didn't get to are 0
+    pub fn timings(&self) -> &PhaseTimings {
+        &self.timings
+    }
+
+    /// Signal strength of the last successful [`Self::get_metrics`], `None` if it failed
+    pub fn link_quality(&self) -> Option<LinkQuality> {
+        self.link
+    }
+
+    /// Latest outdoor sensor broadcast from the scans, unless it's too old to be shown
+    pub fn outdoor(&self) -> Option<MeasurementSet> {
+        self.outdoor
+            .as_ref()
+            .filter(|(seen, _)| seen.elapsed() < OUTDOOR_MAX_AGE)
+            .map(|(_, readings)| readings.clone())
+    }
+
+    /// Keeps the readings of an outdoor sensor, `false` for the other profiles
+    fn keep_outdoor(&mut self, profile: &dyn SensorProfile, data: &[u8]) -> bool {
+        if !profile.is_outdoor() {
+            return false;
+        }
+        if let Some(readings) = profile.decode_adv(data) {
+            self.outdoor = Some((Instant::now(), readings));
+        }
+        true
+    }
+
+    pub(crate) fn stack(&self) -> Stack<'static, BleController> {
+        self.stack
+    }
 
-            'scan: loop {
-                let reports = central.scan(&ScanConfig::default()).await;
-                let Ok(reports) = reports else {
+    pub(crate) fn peripheral(&mut self) -> &mut Peripheral<'static, BleController> {
+        &mut self.peripheral
+    }
+
+    /// The VBUS sense line of the Pico W module is wired to WL_GPIO2 of the cyw43
+    pub async fn usb_powered(&mut self) -> bool {
+        let mut gpio_in = [0; 4];
+        self.control.get_iovar("gpioin", &mut gpio_in).await;
+        u32::from_le_bytes(gpio_in) & (1 << 2) != 0
+    }
+
+    /// Lists the supported sensors advertising within `duration`, only the one with the
+    /// `serial` when given
+    pub async fn scan(
+        &mut self,
+        duration: EmbassyDuration,
+        serial: Option<u32>,
+    ) -> Vec<FoundDevice, 8> {
+        let mut found = Vec::new();
+        let scan = async {
+            loop {
+                let Ok(reports) = self.central.scan(&ScanConfig::default()).await else {
                     defmt::error!("BLEHostError");
                     continue;
                 };
-                for report in reports.iter() {
-                    let Ok(report) = report else {
-                        defmt::error!("FromHCIBytesError");
+                for report in reports.iter().flatten() {
+                    let mut payload = Vec::<u8, 256>::new();
+                    if fix_adv_payload(&report.data, &mut payload).is_err() {
+                        continue;
+                    }
+                    let Some((profile, data)) = find_sensor(&payload) else {
                         continue;
                     };
-                    if report.event_kind != AdvInd {
-                        // https://academy.nordicsemi.com/courses/bluetooth-low-energy-fundamentals/lessons/lesson-2-bluetooth-le-advertising/topic/advertising-types/
+                    if self.keep_outdoor(profile, data) || !has_serial(profile, data, serial) {
                         continue;
                     }
-                    defmt::info!(
-                        "> {:?}\t{:X}: {:X}",
-                        report.event_kind,
-                        report.addr,
-                        report.data
-                    );
-                    let mut fixed_report_data = Vec::<u8, 256>::new();
-                    fix_adv_payload(&report.data, &mut fixed_report_data);
-                    defmt::info!(
-                        "= {:?}\t{:X}: {:X}",
-                        report.event_kind,
-                        report.addr,
-                        fixed_report_data
-                    );
-
-                    for ad in AdStructure::decode(&fixed_report_data[..]) {
-                        let ad = match ad {
-                            Ok(ad) => ad,
-                            Err(e) => {
-                                defmt::error!("Structure decode error: {:?}", e);
-                                break;
-                            }
-                        };
-                        defmt::info!("{:?}", ad);
-                        match ad {
-                            AdStructure::ManufacturerSpecificData {
-                                company_identifier: 0x0334,
-                                ..
-                            } => {
-                                found_addr = Some(Address {
-                                    kind: report.addr_kind,
-                                    addr: report.addr,
-                                });
-                                break 'scan;
-                            }
-                            _ => (),
-                        }
+                    let addr = addr_bytes(&report.addr);
+                    if found.iter().any(|d: &FoundDevice| d.addr == addr) {
+                        continue;
                     }
+                    let _ = found.push(FoundDevice {
+                        addr,
+                        rssi: report.rssi,
+                        serial: profile.serial(data),
+                        profile,
+                    });
                 }
             }
-            // Fetch metrics
-            defmt::info!("Found airthings. {:?}", found_addr);
-            let Some(target) = found_addr else {
-                defmt::error!("Couldn't connect. No address specified");
-                scan_and_fetch_error = Some(BLEError::ConnectionProblem);
-                return;
-            };
-            let conn = central
-                .connect(&ConnectConfig {
-                    connect_params: Default::default(),
-                    scan_config: ScanConfig {
-                        filter_accept_list: &[(target.kind, &target.addr)],
-                        ..Default::default()
-                    },
-                })
-                .await;
-            let Ok(conn) = conn else {
-                scan_and_fetch_error = Some(BLEError::ConnectionProblem);
-                return;
-            };
-            defmt::info!("Connected, creating gatt client");
+        };
+        let _ = with_timeout(duration, scan).await;
+        found
+    }
 
-            let Ok(client) = GattClient::<_, 10, 27>::new(stack, &conn).await else {
-                scan_and_fetch_error = Some(BLEError::ConnectionProblem);
-                return;
+    /// Scans until the first supported sensor shows up, the one with the `serial` when given
+    pub async fn find(&mut self, serial: Option<u32>) -> Advertiser {
+        defmt::info!("Scan start");
+        loop {
+            let reports = self.central.scan(&ScanConfig::default()).await;
+            let Ok(reports) = reports else {
+                defmt::error!("BLEHostError");
+                continue;
             };
-
-            let _ = select(client.task(), async {
-                defmt::info!("Looking for Airthings metrics service");
-                let Ok(services) = client.services_by_uuid(&SERVICE_UUID).await else {
-                    scan_and_fetch_error = Some(BLEError::ServiceNotFound);
-                    return;
+            for report in reports.iter() {
+                let Ok(report) = report else {
+                    defmt::error!("FromHCIBytesError");
+                    continue;
                 };
-                let Some(service) = services.first() else {
-                    scan_and_fetch_error = Some(BLEError::ServiceNotFound);
-                    return;
+                let mut fixed_report_data = Vec::<u8, 256>::new();
+                if let Err(e) = fix_adv_payload(&report.data, &mut fixed_report_data) {
+                    defmt::error!("Malformed advertisement: {:?}", e);
+                    continue;
+                }
+                let Some((profile, data)) = find_sensor(&fixed_report_data) else {
+                    continue;
                 };
-
-                defmt::info!("Looking for Airthings metrics characteristics");
-                let characteristic = client
-                    .characteristic_by_uuid(&service.clone(), &CHAR_UUID)
-                    .await;
-                let Ok(characteristic) = characteristic else {
-                    scan_and_fetch_error = Some(BLEError::CharacteristicsNotFound);
-                    return;
+                // Outdoor sensors may not be connectable, so before the filters below
+                if self.keep_outdoor(profile, data) || !has_serial(profile, data, serial) {
+                    continue;
+                }
+                let metrics = profile.decode_adv(data);
+                if profile.read_plan().is_none() {
+                    if metrics.is_none() {
+                        defmt::debug!("Undecodable {} broadcast: {:X}", profile.name(), data);
+                        continue;
+                    }
+                } else if report.event_kind != AdvInd {
+                    // https://academy.nordicsemi.com/courses/bluetooth-low-energy-fundamentals/lessons/lesson-2-bluetooth-le-advertising/topic/advertising-types/
+                    continue;
+                }
+                defmt::info!(
+                    "> {:?}\t{:X}: {:X}",
+                    report.event_kind,
+                    report.addr,
+                    report.data
+                );
+                defmt::info!(
+                    "= {:?}\t{:X}: {:X}",
+                    report.event_kind,
+                    report.addr,
+                    fixed_report_data
+                );
+                let address = Address {
+                    kind: report.addr_kind,
+                    addr: report.addr,
                 };
-                let _ = client
-                    .read_characteristic(&characteristic, &mut raw_metrics[..])
-                    .await;
-                defmt::info!("Got characteristics: {:X}", raw_metrics);
+                defmt::info!(
+                    "Found {}. {:?} {} dBm",
+                    profile.name(),
+                    address,
+                    report.rssi
+                );
+                return Advertiser {
+                    address,
+                    rssi: report.rssi,
+                    profile,
+                    metrics,
+                };
+            }
+        }
+    }
+
+    /// The connection is closed when dropped
+    pub async fn connect(&mut self, target: Address) -> Result<Connection<'static>, BLEError> {
+        self.central
+            .connect(&ConnectConfig {
+                connect_params: Default::default(),
+                scan_config: ScanConfig {
+                    filter_accept_list: &[(target.kind, &target.addr)],
+                    ..Default::default()
+                },
             })
-            .await;
+            .await
+            .map_err(|e| BLEError::ConnectionProblem {
+                stage: Stage::Connect,
+                code: error_code(&e),
+            })
+    }
+
+    /// Reads the metrics characteristic of a connected sensor following its `profile`.
+    /// Uses the handle cached for `addr` when there is one, discovering it otherwise
+    pub async fn read(
+        &mut self,
+        conn: &Connection<'static>,
+        addr: [u8; 6],
+        profile: &'static dyn SensorProfile,
+    ) -> Result<MeasurementSet, BLEError> {
+        let Some(plan) = profile.read_plan() else {
+            // Only listened to, there's nothing to read
+            return Err(BLEError::ServiceNotFound { code: None });
         };
-        let mut timeout_error: Option<BLEError> = None;
-        select(trouble_runner.run(), async {
-            let Ok(_) = with_timeout(operation_timeout, scan_and_fetch).await else {
-                defmt::error!("Scan timed out");
-                timeout_error = Some(BLEError::TimedOut);
-                return;
-            };
+        defmt::info!("Connected, creating gatt client");
+        let client = GattClient::<_, 10, 27>::new(self.stack, conn)
+            .await
+            .map_err(|e| BLEError::ConnectionProblem {
+                stage: Stage::GattClient,
+                code: error_code(&e),
+            })?;
+
+        let mut raw_metrics = [0; 256];
+        let timings = &mut self.timings;
+        let gatt_cache = &mut self.gatt_cache;
+        let read = async {
+            if let Some(handle) = gatt_cache.get(&addr) {
+                let started = Instant::now();
+                let hash = gatt_cache.database_hash(&addr);
+                let read = match hash {
+                    Some(hash) if database_changed(&client, &hash).await => None,
+                    _ => Some(
+                        client
+                            .read_characteristic(
+                                &cached_characteristic(handle),
+                                &mut raw_metrics[..],
+                            )
+                            .await,
+                    ),
+                };
+                match read {
+                    Some(Ok(len)) => {
+                        timings.set(Phase::Read, elapsed(started));
+                        defmt::info!(
+                            "Got characteristics from cached handle: {:X}",
+                            &raw_metrics[..len]
+                        );
+                        gatt_cache.insert(addr, handle, hash);
+                        return Ok((len, true));
+                    }
+                    Some(Err(e)) => {
+                        // The services changed, e.g. after a firmware update of the device
+                        defmt::warn!(
+                            "Cached GATT handle {} failed ({:?}), rediscovering",
+                            handle,
+                            e
+                        );
+                        gatt_cache.remove(&addr);
+                    }
+                    None => {
+                        defmt::info!("GATT database hash changed, rediscovering");
+                        gatt_cache.remove(&addr);
+                    }
+                }
+            }
+
+            let started = Instant::now();
+            defmt::info!("Looking for the {} metrics service", profile.name());
+            let services = client
+                .services_by_uuid(&uuid(plan.service))
+                .await
+                .map_err(|e| BLEError::ServiceNotFound { code: att_code(&e) })?;
+            let service = services
+                .first()
+                .ok_or(BLEError::ServiceNotFound { code: None })?;
+
+            defmt::info!("Looking for the {} metrics characteristic", profile.name());
+            let characteristic: MetricsCharacteristic = client
+                .characteristic_by_uuid(&service.clone(), &uuid(plan.characteristic))
+                .await
+                .map_err(|e| BLEError::CharacteristicsNotFound { code: att_code(&e) })?;
+            let hash = read_database_hash(&client).await;
+            timings.set(Phase::Discovery, elapsed(started));
+            gatt_cache.insert(addr, characteristic.handle, hash);
+
+            let started = Instant::now();
+            let len = client
+                .read_characteristic(&characteristic, &mut raw_metrics[..])
+                .await
+                .map_err(|e| BLEError::ReadFailed { code: att_code(&e) })?;
+            timings.set(Phase::Read, elapsed(started));
+            defmt::info!("Got characteristics: {:X}", &raw_metrics[..len]);
+            Ok((len, false))
+        };
+        let (len, cached) = match select(client.task(), read).await {
+            Either::First(_) => {
+                return Err(BLEError::ConnectionProblem {
+                    stage: Stage::Link,
+                    code: None,
+                })
+            }
+            Either::Second(result) => result?,
+        };
+        profile.decode_read(&raw_metrics[..len]).map_err(|e| {
+            if cached {
+                // Readable, but not the metrics characteristic any more
+                self.gatt_cache.remove(&addr);
+            }
+            BLEError::ParseMetricsProblem(e)
         })
-        .await;
-        match scan_and_fetch_error {
-            Some(e) => Err(e),
-            None => match timeout_error {
-                Some(e) => Err(e),
-                None => match AirMetrics::from_bytes(&raw_metrics) {
-                    Ok(result) => Ok(result),
-                    Err(err) => Err(BLEError::ParseMetricsProblem(err)),
-                },
-            },
+    }
+
+    /// Finds, connects to and reads the first supported sensor around, the one with the `serial`
+    /// when given. The models only broadcasting their readings aren't connected to.
+    /// Fails with [`ParseMetricsError::NoReadings`] when the sensor measured nothing
+    pub async fn get_metrics(
+        &mut self,
+        operation_timeout: EmbassyDuration,
+        serial: Option<u32>,
+    ) -> Result<MeasurementSet, BLEError> {
+        self.timings = PhaseTimings::default();
+        self.link = None;
+        let fetch = async {
+            let started = Instant::now();
+            let found = self.find(serial).await;
+            self.timings.set(Phase::Scan, elapsed(started));
+            let (target, adv_rssi) = (found.address, found.rssi);
+            let listen = found.profile.read_plan().is_none();
+            if let (true, Some(metrics)) = (listen, found.metrics) {
+                defmt::info!("Using the advertised metrics");
+                self.link = Some(LinkQuality {
+                    adv_rssi,
+                    conn_rssi: None,
+                });
+                return Ok(metrics);
+            }
+            let started = Instant::now();
+            let conn = self.connect(target).await?;
+            self.timings.set(Phase::Connect, elapsed(started));
+            let metrics = self
+                .read(&conn, addr_bytes(&target.addr), found.profile)
+                .await?;
+            // Not every controller supports it, the advertisement RSSI is enough then
+            let conn_rssi = conn.rssi(self.stack).await.ok();
+            self.link = Some(LinkQuality {
+                adv_rssi,
+                conn_rssi,
+            });
+            Ok(metrics)
+        };
+        let metrics = with_timeout(operation_timeout, fetch)
+            .await
+            .unwrap_or_else(|_| {
+                defmt::error!("Scan timed out");
+                Err(BLEError::TimedOut)
+            })?;
+        if metrics.is_empty() {
+            return Err(BLEError::ParseMetricsProblem(ParseMetricsError::NoReadings));
         }
+        Ok(metrics)
     }
 }
 
-fn fix_adv_payload<const N: usize>(payload: &[u8], result: &mut Vec<u8, N>) {
-    // Workaround for bug https://github.com/embassy-rs/trouble/issues/137
-    if payload.is_empty() {
-        return;
+type MetricsCharacteristic = Characteristic<[u8; AirMetrics::PACKET_LEN]>;
+
+/// The metrics characteristic is only read, so it has no CCCD
+fn cached_characteristic(handle: u16) -> MetricsCharacteristic {
+    Characteristic {
+        handle,
+        cccd_handle: None,
+        phantom: PhantomData,
     }
-    let mut pos: usize = 0;
-    loop {
-        let chunk_len = payload[pos] as usize;
-        if chunk_len == 0 {
-            return;
-        }
-        result.push(payload[pos]).unwrap();
-        pos += 1;
-        let bytes_left = payload[pos..].len();
-        defmt::debug!(
-            "pos: {}, chunk_len: {}, bytes_left: {}",
-            pos,
-            chunk_len,
-            bytes_left
-        );
+}
 
-        let offset = if bytes_left < chunk_len {
-            bytes_left
-        } else {
-            chunk_len
-        };
-        result.extend(payload[pos..pos + offset].iter().cloned());
-        if bytes_left < chunk_len {
-            // Probably at the end
-            result.extend(iter::repeat(0u8).take(chunk_len - bytes_left));
-            return;
+/// Reads the Database Hash characteristic of the Generic Attribute service. Devices implementing
+/// GATT before Bluetooth 5.1 don't have it
+async fn read_database_hash<C: Controller>(
+    client: &GattClient<'_, C, 10, 27>,
+) -> Option<DatabaseHash> {
+    let services = client
+        .services_by_uuid(&Uuid::new_short(GENERIC_ATTRIBUTE_SERVICE))
+        .await
+        .ok()?;
+    let service = services.first()?;
+    let characteristic: Characteristic<[u8; 16]> = client
+        .characteristic_by_uuid(service, &Uuid::new_short(DATABASE_HASH))
+        .await
+        .ok()?;
+    let mut value = [0; 16];
+    match client
+        .read_characteristic(&characteristic, &mut value[..])
+        .await
+    {
+        Ok(16) => Some(DatabaseHash {
+            handle: characteristic.handle,
+            value,
+        }),
+        _ => None,
+    }
+}
+
+/// Whether the Database Hash differs from the cached one, or can't be read at its handle any more
+async fn database_changed<C: Controller>(
+    client: &GattClient<'_, C, 10, 27>,
+    hash: &DatabaseHash,
+) -> bool {
+    let characteristic: Characteristic<[u8; 16]> = Characteristic {
+        handle: hash.handle,
+        cccd_handle: None,
+        phantom: PhantomData,
+    };
+    let mut value = [0; 16];
+    match client
+        .read_characteristic(&characteristic, &mut value[..])
+        .await
+    {
+        Ok(16) => value != hash.value,
+        _ => true,
+    }
+}
+
+/// The error code of an ATT error response, other failures have none
+fn att_code<E>(error: &BleHostError<E>) -> Option<u8> {
+    match error {
+        BleHostError::BleHost(Error::Att(code)) => Some(u8::from(*code)),
+        _ => None,
+    }
+}
+
+/// The ATT error code or the HCI status. Failures of the transport to the controller, reported
+/// as [`BleHostError::Controller`], have neither
+fn error_code<E>(error: &BleHostError<E>) -> Option<ErrorCode> {
+    match error {
+        BleHostError::BleHost(Error::Hci(status)) => {
+            Some(ErrorCode::Hci(status.to_status().into_inner()))
         }
-        pos += chunk_len;
-        if pos >= payload.len() {
-            return;
+        BleHostError::Controller(_) => None,
+        _ => att_code(error).map(ErrorCode::Att),
+    }
+}
+
+fn uuid(uuid: GattUuid) -> Uuid {
+    match uuid {
+        GattUuid::Short(short) => Uuid::new_short(short),
+        GattUuid::Long(bytes) => Uuid::new_long(bytes),
+    }
+}
+
+/// Sensors that don't advertise a serial number never match one
+fn has_serial(profile: &dyn SensorProfile, data: &[u8], serial: Option<u32>) -> bool {
+    serial.is_none() || profile.serial(data) == serial
+}
+
+fn addr_bytes(addr: &BdAddr) -> [u8; 6] {
+    let mut bytes = [0; 6];
+    bytes.copy_from_slice(addr.raw());
+    bytes
+}
+
+fn elapsed(since: Instant) -> core::time::Duration {
+    core::time::Duration::from_micros(since.elapsed().as_micros())
+}
+
+/// [`MetricsSource`] reading through a [`BleSession`] shared with the other tasks
+pub struct BleMetricsSource<'a> {
+    session: &'a Mutex<NoopRawMutex, BleSession>,
+    operation_timeout: EmbassyDuration,
+    timings: PhaseTimings,
+    link: Option<LinkQuality>,
+    outdoor: Option<MeasurementSet>,
+    serial: Option<u32>,
+}
+
+impl<'a> BleMetricsSource<'a> {
+    pub fn new(
+        session: &'a Mutex<NoopRawMutex, BleSession>,
+        operation_timeout: EmbassyDuration,
+    ) -> Self {
+        Self {
+            session,
+            operation_timeout,
+            timings: PhaseTimings::default(),
+            link: None,
+            outdoor: None,
+            serial: None,
         }
     }
+
+    /// See [`crate::config::Config::airthings_serial`]
+    pub fn set_serial(&mut self, serial: Option<u32>) {
+        self.serial = serial;
+    }
+}
+
+impl MetricsSource for BleMetricsSource<'_> {
+    type Error = BLEError;
+
+    async fn fetch(&mut self) -> Result<MeasurementSet, BLEError> {
+        let mut session = self.session.lock().await;
+        let result = session
+            .get_metrics(self.operation_timeout, self.serial)
+            .await;
+        self.timings = *session.timings();
+        self.link = session.link_quality();
+        self.outdoor = session.outdoor();
+        result
+    }
+
+    fn fill_timings(&self, timings: &mut PhaseTimings) {
+        timings.merge(&self.timings);
+    }
+
+    fn link_quality(&self) -> Option<LinkQuality> {
+        self.link
+    }
+
+    fn outdoor(&self) -> Option<MeasurementSet> {
+        self.outdoor.clone()
+    }
 }
@@ -0,0 +1,177 @@
+//! Metrics derived from the temperature, humidity and pressure
+use libm::{expf, fabsf, logf, powf, sqrtf};
+
+use crate::measurement::{MeasurementSet, Quantity};
+use crate::metrics::AirMetrics;
+
+// Magnus formula coefficients (Sonntag 1990), valid for -45..60 °C
+const MAGNUS_A: f32 = 17.62;
+const MAGNUS_B: f32 = 243.12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, defmt::Format)]
+pub enum MouldRisk {
+    Low,
+    Moderate,
+    High,
+}
+
+impl MouldRisk {
+    /// Mould needs both humidity and a temperature it can grow in
+    pub fn estimate(temperature: f32, humidity: f32) -> Self {
+        if !(5.0..=50.0).contains(&temperature) {
+            return Self::Low;
+        }
+        if humidity >= 80.0 {
+            Self::High
+        } else if humidity >= 70.0 {
+            Self::Moderate
+        } else {
+            Self::Low
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Low => "low",
+            Self::Moderate => "mid",
+            Self::High => "high",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+pub struct ComfortMetrics {
+    /// °C
+    pub dew_point: f32,
+    /// g/m3
+    pub absolute_humidity: f32,
+    /// Apparent temperature, °C
+    pub heat_index: f32,
+    pub mould_risk: MouldRisk,
+    /// hPa, only known with the altitude configured on a device measuring the pressure
+    pub sea_level_pressure: Option<f32>,
+}
+
+impl ComfortMetrics {
+    /// `altitude` of the sensor is in meters above the sea level
+    pub fn new(metrics: &AirMetrics, altitude: Option<i16>) -> Self {
+        Self::derive(
+            metrics.temperature,
+            metrics.humidity,
+            metrics.pressure,
+            altitude,
+        )
+    }
+
+    /// `None` without the temperature or the humidity, e.g. for the CO2 or PM only sensors
+    pub fn from_measurements(measurements: &MeasurementSet, altitude: Option<i16>) -> Option<Self> {
+        let t = measurements.value(Quantity::Temperature)?;
+        let rh = measurements.value(Quantity::Humidity)?;
+        let pressure = measurements.value(Quantity::Pressure);
+        Some(Self::derive(t, rh, pressure, altitude))
+    }
+
+    fn derive(t: f32, rh: f32, pressure: Option<f32>, altitude: Option<i16>) -> Self {
+        Self {
+            dew_point: dew_point(t, rh),
+            absolute_humidity: absolute_humidity(t, rh),
+            heat_index: heat_index(t, rh),
+            mould_risk: MouldRisk::estimate(t, rh),
+            sea_level_pressure: altitude
+                .zip(pressure)
+                .map(|(h, p)| sea_level_pressure(p, t, h as f32)),
+        }
+    }
+}
+
+pub fn dew_point(temperature: f32, humidity: f32) -> f32 {
+    // ln(0) is undefined, dry air has the dew point far below anything we can measure anyway
+    let gamma = logf(humidity.max(1.0) / 100.0) + MAGNUS_A * temperature / (MAGNUS_B + temperature);
+    MAGNUS_B * gamma / (MAGNUS_A - gamma)
+}
+
+pub fn absolute_humidity(temperature: f32, humidity: f32) -> f32 {
+    // Saturation vapour pressure in hPa, then the ideal gas law for water vapour
+    let saturation = 6.112 * expf(MAGNUS_A * temperature / (MAGNUS_B + temperature));
+    saturation * humidity * 2.1674 / (273.15 + temperature)
+}
+
+/// NOAA heat index (Steadman/Rothfusz), takes and returns °C
+pub fn heat_index(temperature: f32, humidity: f32) -> f32 {
+    let t = temperature * 9.0 / 5.0 + 32.0;
+    let rh = humidity;
+    let simple = 0.5 * (t + 61.0 + (t - 68.0) * 1.2 + rh * 0.094);
+    let hi = if (simple + t) / 2.0 < 80.0 {
+        simple
+    } else {
+        let mut hi = -42.379 + 2.049_015_2 * t + 10.143_331 * rh
+            - 0.224_755_4 * t * rh
+            - 0.006_837_83 * t * t
+            - 0.054_817_17 * rh * rh
+            + 0.001_228_74 * t * t * rh
+            + 0.000_852_82 * t * rh * rh
+            - 0.000_001_99 * t * t * rh * rh;
+        if rh < 13.0 && (80.0..=112.0).contains(&t) {
+            hi -= (13.0 - rh) / 4.0 * sqrtf((17.0 - fabsf(t - 95.0)) / 17.0);
+        } else if rh > 85.0 && (80.0..=87.0).contains(&t) {
+            hi += (rh - 85.0) / 10.0 * (87.0 - t) / 5.0;
+        }
+        hi
+    };
+    (hi - 32.0) * 5.0 / 9.0
+}
+
+/// Reduces the station pressure (hPa) to the sea level using the barometric formula
+pub fn sea_level_pressure(pressure: f32, temperature: f32, altitude: f32) -> f32 {
+    let lapse = 0.0065 * altitude;
+    pressure * powf(1.0 - lapse / (temperature + lapse + 273.15), -5.257)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(value: f32, expected: f32, tolerance: f32) {
+        assert!(
+            (value - expected).abs() <= tolerance,
+            "{} is not {} ± {}",
+            value,
+            expected,
+            tolerance
+        );
+    }
+
+    #[test]
+    fn dew_point_references() {
+        assert_near(dew_point(20.0, 50.0), 9.3, 0.1);
+        assert_near(dew_point(30.0, 80.0), 26.2, 0.1);
+        assert_near(dew_point(-10.0, 90.0), -11.3, 0.2);
+        // Saturated air
+        assert_near(dew_point(15.0, 100.0), 15.0, 0.01);
+    }
+
+    #[test]
+    fn absolute_humidity_references() {
+        assert_near(absolute_humidity(20.0, 50.0), 8.65, 0.05);
+        assert_near(absolute_humidity(30.0, 100.0), 30.4, 0.2);
+    }
+
+    /// Values of the NOAA heat index chart, converted from °F
+    #[test]
+    fn heat_index_references() {
+        let fahrenheit = |f: f32| (f - 32.0) * 5.0 / 9.0;
+        // Simple formula below 80 °F
+        assert_near(heat_index(fahrenheit(70.0), 50.0), fahrenheit(69.0), 0.5);
+        assert_near(heat_index(fahrenheit(90.0), 70.0), fahrenheit(106.0), 0.5);
+        assert_near(heat_index(fahrenheit(100.0), 40.0), fahrenheit(109.0), 0.5);
+        assert_near(heat_index(fahrenheit(84.0), 90.0), fahrenheit(98.0), 0.5);
+    }
+
+    #[test]
+    fn sea_level_pressure_references() {
+        assert_eq!(sea_level_pressure(1000.0, 15.0, 0.0), 1000.0);
+        // International standard atmosphere at 500 m and 1000 m
+        assert_near(sea_level_pressure(954.6, 11.75, 500.0), 1013.25, 0.5);
+        assert_near(sea_level_pressure(898.8, 8.5, 1000.0), 1013.25, 0.5);
+    }
+}
@@ -0,0 +1,568 @@
+//! User settings kept in the persistent storage
+//!
+//! Serialized as a version byte followed by `key, length, value` entries,
+//! so new settings can be added without invalidating stored configs.
+use core::fmt;
+use heapless::{String, Vec};
+
+use crate::alert::{AlertRule, ALERT_RULES};
+use crate::particulates::PmSensorModel;
+use crate::platform::{Slot, Storage};
+use crate::timing::CurrentProfile;
+use crate::units::{PressureUnit, RadonUnit, TemperatureUnit, UnitProfile};
+
+const CONFIG_VERSION: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum ConfigError {
+    UnsupportedVersion,
+    Truncated,
+    Overflow,
+    InvalidValue(ConfigKey),
+}
+
+/// Setting identifiers. The discriminants are stored in the config, don't change them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum ConfigKey {
+    TemperatureUnit = 1,
+    PressureUnit = 2,
+    RadonUnit = 3,
+    Altitude = 4,
+    WifiSsid = 5,
+    WifiPassphrase = 6,
+    MqttBroker = 7,
+    AirthingsSerial = 8,
+    McuCurrent = 9,
+    RadioCurrent = 10,
+    DisplayCurrent = 11,
+    SleepCurrent = 12,
+    // 13 was the passive scan switch, don't reuse it
+    /// See [`crate::alert`]
+    Alert1 = 14,
+    Alert2 = 15,
+    Alert3 = 16,
+    Alert4 = 17,
+    /// See [`crate::scd4x`]
+    Scd4x = 18,
+    /// See [`crate::particulates`]
+    PmSensor = 19,
+}
+
+impl ConfigKey {
+    pub const ALL: [Self; 18] = [
+        Self::TemperatureUnit,
+        Self::PressureUnit,
+        Self::RadonUnit,
+        Self::Altitude,
+        Self::WifiSsid,
+        Self::WifiPassphrase,
+        Self::MqttBroker,
+        Self::AirthingsSerial,
+        Self::McuCurrent,
+        Self::RadioCurrent,
+        Self::DisplayCurrent,
+        Self::SleepCurrent,
+        Self::Alert1,
+        Self::Alert2,
+        Self::Alert3,
+        Self::Alert4,
+        Self::Scd4x,
+        Self::PmSensor,
+    ];
+
+    const ALERTS: [Self; ALERT_RULES] = [Self::Alert1, Self::Alert2, Self::Alert3, Self::Alert4];
+
+    const CURRENTS: [Self; 4] = [
+        Self::McuCurrent,
+        Self::RadioCurrent,
+        Self::DisplayCurrent,
+        Self::SleepCurrent,
+    ];
+
+    fn from_u8(key: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|k| *k as u8 == key)
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|k| k.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::TemperatureUnit => "temperature_unit",
+            Self::PressureUnit => "pressure_unit",
+            Self::RadonUnit => "radon_unit",
+            Self::Altitude => "altitude",
+            Self::WifiSsid => "wifi_ssid",
+            Self::WifiPassphrase => "wifi_passphrase",
+            Self::MqttBroker => "mqtt_broker",
+            Self::AirthingsSerial => "airthings_serial",
+            Self::McuCurrent => "mcu_current_ma",
+            Self::RadioCurrent => "radio_current_ma",
+            Self::DisplayCurrent => "display_current_ma",
+            Self::SleepCurrent => "sleep_current_ua",
+            Self::Alert1 => "alert_1",
+            Self::Alert2 => "alert_2",
+            Self::Alert3 => "alert_3",
+            Self::Alert4 => "alert_4",
+            Self::Scd4x => "scd4x",
+            Self::PmSensor => "pm_sensor",
+        }
+    }
+
+    /// Index of the alert rule set by the key
+    fn alert(self) -> Option<usize> {
+        Self::ALERTS.iter().position(|k| *k == self)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Config {
+    pub units: UnitProfile,
+    /// Meters above the sea level, used to correct the pressure
+    pub altitude: Option<i16>,
+    pub wifi_ssid: String<32>,
+    /// Empty for open networks
+    pub wifi_passphrase: String<63>,
+    /// `host[:port]`
+    pub mqtt_broker: String<64>,
+    /// Serial number of the Airthings device to read. Any is used when not set
+    pub airthings_serial: Option<u32>,
+    /// Used for the battery life estimate, see [`crate::timing`]
+    pub currents: CurrentProfile,
+    pub alerts: [Option<AlertRule>; ALERT_RULES],
+    /// Measure with the SCD41 on the Qw/ST connector instead of reading a sensor over BLE
+    pub scd4x: bool,
+    /// Particulate matter sensor on the Qw/ST connector, read along with the other metrics
+    pub pm_sensor: Option<PmSensorModel>,
+}
+
+impl Config {
+    pub const MAX_SIZE: usize = 320;
+
+    /// Reads the config from the storage, falling back to the defaults
+    pub async fn load<S: Storage>(storage: &mut S) -> Self {
+        let mut buf = [0; Self::MAX_SIZE];
+        let len = match storage.load(Slot::Config, &mut buf).await {
+            Ok(len) => len,
+            Err(e) => {
+                defmt::error!("Config load failed: {:?}", defmt::Debug2Format(&e));
+                0
+            }
+        };
+        if len == 0 {
+            return Self::default();
+        }
+        Self::from_bytes(&buf[..len]).unwrap_or_else(|e| {
+            defmt::error!("Stored config is invalid: {:?}", e);
+            Self::default()
+        })
+    }
+
+    pub async fn save<S: Storage>(&self, storage: &mut S) -> Result<(), S::Error> {
+        // Config always fits into MAX_SIZE
+        let bytes = self.to_bytes().unwrap();
+        storage.store(Slot::Config, &bytes).await
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ConfigError> {
+        let Some((&version, mut entries)) = bytes.split_first() else {
+            return Err(ConfigError::Truncated);
+        };
+        if version != CONFIG_VERSION {
+            return Err(ConfigError::UnsupportedVersion);
+        }
+        let mut config = Self::default();
+        while let [key, len, rest @ ..] = entries {
+            let value = rest.get(..*len as usize).ok_or(ConfigError::Truncated)?;
+            entries = &rest[value.len()..];
+            // Unknown keys and values are skipped to stay compatible with other firmware versions
+            let Some(key) = ConfigKey::from_u8(*key) else {
+                continue;
+            };
+            if config.apply(key, value).is_err() {
+                defmt::warn!("Ignoring invalid config value for {}", key);
+            }
+        }
+        if !entries.is_empty() {
+            return Err(ConfigError::Truncated);
+        }
+        Ok(config)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8, { Self::MAX_SIZE }>, ConfigError> {
+        let mut bytes = Vec::new();
+        bytes
+            .push(CONFIG_VERSION)
+            .map_err(|_| ConfigError::Overflow)?;
+        let units = &self.units;
+        put(
+            &mut bytes,
+            ConfigKey::TemperatureUnit,
+            &[units.temperature as u8],
+        )?;
+        put(&mut bytes, ConfigKey::PressureUnit, &[units.pressure as u8])?;
+        put(&mut bytes, ConfigKey::RadonUnit, &[units.radon as u8])?;
+        if let Some(altitude) = self.altitude {
+            put(&mut bytes, ConfigKey::Altitude, &altitude.to_le_bytes())?;
+        }
+        put(&mut bytes, ConfigKey::WifiSsid, self.wifi_ssid.as_bytes())?;
+        put(
+            &mut bytes,
+            ConfigKey::WifiPassphrase,
+            self.wifi_passphrase.as_bytes(),
+        )?;
+        put(
+            &mut bytes,
+            ConfigKey::MqttBroker,
+            self.mqtt_broker.as_bytes(),
+        )?;
+        if let Some(serial) = self.airthings_serial {
+            put(
+                &mut bytes,
+                ConfigKey::AirthingsSerial,
+                &serial.to_le_bytes(),
+            )?;
+        }
+        for key in ConfigKey::CURRENTS {
+            let current = *self.current(key).unwrap();
+            put(&mut bytes, key, &current.to_le_bytes())?;
+        }
+        for (key, rule) in ConfigKey::ALERTS.into_iter().zip(&self.alerts) {
+            if let Some(rule) = rule {
+                put(&mut bytes, key, &rule.to_bytes())?;
+            }
+        }
+        put(&mut bytes, ConfigKey::Scd4x, &[self.scd4x as u8])?;
+        if let Some(model) = self.pm_sensor {
+            put(&mut bytes, ConfigKey::PmSensor, &[model as u8])?;
+        }
+        Ok(bytes)
+    }
+
+    /// Sets a value from its text form, as typed by the user. Empty value clears optional settings
+    pub fn set(&mut self, key: ConfigKey, value: &str) -> Result<(), ConfigError> {
+        let invalid = ConfigError::InvalidValue(key);
+        let units = &mut self.units;
+        match key {
+            ConfigKey::TemperatureUnit => {
+                units.temperature = [TemperatureUnit::Celsius, TemperatureUnit::Fahrenheit]
+                    .into_iter()
+                    .find(|u| u.symbol().eq_ignore_ascii_case(value))
+                    .ok_or(invalid)?
+            }
+            ConfigKey::PressureUnit => {
+                units.pressure = [
+                    PressureUnit::Hectopascal,
+                    PressureUnit::Kilopascal,
+                    PressureUnit::InchOfMercury,
+                    PressureUnit::MillimeterOfMercury,
+                ]
+                .into_iter()
+                .find(|u| u.symbol().eq_ignore_ascii_case(value))
+                .ok_or(invalid)?
+            }
+            ConfigKey::RadonUnit => {
+                units.radon = [
+                    RadonUnit::BecquerelPerCubicMeter,
+                    RadonUnit::PicocuriePerLiter,
+                ]
+                .into_iter()
+                .find(|u| u.symbol().eq_ignore_ascii_case(value))
+                .ok_or(invalid)?
+            }
+            ConfigKey::Altitude => {
+                self.altitude = match value {
+                    "" => None,
+                    value => Some(value.parse().map_err(|_| invalid)?),
+                }
+            }
+            ConfigKey::AirthingsSerial => {
+                // Airthings serial numbers have 10 digits
+                self.airthings_serial = match value {
+                    "" => None,
+                    value if value.len() == 10 => Some(value.parse().map_err(|_| invalid)?),
+                    _ => return Err(invalid),
+                }
+            }
+            ConfigKey::WifiSsid | ConfigKey::WifiPassphrase | ConfigKey::MqttBroker => {
+                self.apply(key, value.as_bytes())?
+            }
+            ConfigKey::McuCurrent
+            | ConfigKey::RadioCurrent
+            | ConfigKey::DisplayCurrent
+            | ConfigKey::SleepCurrent => {
+                let current = value.parse().map_err(|_| invalid)?;
+                *self.current_mut(key).unwrap() = current;
+            }
+            ConfigKey::Alert1 | ConfigKey::Alert2 | ConfigKey::Alert3 | ConfigKey::Alert4 => {
+                let rule = &mut self.alerts[key.alert().unwrap()];
+                *rule = match value {
+                    "" => None,
+                    value => Some(AlertRule::parse(value).map_err(|_| invalid)?),
+                }
+            }
+            ConfigKey::Scd4x => self.scd4x = parse_switch(value).ok_or(invalid)?,
+            ConfigKey::PmSensor => {
+                self.pm_sensor = match value {
+                    "" => None,
+                    value => Some(
+                        PmSensorModel::ALL
+                            .into_iter()
+                            .find(|m| m.name() == value)
+                            .ok_or(invalid)?,
+                    ),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the text form of a value. The passphrase is masked
+    pub fn get<W: fmt::Write>(&self, key: ConfigKey, out: &mut W) -> fmt::Result {
+        let units = &self.units;
+        match key {
+            ConfigKey::TemperatureUnit => out.write_str(units.temperature.symbol()),
+            ConfigKey::PressureUnit => out.write_str(units.pressure.symbol()),
+            ConfigKey::RadonUnit => out.write_str(units.radon.symbol()),
+            ConfigKey::Altitude => match self.altitude {
+                Some(altitude) => write!(out, "{}", altitude),
+                None => Ok(()),
+            },
+            ConfigKey::WifiSsid => out.write_str(&self.wifi_ssid),
+            ConfigKey::WifiPassphrase if self.wifi_passphrase.is_empty() => Ok(()),
+            ConfigKey::WifiPassphrase => out.write_str("********"),
+            ConfigKey::MqttBroker => out.write_str(&self.mqtt_broker),
+            ConfigKey::AirthingsSerial => match self.airthings_serial {
+                Some(serial) => write!(out, "{}", serial),
+                None => Ok(()),
+            },
+            ConfigKey::McuCurrent
+            | ConfigKey::RadioCurrent
+            | ConfigKey::DisplayCurrent
+            | ConfigKey::SleepCurrent => write!(out, "{}", self.current(key).unwrap()),
+            ConfigKey::Alert1 | ConfigKey::Alert2 | ConfigKey::Alert3 | ConfigKey::Alert4 => {
+                match &self.alerts[key.alert().unwrap()] {
+                    Some(rule) => write!(out, "{}", rule),
+                    None => Ok(()),
+                }
+            }
+            ConfigKey::Scd4x => out.write_str(switch(self.scd4x)),
+            ConfigKey::PmSensor => out.write_str(self.pm_sensor.map_or("", PmSensorModel::name)),
+        }
+    }
+
+    fn current(&self, key: ConfigKey) -> Option<&u16> {
+        let currents = &self.currents;
+        match key {
+            ConfigKey::McuCurrent => Some(&currents.mcu_ma),
+            ConfigKey::RadioCurrent => Some(&currents.radio_ma),
+            ConfigKey::DisplayCurrent => Some(&currents.display_ma),
+            ConfigKey::SleepCurrent => Some(&currents.sleep_ua),
+            _ => None,
+        }
+    }
+
+    fn current_mut(&mut self, key: ConfigKey) -> Option<&mut u16> {
+        let currents = &mut self.currents;
+        match key {
+            ConfigKey::McuCurrent => Some(&mut currents.mcu_ma),
+            ConfigKey::RadioCurrent => Some(&mut currents.radio_ma),
+            ConfigKey::DisplayCurrent => Some(&mut currents.display_ma),
+            ConfigKey::SleepCurrent => Some(&mut currents.sleep_ua),
+            _ => None,
+        }
+    }
+
+    /// Sets a value from its stored form
+    fn apply(&mut self, key: ConfigKey, value: &[u8]) -> Result<(), ConfigError> {
+        let invalid = ConfigError::InvalidValue(key);
+        let units = &mut self.units;
+        match (key, value) {
+            (ConfigKey::TemperatureUnit, [0]) => units.temperature = TemperatureUnit::Celsius,
+            (ConfigKey::TemperatureUnit, [1]) => units.temperature = TemperatureUnit::Fahrenheit,
+            (ConfigKey::PressureUnit, [0]) => units.pressure = PressureUnit::Hectopascal,
+            (ConfigKey::PressureUnit, [1]) => units.pressure = PressureUnit::Kilopascal,
+            (ConfigKey::PressureUnit, [2]) => units.pressure = PressureUnit::InchOfMercury,
+            (ConfigKey::PressureUnit, [3]) => units.pressure = PressureUnit::MillimeterOfMercury,
+            (ConfigKey::RadonUnit, [0]) => units.radon = RadonUnit::BecquerelPerCubicMeter,
+            (ConfigKey::RadonUnit, [1]) => units.radon = RadonUnit::PicocuriePerLiter,
+            (ConfigKey::Altitude, &[lo, hi]) => self.altitude = Some(i16::from_le_bytes([lo, hi])),
+            (ConfigKey::WifiSsid, ssid) => self.wifi_ssid = to_string(ssid).ok_or(invalid)?,
+            (ConfigKey::WifiPassphrase, passphrase) => {
+                // WPA2 passphrase is 8..63 printable ASCII characters
+                let printable = passphrase.iter().all(|c| (0x20..0x7f).contains(c));
+                if !passphrase.is_empty() && (passphrase.len() < 8 || !printable) {
+                    return Err(invalid);
+                }
+                self.wifi_passphrase = to_string(passphrase).ok_or(invalid)?;
+            }
+            (ConfigKey::MqttBroker, broker) => {
+                let broker = core::str::from_utf8(broker).map_err(|_| invalid)?;
+                let valid = match broker.split_once(':') {
+                    Some((host, port)) => is_hostname(host) && port.parse::<u16>().is_ok(),
+                    None => broker.is_empty() || is_hostname(broker),
+                };
+                if !valid {
+                    return Err(invalid);
+                }
+                self.mqtt_broker = to_string(broker.as_bytes()).ok_or(invalid)?;
+            }
+            (ConfigKey::AirthingsSerial, &[a, b, c, d]) => {
+                self.airthings_serial = Some(u32::from_le_bytes([a, b, c, d]))
+            }
+            (key, &[lo, hi]) if ConfigKey::CURRENTS.contains(&key) => {
+                *self.current_mut(key).unwrap() = u16::from_le_bytes([lo, hi])
+            }
+            (ConfigKey::Scd4x, [0]) => self.scd4x = false,
+            (ConfigKey::Scd4x, [1]) => self.scd4x = true,
+            (ConfigKey::PmSensor, &[model]) => {
+                self.pm_sensor = Some(
+                    PmSensorModel::ALL
+                        .into_iter()
+                        .find(|m| *m as u8 == model)
+                        .ok_or(invalid)?,
+                )
+            }
+            (key, rule) if ConfigKey::ALERTS.contains(&key) => {
+                self.alerts[key.alert().unwrap()] =
+                    Some(AlertRule::from_bytes(rule).ok_or(invalid)?)
+            }
+            _ => return Err(invalid),
+        }
+        Ok(())
+    }
+}
+
+fn parse_switch(value: &str) -> Option<bool> {
+    match value {
+        "on" => Some(true),
+        "off" => Some(false),
+        _ => None,
+    }
+}
+
+fn switch(on: bool) -> &'static str {
+    if on {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+fn is_hostname(host: &str) -> bool {
+    !host.is_empty()
+        && host
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || c == b'.' || c == b'-')
+}
+
+fn to_string<const N: usize>(bytes: &[u8]) -> Option<String<N>> {
+    let mut string = String::new();
+    string.push_str(core::str::from_utf8(bytes).ok()?).ok()?;
+    Some(string)
+}
+
+fn put<const N: usize>(
+    bytes: &mut Vec<u8, N>,
+    key: ConfigKey,
+    value: &[u8],
+) -> Result<(), ConfigError> {
+    bytes.push(key as u8).map_err(|_| ConfigError::Overflow)?;
+    bytes
+        .push(value.len() as u8)
+        .map_err(|_| ConfigError::Overflow)?;
+    bytes
+        .extend_from_slice(value)
+        .map_err(|_| ConfigError::Overflow)
+}
+
+#[cfg(test)]
+mod tests {
+    use heapless::String;
+
+    use super::*;
+
+    fn configured() -> Config {
+        let mut config = Config::default();
+        let settings = [
+            (ConfigKey::TemperatureUnit, "F"),
+            (ConfigKey::PressureUnit, "mmHg"),
+            (ConfigKey::RadonUnit, "pCi/L"),
+            (ConfigKey::Altitude, "-12"),
+            (ConfigKey::WifiSsid, "home"),
+            (ConfigKey::WifiPassphrase, "correct horse"),
+            (ConfigKey::MqttBroker, "broker.local:1883"),
+            (ConfigKey::AirthingsSerial, "2930012345"),
+            (ConfigKey::McuCurrent, "25"),
+            (ConfigKey::RadioCurrent, "45"),
+            (ConfigKey::DisplayCurrent, "6"),
+            (ConfigKey::SleepCurrent, "3"),
+            (ConfigKey::Alert1, "co2 > 1400 100"),
+            (ConfigKey::Alert3, "humidity < 30 5"),
+            (ConfigKey::Scd4x, "on"),
+            (ConfigKey::PmSensor, "sen5x"),
+        ];
+        for (key, value) in settings {
+            config.set(key, value).unwrap();
+        }
+        config
+    }
+
+    #[test]
+    fn every_setting_round_trips() {
+        let config = configured();
+        assert_ne!(config, Config::default());
+        let bytes = config.to_bytes().unwrap();
+        assert_eq!(Config::from_bytes(&bytes), Ok(config));
+    }
+
+    #[test]
+    fn text_values_round_trip() {
+        let config = configured();
+        for key in ConfigKey::ALL {
+            let mut value = String::<64>::new();
+            config.get(key, &mut value).unwrap();
+            if key == ConfigKey::WifiPassphrase {
+                assert_eq!(value, "********");
+                continue;
+            }
+            let mut parsed = Config::default();
+            parsed.set(key, &value).unwrap();
+            let mut again = String::<64>::new();
+            parsed.get(key, &mut again).unwrap();
+            assert_eq!(again, value, "{}", key.name());
+        }
+    }
+
+    #[test]
+    fn unknown_and_invalid_entries_are_skipped() {
+        let mut bytes = Config::default().to_bytes().unwrap();
+        // Unknown key, then a Fahrenheit temperature unit and an invalid radon unit
+        bytes.extend_from_slice(&[0xee, 2, 1, 2]).unwrap();
+        bytes
+            .extend_from_slice(&[ConfigKey::TemperatureUnit as u8, 1, 1])
+            .unwrap();
+        bytes
+            .extend_from_slice(&[ConfigKey::RadonUnit as u8, 1, 9])
+            .unwrap();
+        let config = Config::from_bytes(&bytes).unwrap();
+        assert_eq!(config.units.temperature, TemperatureUnit::Fahrenheit);
+        assert_eq!(config.units.radon, RadonUnit::BecquerelPerCubicMeter);
+    }
+
+    #[test]
+    fn truncated_entries_are_rejected() {
+        let bytes = configured().to_bytes().unwrap();
+        assert_eq!(Config::from_bytes(&[]), Err(ConfigError::Truncated));
+        assert_eq!(
+            Config::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(ConfigError::Truncated)
+        );
+        assert_eq!(
+            Config::from_bytes(&[CONFIG_VERSION + 1]),
+            Err(ConfigError::UnsupportedVersion)
+        );
+    }
+}
@@ -0,0 +1,207 @@
+//! Serial console command parser
+use crate::config::ConfigKey;
+
+pub const HELP: &str = "\
+get [key]         show settings\r\n\
+set <key> <value> change a setting, empty value clears it\r\n\
+                  alert levels in C, %, hPa, ppm, ppb, Bq/m3, ug/m3 whatever the units\r\n\
+scan              list nearby sensors, only airthings_serial when set\r\n\
+read              fetch and print the metrics\r\n\
+history dump      print the stored readings as CSV\r\n\
+timings dump      print the cycle timings and battery estimates as CSV\r\n\
+scd4x asc on|off  turn the SCD4x automatic self calibration on or off\r\n\
+scd4x frc <ppm>   measure for 3 minutes, then calibrate the SCD4x to the CO2 level\r\n\
+reboot            restart the firmware\r\n\
+bootsel           restart into the USB bootloader\r\n";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command<'a> {
+    Help,
+    /// Every setting when the key isn't given
+    Get(Option<ConfigKey>),
+    Set(ConfigKey, &'a str),
+    Scan,
+    Read,
+    HistoryDump,
+    TimingsDump,
+    /// See [`crate::scd4x::Scd4x::set_automatic_self_calibration`]
+    Scd4xSelfCalibration(bool),
+    /// Target CO2 level in ppm, see [`crate::scd4x::Scd4xSource::recalibrate`]
+    Scd4xRecalibrate(u16),
+    Reboot,
+    Bootsel,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError<'a> {
+    Empty,
+    UnknownCommand(&'a str),
+    UnknownKey(&'a str),
+    MissingArgument(&'static str),
+    UnexpectedArgument(&'a str),
+    InvalidArgument(&'a str),
+}
+
+impl core::fmt::Display for ParseError<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Empty => Ok(()),
+            Self::UnknownCommand(command) => write!(f, "unknown command '{}', try 'help'", command),
+            Self::UnknownKey(key) => write!(f, "unknown key '{}'", key),
+            Self::MissingArgument(name) => write!(f, "missing {}", name),
+            Self::UnexpectedArgument(arg) => write!(f, "unexpected '{}'", arg),
+            Self::InvalidArgument(arg) => write!(f, "invalid '{}'", arg),
+        }
+    }
+}
+
+pub fn parse(line: &str) -> Result<Command<'_>, ParseError<'_>> {
+    let line = line.trim();
+    let (command, args) = line
+        .split_once(char::is_whitespace)
+        .map(|(command, args)| (command, args.trim_start()))
+        .unwrap_or((line, ""));
+    let command = match command {
+        "" => return Err(ParseError::Empty),
+        "help" | "?" => Command::Help,
+        "get" => {
+            let key = match args {
+                "" => None,
+                name => Some(ConfigKey::from_name(name).ok_or(ParseError::UnknownKey(name))?),
+            };
+            return Ok(Command::Get(key));
+        }
+        "set" => {
+            let (name, value) = args
+                .split_once(char::is_whitespace)
+                .map(|(name, value)| (name, value.trim_start()))
+                .unwrap_or((args, ""));
+            if name.is_empty() {
+                return Err(ParseError::MissingArgument("key"));
+            }
+            let key = ConfigKey::from_name(name).ok_or(ParseError::UnknownKey(name))?;
+            return Ok(Command::Set(key, value));
+        }
+        "scan" => Command::Scan,
+        "read" => Command::Read,
+        "history" => return dump(args, Command::HistoryDump),
+        "timings" => return dump(args, Command::TimingsDump),
+        "scd4x" => return scd4x(args),
+        "reboot" => Command::Reboot,
+        "bootsel" => Command::Bootsel,
+        other => return Err(ParseError::UnknownCommand(other)),
+    };
+    match args {
+        "" => Ok(command),
+        other => Err(ParseError::UnexpectedArgument(other)),
+    }
+}
+
+fn dump<'a>(args: &'a str, command: Command<'a>) -> Result<Command<'a>, ParseError<'a>> {
+    match args {
+        "dump" => Ok(command),
+        "" => Err(ParseError::MissingArgument("'dump'")),
+        other => Err(ParseError::UnexpectedArgument(other)),
+    }
+}
+
+fn scd4x(args: &str) -> Result<Command<'_>, ParseError<'_>> {
+    let (subcommand, value) = args
+        .split_once(char::is_whitespace)
+        .map(|(subcommand, value)| (subcommand, value.trim_start()))
+        .unwrap_or((args, ""));
+    match (subcommand, value) {
+        ("", _) => Err(ParseError::MissingArgument("'asc' or 'frc'")),
+        ("asc", "on") => Ok(Command::Scd4xSelfCalibration(true)),
+        ("asc", "off") => Ok(Command::Scd4xSelfCalibration(false)),
+        ("asc", "") => Err(ParseError::MissingArgument("'on' or 'off'")),
+        ("frc", "") => Err(ParseError::MissingArgument("ppm")),
+        ("frc", ppm) => ppm
+            .parse()
+            .map(Command::Scd4xRecalibrate)
+            .map_err(|_| ParseError::InvalidArgument(ppm)),
+        ("asc", other) | (other, _) => Err(ParseError::UnexpectedArgument(other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_without_arguments() {
+        assert_eq!(parse("help"), Ok(Command::Help));
+        assert_eq!(parse(" ? \r\n"), Ok(Command::Help));
+        assert_eq!(parse("scan"), Ok(Command::Scan));
+        assert_eq!(parse("read"), Ok(Command::Read));
+        assert_eq!(parse("reboot"), Ok(Command::Reboot));
+        assert_eq!(parse("bootsel"), Ok(Command::Bootsel));
+        assert_eq!(
+            parse("read now"),
+            Err(ParseError::UnexpectedArgument("now"))
+        );
+    }
+
+    #[test]
+    fn get_takes_an_optional_key() {
+        assert_eq!(parse("get"), Ok(Command::Get(None)));
+        assert_eq!(
+            parse("get  wifi_ssid"),
+            Ok(Command::Get(Some(ConfigKey::WifiSsid)))
+        );
+        assert_eq!(parse("get wifi"), Err(ParseError::UnknownKey("wifi")));
+    }
+
+    #[test]
+    fn set_keeps_the_rest_of_the_line() {
+        assert_eq!(
+            parse("set wifi_ssid  My Network "),
+            Ok(Command::Set(ConfigKey::WifiSsid, "My Network"))
+        );
+        // Clears the setting
+        assert_eq!(
+            parse("set airthings_serial"),
+            Ok(Command::Set(ConfigKey::AirthingsSerial, ""))
+        );
+        assert_eq!(parse("set"), Err(ParseError::MissingArgument("key")));
+        assert_eq!(parse("set ssid x"), Err(ParseError::UnknownKey("ssid")));
+    }
+
+    #[test]
+    fn dumps_need_the_subcommand() {
+        assert_eq!(parse("history dump"), Ok(Command::HistoryDump));
+        assert_eq!(parse("timings dump"), Ok(Command::TimingsDump));
+        assert_eq!(parse("history"), Err(ParseError::MissingArgument("'dump'")));
+        assert_eq!(
+            parse("timings clear"),
+            Err(ParseError::UnexpectedArgument("clear"))
+        );
+    }
+
+    #[test]
+    fn scd4x_calibration() {
+        assert_eq!(
+            parse("scd4x asc off"),
+            Ok(Command::Scd4xSelfCalibration(false))
+        );
+        assert_eq!(parse("scd4x frc  420"), Ok(Command::Scd4xRecalibrate(420)));
+        assert_eq!(
+            parse("scd4x frc 4e2"),
+            Err(ParseError::InvalidArgument("4e2"))
+        );
+        assert_eq!(
+            parse("scd4x asc"),
+            Err(ParseError::MissingArgument("'on' or 'off'"))
+        );
+        assert_eq!(
+            parse("scd4x reset"),
+            Err(ParseError::UnexpectedArgument("reset"))
+        );
+    }
+
+    #[test]
+    fn invalid_lines_are_reported() {
+        assert_eq!(parse("   "), Err(ParseError::Empty));
+        assert_eq!(parse("status"), Err(ParseError::UnknownCommand("status")));
+    }
+}
@@ -0,0 +1,77 @@
+use core::convert::Infallible;
+use embedded_graphics::{pixelcolor::BinaryColor, prelude::*};
+
+use crate::screens::{HEIGHT, WIDTH};
+
+const ROW_BYTES: usize = (WIDTH as usize).div_ceil(8);
+
+/// In-memory 1bpp framebuffer with the same geometry as the Badger display.
+/// Used to render screens off the device, e.g. by the simulator.
+pub struct Framebuffer {
+    buffer: [u8; ROW_BYTES * HEIGHT as usize],
+}
+
+impl Framebuffer {
+    pub fn new() -> Self {
+        Self {
+            buffer: [0; ROW_BYTES * HEIGHT as usize],
+        }
+    }
+
+    pub fn pixel(&self, point: Point) -> Option<BinaryColor> {
+        let (index, mask) = Self::locate(point)?;
+        Some(if self.buffer[index] & mask != 0 {
+            BinaryColor::Off
+        } else {
+            BinaryColor::On
+        })
+    }
+
+    /// Raster in PBM bit order: rows of MSB-first bits, `1` is black.
+    /// The UC8151 driver draws `BinaryColor::Off` as black, so does this.
+    pub fn as_pbm_data(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    fn locate(point: Point) -> Option<(usize, u8)> {
+        let (x, y) = (point.x, point.y);
+        if x < 0 || y < 0 || x >= WIDTH as i32 || y >= HEIGHT as i32 {
+            return None;
+        }
+        let (x, y) = (x as usize, y as usize);
+        Some((y * ROW_BYTES + x / 8, 0x80 >> (x % 8)))
+    }
+}
+
+impl Default for Framebuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OriginDimensions for Framebuffer {
+    fn size(&self) -> Size {
+        Size::new(WIDTH, HEIGHT)
+    }
+}
+
+impl DrawTarget for Framebuffer {
+    type Color = BinaryColor;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(point, color) in pixels {
+            let Some((index, mask)) = Self::locate(point) else {
+                continue;
+            };
+            match color {
+                BinaryColor::Off => self.buffer[index] |= mask,
+                BinaryColor::On => self.buffer[index] &= !mask,
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,214 @@
+//! Attribute handles of the metrics characteristic, remembered per device so the
+//! GATT discovery can be skipped on the next connections.
+//!
+//! Devices without bonding never get the Service Changed indication on a new connection, so
+//! the [`DatabaseHash`] is kept as well when the device has one. A different hash means the
+//! handles moved, the entry is removed then.
+//!
+//! Stored as a version byte followed by `address, handle, hash handle, hash` entries, most
+//! recently used first. The hash handle is 0 when the device has no hash.
+use heapless::Vec;
+
+use crate::platform::{Slot, Storage};
+
+const CACHE_VERSION: u8 = 2;
+const ENTRY_LEN: usize = 26;
+const CAPACITY: usize = 8;
+
+/// Database Hash characteristic of the Generic Attribute service, it changes with the handles
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct DatabaseHash {
+    pub handle: u16,
+    pub value: [u8; 16],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+struct Entry {
+    addr: [u8; 6],
+    handle: u16,
+    hash: Option<DatabaseHash>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GattCache {
+    entries: Vec<Entry, CAPACITY>,
+    /// Changed since loaded or saved
+    dirty: bool,
+}
+
+impl GattCache {
+    pub const MAX_SIZE: usize = 1 + CAPACITY * ENTRY_LEN;
+
+    /// Reads the cache from the storage. Starts empty if it's missing or invalid
+    pub async fn load<S: Storage>(storage: &mut S) -> Self {
+        let mut buf = [0; Self::MAX_SIZE];
+        match storage.load(Slot::GattCache, &mut buf).await {
+            Ok(len) => Self::from_bytes(&buf[..len]),
+            Err(e) => {
+                defmt::error!("GATT cache load failed: {:?}", defmt::Debug2Format(&e));
+                Self::default()
+            }
+        }
+    }
+
+    /// Writes the cache if it changed
+    pub async fn save<S: Storage>(&mut self, storage: &mut S) -> Result<(), S::Error> {
+        if !self.dirty {
+            return Ok(());
+        }
+        storage.store(Slot::GattCache, &self.to_bytes()).await?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let mut cache = Self::default();
+        let Some((&CACHE_VERSION, entries)) = bytes.split_first() else {
+            return cache;
+        };
+        for entry in entries.chunks_exact(ENTRY_LEN) {
+            let mut addr = [0; 6];
+            addr.copy_from_slice(&entry[..6]);
+            let handle = u16::from_le_bytes([entry[6], entry[7]]);
+            let hash = match u16::from_le_bytes([entry[8], entry[9]]) {
+                // Not a valid attribute handle
+                0 => None,
+                handle => {
+                    let mut value = [0; 16];
+                    value.copy_from_slice(&entry[10..]);
+                    Some(DatabaseHash { handle, value })
+                }
+            };
+            if cache.entries.push(Entry { addr, handle, hash }).is_err() {
+                break;
+            }
+        }
+        cache
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8, { Self::MAX_SIZE }> {
+        let mut bytes = Vec::new();
+        // Always fits, MAX_SIZE is computed from the capacity
+        let _ = bytes.push(CACHE_VERSION);
+        for entry in &self.entries {
+            let _ = bytes.extend_from_slice(&entry.addr);
+            let _ = bytes.extend_from_slice(&entry.handle.to_le_bytes());
+            let hash = entry.hash.unwrap_or(DatabaseHash {
+                handle: 0,
+                value: [0; 16],
+            });
+            let _ = bytes.extend_from_slice(&hash.handle.to_le_bytes());
+            let _ = bytes.extend_from_slice(&hash.value);
+        }
+        bytes
+    }
+
+    pub fn get(&self, addr: &[u8; 6]) -> Option<u16> {
+        self.entries
+            .iter()
+            .find(|e| e.addr == *addr)
+            .map(|e| e.handle)
+    }
+
+    /// Database hash of the device when it was cached, if it has one
+    pub fn database_hash(&self, addr: &[u8; 6]) -> Option<DatabaseHash> {
+        self.entries
+            .iter()
+            .find(|e| e.addr == *addr)
+            .and_then(|e| e.hash)
+    }
+
+    /// Remembers the handle, or marks it as the most recently used. Drops the least recently
+    /// used device when full
+    pub fn insert(&mut self, addr: [u8; 6], handle: u16, hash: Option<DatabaseHash>) {
+        let entry = Entry { addr, handle, hash };
+        if self.entries.first() == Some(&entry) {
+            return;
+        }
+        self.entries.retain(|e| e.addr != addr);
+        if self.entries.is_full() {
+            self.entries.pop();
+        }
+        let _ = self.entries.insert(0, entry);
+        self.dirty = true;
+    }
+
+    /// Forgets the device, e.g. after its handles stopped working
+    pub fn remove(&mut self, addr: &[u8; 6]) {
+        let len = self.entries.len();
+        self.entries.retain(|e| e.addr != *addr);
+        self.dirty |= self.entries.len() != len;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: DatabaseHash = DatabaseHash {
+        handle: 0x0010,
+        value: [0xab; 16],
+    };
+
+    fn addr(n: u8) -> [u8; 6] {
+        [n, 0, 0, 0, 0x80, 0x2e]
+    }
+
+    #[test]
+    fn entries_round_trip() {
+        let mut cache = GattCache::default();
+        cache.insert(addr(1), 0x000d, None);
+        cache.insert(addr(2), 0x0023, Some(HASH));
+        let bytes = cache.to_bytes();
+        assert_eq!(bytes.len(), 1 + 2 * ENTRY_LEN);
+        let loaded = GattCache::from_bytes(&bytes);
+        assert_eq!(loaded.entries, cache.entries);
+        assert_eq!(loaded.get(&addr(1)), Some(0x000d));
+        assert_eq!(loaded.database_hash(&addr(1)), None);
+        assert_eq!(loaded.database_hash(&addr(2)), Some(HASH));
+        assert!(!loaded.dirty);
+    }
+
+    #[test]
+    fn other_versions_and_partial_entries_are_ignored() {
+        let mut bytes = GattCache::default().to_bytes();
+        bytes.extend_from_slice(&[1; ENTRY_LEN + 3]).unwrap();
+        assert_eq!(GattCache::from_bytes(&bytes).entries.len(), 1);
+        bytes[0] = 1;
+        assert_eq!(GattCache::from_bytes(&bytes), GattCache::default());
+        assert_eq!(GattCache::from_bytes(&[]), GattCache::default());
+    }
+
+    #[test]
+    fn insert_replaces_and_marks_dirty() {
+        let mut cache = GattCache::from_bytes(&[CACHE_VERSION]);
+        cache.insert(addr(1), 0x000d, None);
+        assert!(cache.dirty);
+        cache.dirty = false;
+        // Already the most recent one
+        cache.insert(addr(1), 0x000d, None);
+        assert!(!cache.dirty);
+        cache.insert(addr(1), 0x0011, Some(HASH));
+        assert!(cache.dirty);
+        assert_eq!(cache.entries.len(), 1);
+        assert_eq!(cache.get(&addr(1)), Some(0x0011));
+        cache.remove(&addr(1));
+        assert_eq!(cache.get(&addr(1)), None);
+    }
+
+    #[test]
+    fn least_recently_used_device_is_dropped() {
+        let mut cache = GattCache::default();
+        for n in 0..CAPACITY as u8 {
+            cache.insert(addr(n), n as u16, None);
+        }
+        // Used again, so the second one is the oldest now
+        cache.insert(addr(0), 0, None);
+        cache.insert(addr(0xff), 0xff, None);
+        assert_eq!(cache.entries.len(), CAPACITY);
+        assert_eq!(cache.get(&addr(0)), Some(0));
+        assert_eq!(cache.get(&addr(1)), None);
+        assert_eq!(cache.get(&addr(0xff)), Some(0xff));
+        assert_eq!(cache.entries[0].addr, addr(0xff));
+    }
+}
@@ -0,0 +1,178 @@
+//! Past readings, kept in the [`Log::History`] log.
+//!
+//! Records are the RSSI, 0 if unknown, then the value of each of [`Quantity::ALL`] as a little
+//! endian `f32`, NaN when it wasn't measured. Adding a quantity changes the record size, the
+//! older records can't be read back then.
+use core::fmt;
+
+use crate::measurement::{Measurement, MeasurementSet, Quantity};
+use crate::platform::{Log, Storage};
+
+/// Exported quantities with their column and the decimal places, the RSSI comes after them
+const COLUMNS: [(Quantity, &str, usize); Quantity::COUNT] = [
+    (Quantity::Humidity, "humidity_pct", 1),
+    (Quantity::Temperature, "temperature_c", 2),
+    (Quantity::Pressure, "pressure_hpa", 2),
+    (Quantity::Co2, "co2_ppm", 0),
+    (Quantity::Voc, "voc_ppb", 0),
+    (Quantity::RadonShortTerm, "radon_short_bq_m3", 0),
+    (Quantity::RadonLongTerm, "radon_long_bq_m3", 0),
+    (Quantity::Illuminance, "illuminance_pct", 0),
+    (Quantity::HeatIndex, "heat_index_c", 2),
+    (Quantity::Battery, "battery_pct", 0),
+    (Quantity::Pm1, "pm1_ug_m3", 1),
+    (Quantity::Pm2_5, "pm2_5_ug_m3", 1),
+    (Quantity::Pm10, "pm10_ug_m3", 1),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reading {
+    /// Without their timestamps
+    pub measurements: MeasurementSet,
+    /// See [`crate::link::LinkQuality::rssi`]
+    pub rssi: Option<i8>,
+}
+
+impl Reading {
+    pub const PACKET_LEN: usize = 1 + 4 * Quantity::COUNT;
+
+    pub fn to_bytes(&self) -> [u8; Self::PACKET_LEN] {
+        let mut bytes = [0; Self::PACKET_LEN];
+        // 0 dBm is never reported, so it marks the readings without RSSI
+        bytes[0] = self.rssi.unwrap_or(0) as u8;
+        for (quantity, value) in Quantity::ALL
+            .into_iter()
+            .zip(bytes[1..].chunks_exact_mut(4))
+        {
+            let measured = self.measurements.value(quantity).unwrap_or(f32::NAN);
+            value.copy_from_slice(&measured.to_le_bytes());
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; Self::PACKET_LEN]) -> Self {
+        let rssi = match bytes[0] as i8 {
+            0 => None,
+            rssi => Some(rssi),
+        };
+        let mut measurements = MeasurementSet::new();
+        for (quantity, value) in Quantity::ALL.into_iter().zip(bytes[1..].chunks_exact(4)) {
+            let value = f32::from_le_bytes([value[0], value[1], value[2], value[3]]);
+            if !value.is_nan() {
+                measurements.insert(Measurement::new(quantity, value));
+            }
+        }
+        Self { measurements, rssi }
+    }
+}
+
+pub async fn record<S: Storage>(storage: &mut S, reading: &Reading) -> Result<(), S::Error> {
+    storage.append(Log::History, &reading.to_bytes()).await
+}
+
+/// Reads the `index`-th reading counting from the oldest one
+pub async fn read<S: Storage>(storage: &mut S, index: usize) -> Result<Option<Reading>, S::Error> {
+    let mut buf = [0; Reading::PACKET_LEN];
+    if !storage.read_record(Log::History, index, &mut buf).await? {
+        return Ok(None);
+    }
+    Ok(Some(Reading::from_bytes(&buf)))
+}
+
+pub fn write_csv_header<W: fmt::Write>(out: &mut W) -> fmt::Result {
+    out.write_str("index")?;
+    for (_, name, _) in COLUMNS {
+        write!(out, ",{}", name)?;
+    }
+    out.write_str(",rssi_dbm\r\n")
+}
+
+/// Columns of the values that weren't measured, and of the RSSI when unknown, are empty
+pub fn write_csv_row<W: fmt::Write>(out: &mut W, index: usize, reading: &Reading) -> fmt::Result {
+    write!(out, "{}", index)?;
+    for (quantity, _, precision) in COLUMNS {
+        out.write_char(',')?;
+        if let Some(value) = reading.measurements.value(quantity) {
+            write!(out, "{:.*}", precision, value)?;
+        }
+    }
+    out.write_char(',')?;
+    write_optional(out, reading.rssi)?;
+    out.write_str("\r\n")
+}
+
+fn write_optional<W: fmt::Write, T: fmt::Display>(out: &mut W, value: Option<T>) -> fmt::Result {
+    match value {
+        Some(value) => write!(out, "{}", value),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use heapless::String;
+
+    use super::*;
+    use crate::metrics::AirMetrics;
+    use crate::particulates::PmReading;
+
+    fn reading() -> Reading {
+        let mut measurements = MeasurementSet::from(&AirMetrics {
+            version: 1,
+            humidity: 41.5,
+            illuminance: None,
+            radon_short: Some(48),
+            radon_long: Some(61),
+            temperature: 22.37,
+            pressure: Some(1003.2),
+            co2_level: Some(812),
+            voc_level: None,
+            battery: Some(87),
+            update_interval: None,
+        });
+        measurements.insert(Measurement::new(Quantity::HeatIndex, 21.75));
+        measurements.merge(&MeasurementSet::from(&PmReading {
+            pm1: 4.0,
+            pm2_5: 8.0,
+            pm10: 11.0,
+            counts: heapless::Vec::new(),
+        }));
+        Reading {
+            measurements,
+            rssi: Some(-71),
+        }
+    }
+
+    #[test]
+    fn every_quantity_has_a_column() {
+        for quantity in Quantity::ALL {
+            assert!(COLUMNS.iter().any(|(q, _, _)| *q == quantity));
+        }
+    }
+
+    #[test]
+    fn csv_rows_follow_the_header() {
+        let reading = Reading::from_bytes(&reading().to_bytes());
+        let mut out: String<512> = String::new();
+        write_csv_header(&mut out).unwrap();
+        write_csv_row(&mut out, 3, &reading).unwrap();
+        assert_eq!(
+            out,
+            "index,humidity_pct,temperature_c,pressure_hpa,co2_ppm,voc_ppb,radon_short_bq_m3,\
+            radon_long_bq_m3,illuminance_pct,heat_index_c,battery_pct,pm1_ug_m3,pm2_5_ug_m3,\
+            pm10_ug_m3,rssi_dbm\r\n\
+            3,41.5,22.37,1003.20,812,,48,61,,21.75,87,4.0,8.0,11.0,-71\r\n"
+        );
+    }
+
+    #[test]
+    fn partial_readings_are_kept() {
+        let mut measurements = MeasurementSet::new();
+        measurements.insert(Measurement::new(Quantity::Pm2_5, 8.0));
+        let reading = Reading {
+            measurements,
+            rssi: None,
+        };
+        assert_eq!(Reading::from_bytes(&reading.to_bytes()), reading);
+    }
+}
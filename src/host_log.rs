@@ -0,0 +1,17 @@
+//! defmt logger for the host builds (tests, simulator and fuzzing), where there's no probe
+//! to read the logs: they are dropped
+
+#[defmt::global_logger]
+struct HostLogger;
+
+unsafe impl defmt::Logger for HostLogger {
+    fn acquire() {}
+
+    unsafe fn flush() {}
+
+    unsafe fn release() {}
+
+    unsafe fn write(_bytes: &[u8]) {}
+}
+
+defmt::timestamp!("");
@@ -0,0 +1,150 @@
+//! Status LED policy. The LED stays off except for a brief blink after each cycle, so it doesn't
+//! draw power through the BLE fetch and the display update: one flash when the metrics were
+//! shown, a [`BlinkCode`] when something failed, and a dimmed heartbeat while on USB power,
+//! quick flashes instead while an alert fires.
+use core::time::Duration;
+use heapless::Vec;
+
+use crate::platform::StatusLed;
+
+/// Brightness of the heartbeat peak, in percent
+const HEARTBEAT_PEAK: u8 = 20;
+const HEARTBEAT_RAMP_STEPS: u8 = 5;
+
+/// Number of blinks telling what failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum BlinkCode {
+    /// The metrics couldn't be fetched, the error screen is shown
+    Fetch,
+    /// The screen couldn't be drawn or refreshed
+    Display,
+    /// The wake up alarm couldn't be set
+    Alarm,
+}
+
+impl BlinkCode {
+    pub fn blinks(self) -> usize {
+        match self {
+            Self::Fetch => 2,
+            Self::Display => 3,
+            Self::Alarm => 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum LedSignal {
+    Off,
+    /// Brief flash after the metrics were shown
+    Success,
+    Error(BlinkCode),
+    /// One slow dimmed breath, repeated in the desk mode
+    Heartbeat,
+    /// Three quick flashes and a pause, repeated in the desk mode while an alert fires
+    Alert,
+}
+
+/// Brightness in percent held for the duration
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct LedStep {
+    pub brightness: u8,
+    pub duration: Duration,
+}
+
+impl LedStep {
+    const fn new(brightness: u8, duration_ms: u64) -> Self {
+        Self {
+            brightness,
+            duration: Duration::from_millis(duration_ms),
+        }
+    }
+}
+
+impl LedSignal {
+    /// The pattern of the signal, the LED is off after it
+    pub fn steps(self) -> Vec<LedStep, 16> {
+        let mut steps = Vec::new();
+        match self {
+            Self::Off => (),
+            Self::Success => {
+                let _ = steps.push(LedStep::new(100, 60));
+            }
+            Self::Error(code) => {
+                for _ in 0..code.blinks() {
+                    let _ = steps.push(LedStep::new(100, 150));
+                    let _ = steps.push(LedStep::new(0, 250));
+                }
+            }
+            Self::Heartbeat => {
+                let ramp = (1..=HEARTBEAT_RAMP_STEPS)
+                    .chain((1..HEARTBEAT_RAMP_STEPS).rev())
+                    .map(|i| LedStep::new(HEARTBEAT_PEAK * i / HEARTBEAT_RAMP_STEPS, 60));
+                steps.extend(ramp);
+                let _ = steps.push(LedStep::new(0, 1500));
+            }
+            Self::Alert => {
+                for _ in 0..3 {
+                    let _ = steps.push(LedStep::new(100, 80));
+                    let _ = steps.push(LedStep::new(0, 120));
+                }
+                let _ = steps.push(LedStep::new(0, 1000));
+            }
+        }
+        steps
+    }
+}
+
+/// Plays the pattern of the `signal`, then turns the LED off
+pub async fn play<L: StatusLed>(led: &mut L, signal: LedSignal) {
+    for step in signal.steps() {
+        led.set_brightness(step.brightness);
+        led.hold(step.duration).await;
+    }
+    led.set_brightness(0);
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+
+    use super::*;
+    use crate::mock::RecordingLed;
+
+    #[test]
+    fn error_codes_blink_their_count() {
+        for code in [BlinkCode::Fetch, BlinkCode::Display, BlinkCode::Alarm] {
+            let steps = LedSignal::Error(code).steps();
+            let flashes = steps.iter().filter(|s| s.brightness == 100).count();
+            assert_eq!(flashes, code.blinks());
+        }
+        assert!(LedSignal::Off.steps().is_empty());
+    }
+
+    #[test]
+    fn heartbeat_stays_dimmed() {
+        let steps = LedSignal::Heartbeat.steps();
+        let peak = steps.iter().map(|s| s.brightness).max();
+        assert_eq!(peak, Some(HEARTBEAT_PEAK));
+        // Ramps up then down, and rests dark
+        assert_eq!(
+            steps.first().unwrap().brightness,
+            HEARTBEAT_PEAK / HEARTBEAT_RAMP_STEPS
+        );
+        assert_eq!(steps.last().unwrap().brightness, 0);
+    }
+
+    #[test]
+    fn play_holds_each_step_then_turns_off() {
+        let mut led = RecordingLed::default();
+        block_on(play(&mut led, LedSignal::Error(BlinkCode::Fetch)));
+        let on = (100, Duration::from_millis(150));
+        let off = (0, Duration::from_millis(250));
+        assert_eq!(led.steps, [on, off, on, off]);
+        assert_eq!(led.brightness, 0);
+
+        led.steps.clear();
+        block_on(play(&mut led, LedSignal::Success));
+        assert_eq!(led.steps, [(100, Duration::from_millis(60))]);
+        assert_eq!(led.brightness, 0);
+    }
+}
@@ -1,5 +1,43 @@
 #![no_std]
 
+#[cfg(test)]
+extern crate std;
+
+pub mod adv;
+pub mod alert;
+pub mod app;
+#[cfg(feature = "firmware")]
 pub mod badger;
+#[cfg(feature = "firmware")]
 pub mod ble;
+pub mod comfort;
+pub mod config;
+pub mod console;
+pub mod framebuffer;
+pub mod gatt_cache;
+pub mod history;
+#[cfg(not(feature = "firmware"))]
+mod host_log;
+pub mod led;
+pub mod link;
+pub mod measurement;
 pub mod metrics;
+#[cfg(any(test, feature = "simulator"))]
+pub mod mock;
+pub mod pairing;
+pub mod particulates;
+pub mod platform;
+pub mod profile;
+#[cfg(feature = "firmware")]
+pub mod provisioning;
+pub mod qr;
+pub mod ruuvi;
+#[cfg(feature = "simulator")]
+pub mod samples;
+pub mod scd4x;
+pub mod screens;
+pub mod timing;
+pub mod units;
+#[cfg(feature = "firmware")]
+pub mod usb_console;
+pub mod xiaomi;
@@ -0,0 +1,105 @@
+//! Radio link quality of the Airthings connection
+use core::fmt;
+
+/// Signal strength seen while fetching one reading
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct LinkQuality {
+    /// Of the advertisement the device was picked from, in dBm
+    pub adv_rssi: i8,
+    /// Read on the open connection, when the controller reports it
+    pub conn_rssi: Option<i8>,
+}
+
+impl LinkQuality {
+    /// Below this reads start to fail now and then
+    pub const MARGINAL_DBM: i8 = -80;
+    pub const MAX_BARS: u8 = 4;
+
+    /// The connection RSSI if known, it's more recent than the advertisement
+    pub fn rssi(&self) -> i8 {
+        self.conn_rssi.unwrap_or(self.adv_rssi)
+    }
+
+    /// Bars of the status bar icon, from 0 to [`Self::MAX_BARS`]
+    pub fn bars(&self) -> u8 {
+        match self.rssi() {
+            -60.. => 4,
+            -70.. => 3,
+            -80.. => 2,
+            -90.. => 1,
+            _ => 0,
+        }
+    }
+
+    pub fn is_marginal(&self) -> bool {
+        self.rssi() < Self::MARGINAL_DBM
+    }
+}
+
+impl fmt::Display for LinkQuality {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "adv {} dBm", self.adv_rssi)?;
+        if let Some(rssi) = self.conn_rssi {
+            write!(f, ", conn {} dBm", rssi)?;
+        }
+        if self.is_marginal() {
+            f.write_str(", marginal")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conn(rssi: i8) -> LinkQuality {
+        LinkQuality {
+            adv_rssi: -100,
+            conn_rssi: Some(rssi),
+        }
+    }
+
+    #[test]
+    fn bars_change_at_each_threshold() {
+        let bars = [
+            (-30, 4),
+            (-60, 4),
+            (-61, 3),
+            (-70, 3),
+            (-71, 2),
+            (-80, 2),
+            (-81, 1),
+            (-90, 1),
+            (-91, 0),
+            (i8::MIN, 0),
+        ];
+        for (rssi, expected) in bars {
+            assert_eq!(conn(rssi).bars(), expected, "{} dBm", rssi);
+        }
+    }
+
+    #[test]
+    fn marginal_below_the_threshold() {
+        assert!(!conn(LinkQuality::MARGINAL_DBM).is_marginal());
+        assert!(conn(LinkQuality::MARGINAL_DBM - 1).is_marginal());
+    }
+
+    #[test]
+    fn advertisement_rssi_without_a_connection_rssi() {
+        let link = LinkQuality {
+            adv_rssi: -85,
+            conn_rssi: None,
+        };
+        assert_eq!(link.rssi(), -85);
+        assert_eq!(link.bars(), 1);
+        assert!(link.is_marginal());
+        // The connection RSSI wins when known
+        let link = LinkQuality {
+            conn_rssi: Some(-65),
+            ..link
+        };
+        assert_eq!((link.rssi(), link.bars()), (-65, 3));
+        assert!(!link.is_marginal());
+    }
+}
@@ -1,28 +1,39 @@
 #![no_std]
 #![no_main]
 
-use core::fmt::Write;
-use core::time;
+use core::{fmt, time};
 use defmt;
+use embassy_embedded_hal::shared_bus::I2cDeviceError;
 use embassy_executor::Spawner;
+use embassy_futures::join::join;
+use embassy_futures::select::{select3, Either3};
+use embassy_rp::clocks::RoscRng;
+use embassy_rp::gpio::Output;
+use embassy_rp::i2c;
 use embassy_rp::Peripherals;
-use embassy_time::{Duration, Timer};
-use embedded_graphics::{
-    mono_font::{ascii::*, MonoTextStyle},
-    pixelcolor::BinaryColor,
-    prelude::*,
-    primitives::{PrimitiveStyle, Rectangle},
-};
-use embedded_text::{
-    alignment::HorizontalAlignment,
-    style::{HeightMode, TextBoxStyleBuilder},
-    TextBox,
-};
-use heapless::String;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_time::{Delay, Duration, Timer};
+use rand::RngCore;
+use trawm::app::{run_cycle, CycleConfig, DeskAction, DeskMode};
 use trawm::badger::*;
 use trawm::ble::*;
+use trawm::config::Config;
+use trawm::gatt_cache::GattCache;
+use trawm::led::{self, LedSignal};
+use trawm::link::LinkQuality;
+use trawm::measurement::MeasurementSet;
+use trawm::pairing::Pin;
+use trawm::particulates::{PmSensor, WithParticulates};
+use trawm::platform::{
+    Button, Buttons, Clock, Display, MetricsSource, StatusLed, Storage, WakeAlarm,
+};
+use trawm::scd4x::{Scd4x, Scd4xError, Scd4xSource};
+use trawm::timing::{self, Phase, PhaseTimings, Stopwatch};
+use trawm::units::UnitProfile;
+use trawm::usb_console::{self, Console};
+use trawm::{provisioning, screens};
 use uc8151::LUT;
-use uc8151::WIDTH;
 use {defmt_rtt as _, panic_probe as _};
 
 #[embassy_executor::main]
@@ -51,11 +62,14 @@ async fn main(spawner: Spawner) {
         DMA_CH2,
         PIN_10,
         PIN_22,
+        PWM_SLICE3,
         PIN_15,
         PIN_11,
         PIN_12,
         PIN_13,
         PIN_14,
+        FLASH,
+        USB,
         ..
     } = embassy_rp::init(Default::default());
     let mut badger = Badger2040wIO::init(Badger2040wParams {
@@ -74,18 +88,24 @@ async fn main(spawner: Spawner) {
         DMA_CH2,
         PIN_10,
         PIN_22,
+        PWM_SLICE3,
         PIN_15,
         PIN_11,
         PIN_12,
         PIN_13,
         PIN_14,
+        FLASH,
     })
     .await;
     badger.power.set_high();
-    badger.led.set_high();
+    let mut stopwatch = Stopwatch::new(BootClock);
+    let started = stopwatch.now();
     badger.display.reset().await;
     // Initialise display. Using the default LUT speed setting
     badger.display.setup(LUT::Internal).await.unwrap();
+    stopwatch.stop(Phase::DisplayInit, started);
+    let scd4x = Mutex::<NoopRawMutex, _>::new(Scd4x::new(badger.i2c_device(), Delay));
+    let pm_i2c = badger.i2c_device();
     let ble = BLE {
         PIN_25,
         PIO0,
@@ -94,48 +114,291 @@ async fn main(spawner: Spawner) {
         PIN_29,
         PIN_23,
     };
-    // Note we're setting the Text color to `Off`. The driver is set up to treat Off as Black so that BMPs work as expected.
-    let character_style = MonoTextStyle::new(&FONT_9X18_BOLD, BinaryColor::Off);
-    let textbox_style = TextBoxStyleBuilder::new()
-        .height_mode(HeightMode::FitToText)
-        .alignment(HorizontalAlignment::Left)
-        .paragraph_spacing(0)
-        .build();
-
-    // Bounding box for our text. Fill it with the opposite color so we can read the text.
-    let bounds = Rectangle::new(Point::new(0, 0), Size::new(WIDTH, 0));
-    bounds
-        .into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
-        .draw(&mut badger.display)
-        .unwrap();
-
-    let mut awake_in = time::Duration::from_secs(90);
-    let mut text: String<256> = String::new();
-    // Try to get air metrics with 10 sec timout
-    match ble.get_metrics(&spawner, Duration::from_secs(10)).await {
-        Ok(metrics) => {
-            write!(text, "{}", metrics).unwrap();
+    let Badger2040wIO {
+        mut power,
+        mut led,
+        buttons,
+        mut display,
+        mut rtc,
+        i2c: _,
+        mut storage,
+    } = badger;
+    let mut config = Config::load(&mut storage).await;
+
+    let started = stopwatch.now();
+    let mut session = BleSession::start(ble, &spawner).await;
+    stopwatch.stop(Phase::RadioInit, started);
+    *session.gatt_cache() = GattCache::load(&mut storage).await;
+
+    if buttons.is_pressed(Button::A) {
+        defmt::info!("Entering provisioning mode");
+        let pin = Pin::new(RoscRng.next_u32());
+        screens::draw_provisioning(&mut display, provisioning::DEVICE_NAME, &pin).unwrap();
+        let _ = display.update().await;
+        provisioning::run(
+            &mut session,
+            &mut config,
+            &mut storage,
+            pin,
+            Duration::from_secs(300),
+        )
+        .await;
+        // Start over with the new settings
+        let awake_in = time::Duration::from_secs(5);
+        rearm(&mut rtc, awake_in).await;
+        defmt::info!("Going to deep sleep for {:?}", awake_in);
+        power_off(&mut power, &mut display).await;
+        // Only reached on USB power
+        let console = Console {
+            config: &Mutex::new(config),
+            storage: &Mutex::new(storage),
+            ble: &Mutex::new(session),
+            scd4x: &scd4x,
+            interval: CycleConfig::default().interval,
+        };
+        let heartbeat = async {
+            loop {
+                led::play(&mut led, LedSignal::Heartbeat).await;
+            }
+        };
+        join(usb_console::run(USB, console), heartbeat).await;
+    } else {
+        let cycle_config = CycleConfig {
+            units: config.units,
+            altitude: config.altitude,
+            alerts: config.alerts,
+            ..Default::default()
+        };
+        let config = Mutex::<NoopRawMutex, _>::new(config);
+        let storage = Mutex::<NoopRawMutex, _>::new(storage);
+        let session = Mutex::<NoopRawMutex, _>::new(session);
+        let console = Console {
+            config: &config,
+            storage: &storage,
+            ble: &session,
+            scd4x: &scd4x,
+            interval: cycle_config.interval,
+        };
+        let cycles = async {
+            let mut source = {
+                let config = config.lock().await;
+                let source = if config.scd4x {
+                    Source::Scd4x(Scd4xSource::new(&scd4x))
+                } else {
+                    // Try to get air metrics with 10 sec timout
+                    Source::Ble(BleMetricsSource::new(&session, Duration::from_secs(10)))
+                };
+                let pm_sensor = config
+                    .pm_sensor
+                    .map(|model| PmSensor::new(model, pm_i2c, Delay));
+                let mut source = WithParticulates::new(source, pm_sensor, BootClock, Delay);
+                source.inner_mut().configure(&config);
+                source
+            };
+            let outcome = run_cycle(
+                &cycle_config,
+                &mut source,
+                &mut display,
+                &mut rtc,
+                &mut &storage,
+                &mut led,
+                &mut stopwatch,
+            )
+            .await;
+            let (awake_in, mut alerting) = match outcome {
+                Ok(outcome) => (outcome.sleep_for, outcome.alerting),
+                Err(e) => {
+                    defmt::error!("Cycle failed: {:?}", defmt::Debug2Format(&e));
+                    rearm(&mut rtc, cycle_config.retry_interval).await;
+                    (cycle_config.retry_interval, false)
+                }
+            };
+            save_gatt_cache(&session, &storage).await;
+            if !session.lock().await.usb_powered().await {
+                let timings = &mut stopwatch.timings;
+                timings.awake_ms = BootClock.now().as_millis() as u32;
+                let mah = config.lock().await.currents.estimate(timings, awake_in);
+                defmt::info!("Cycle timings: {:?}, {} mAh/day", timings, mah);
+                if let Err(e) = timing::record(&mut &storage, timings).await {
+                    defmt::error!("Timings record failed: {:?}", defmt::Debug2Format(&e));
+                }
+                defmt::info!("Going to deep sleep for {:?}", awake_in);
+                power_off(&mut power, &mut display).await;
+            }
+            // Still running, so on USB power
+            defmt::info!("Entering desk mode");
+            let mut desk = DeskMode::new();
+            loop {
+                let interval = Duration::from_secs(desk.interval().as_secs());
+                let signal = if alerting {
+                    LedSignal::Alert
+                } else {
+                    LedSignal::Heartbeat
+                };
+                let blink = async {
+                    loop {
+                        led::play(&mut led, signal).await;
+                    }
+                };
+                let wait = select3(Timer::after(interval), next_press(&buttons), blink).await;
+                // The pattern may have been cut short with the LED on
+                led.set_brightness(0);
+                if let Either3::Second(button) = wait {
+                    if desk.press(button) == DeskAction::ToggleUnits {
+                        // Not saved, `set` the units in the console to keep them
+                        let mut config = config.lock().await;
+                        config.units = if config.units == UnitProfile::US {
+                            UnitProfile::METRIC
+                        } else {
+                            UnitProfile::US
+                        };
+                    }
+                }
+                let mut desk_config = desk.cycle_config(&cycle_config);
+                {
+                    // Picks up the changes made in the console
+                    let config = config.lock().await;
+                    desk_config.units = config.units;
+                    desk_config.altitude = config.altitude;
+                    desk_config.alerts = config.alerts;
+                    source.inner_mut().configure(&config);
+                }
+                // Not recorded, the log is for the cycles on battery
+                let mut stopwatch = Stopwatch::new(BootClock);
+                let outcome = run_cycle(
+                    &desk_config,
+                    &mut source,
+                    &mut display,
+                    &mut rtc,
+                    &mut &storage,
+                    &mut led,
+                    &mut stopwatch,
+                )
+                .await;
+                alerting = match outcome {
+                    Ok(outcome) => outcome.alerting,
+                    Err(e) => {
+                        defmt::error!("Desk cycle failed: {:?}", defmt::Debug2Format(&e));
+                        false
+                    }
+                };
+                defmt::info!("Desk cycle timings: {:?}", stopwatch.timings);
+                save_gatt_cache(&session, &storage).await;
+                if !session.lock().await.usb_powered().await {
+                    defmt::info!(
+                        "USB unplugged, going to deep sleep for {:?}",
+                        cycle_config.interval
+                    );
+                    rearm(&mut rtc, cycle_config.interval).await;
+                    power_off(&mut power, &mut display).await;
+                }
+            }
+        };
+        join(cycles, usb_console::run(USB, console)).await;
+    }
+}
+
+/// Where the metrics come from, see [`Config::scd4x`]. Picked at boot
+enum Source<'a> {
+    Ble(BleMetricsSource<'a>),
+    Scd4x(Scd4xSource<'a, NoopRawMutex, I2c0Device, Delay>),
+}
+
+impl Source<'_> {
+    /// Applies the settings of the BLE source
+    fn configure(&mut self, config: &Config) {
+        if let Self::Ble(source) = self {
+            source.set_serial(config.airthings_serial);
         }
-        Err(e) => {
-            write!(text, "An error occurred:\n{:?}", e).unwrap();
-            awake_in = time::Duration::from_secs(10);
+    }
+}
+
+#[derive(Debug)]
+enum SourceError {
+    Ble(BLEError),
+    Scd4x(Scd4xError<I2cDeviceError<i2c::Error>>),
+}
+
+impl fmt::Display for SourceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ble(e) => e.fmt(f),
+            Self::Scd4x(e) => e.fmt(f),
         }
-    };
+    }
+}
+
+impl MetricsSource for Source<'_> {
+    type Error = SourceError;
+
+    async fn fetch(&mut self) -> Result<MeasurementSet, SourceError> {
+        match self {
+            Self::Ble(source) => source.fetch().await.map_err(SourceError::Ble),
+            Self::Scd4x(source) => source.fetch().await.map_err(SourceError::Scd4x),
+        }
+    }
+
+    fn fill_timings(&self, timings: &mut PhaseTimings) {
+        if let Self::Ble(source) = self {
+            source.fill_timings(timings);
+        }
+    }
+
+    fn link_quality(&self) -> Option<LinkQuality> {
+        match self {
+            Self::Ble(source) => source.link_quality(),
+            Self::Scd4x(_) => None,
+        }
+    }
+
+    fn outdoor(&self) -> Option<MeasurementSet> {
+        match self {
+            Self::Ble(source) => source.outdoor(),
+            Self::Scd4x(_) => None,
+        }
+    }
+}
 
-    let text_box = TextBox::with_textbox_style(&text, bounds, character_style, textbox_style);
+async fn save_gatt_cache<S: Storage>(
+    session: &Mutex<NoopRawMutex, BleSession>,
+    storage: &Mutex<NoopRawMutex, S>,
+) {
+    let mut session = session.lock().await;
+    if let Err(e) = session.gatt_cache().save(&mut &*storage).await {
+        defmt::error!("GATT cache save failed: {:?}", defmt::Debug2Format(&e));
+    }
+}
 
-    // Draw the text box.
-    text_box.draw(&mut badger.display).unwrap();
+/// Sets the wake up alarm. The power is cut anyway when it fails, the badge then only comes
+/// back with a button press or on USB power
+async fn rearm(rtc: &mut Rtc, duration: time::Duration) {
+    if let Err(e) = rtc.wake_up_in(duration).await {
+        defmt::error!("Wake up alarm failed: {:?}", defmt::Debug2Format(&e));
+    }
+}
 
-    let _ = badger.display.update().await;
+/// Puts the display into deep sleep and cuts the power, the RTC alarm turns it back on.
+/// Only returns on USB power, with the display set up again
+async fn power_off(power: &mut Output<'_>, display: &mut BadgerDisplay<'_>) {
+    if let Err(e) = display.power_down().await {
+        defmt::warn!("Display power down failed: {:?}", defmt::Debug2Format(&e));
+    }
+    power.set_low();
+    display.reset().await;
+    if let Err(e) = display.setup(LUT::Internal).await {
+        defmt::error!("Display setup failed: {:?}", defmt::Debug2Format(&e));
+    }
+}
 
-    defmt::info!("Going to deep sleep for {:?}", awake_in);
-    badger.wake_up_in(awake_in).await.unwrap();
-    badger.power.set_low();
+/// Waits until a button is pressed and released
+async fn next_press(buttons: &impl Buttons) -> Button {
     loop {
-        // Will only run on USB power.
-        log::info!("loop");
-        Timer::after(Duration::from_secs(1)).await;
-        badger.led.toggle();
+        if let Some(button) = Button::ALL.into_iter().find(|b| buttons.is_pressed(*b)) {
+            while buttons.is_pressed(button) {
+                Timer::after_millis(20).await;
+            }
+            return button;
+        }
+        Timer::after_millis(50).await;
     }
 }
@@ -0,0 +1,459 @@
+//! Readings as a set of typed quantities, so a sensor can report any subset of them and the
+//! screens, exports and checks can go through them without knowing where they came from.
+//!
+//! [`AirMetrics`] converts into a [`MeasurementSet`] with the quantities it has, and back for
+//! the sets with at least the temperature and the humidity.
+use core::fmt::{self, Write};
+use core::time::Duration;
+use heapless::Vec;
+
+use crate::comfort::ComfortMetrics;
+use crate::metrics::{AirMetrics, ParseMetricsError};
+use crate::particulates::PmReading;
+use crate::ruuvi::RuuviData;
+use crate::units::UnitProfile;
+
+/// What a measurement is of
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Quantity {
+    Temperature,
+    Humidity,
+    Pressure,
+    Co2,
+    Voc,
+    /// 24 hours average
+    RadonShortTerm,
+    RadonLongTerm,
+    /// Relative, as measured by the Airthings devices
+    Illuminance,
+    /// Charge left in the battery of the sensor
+    Battery,
+    /// Mass concentration of the particles up to 1 µm
+    Pm1,
+    Pm2_5,
+    Pm10,
+    /// Apparent temperature, see [`crate::comfort::heat_index`]
+    HeatIndex,
+}
+
+impl Quantity {
+    pub const COUNT: usize = 13;
+    pub const ALL: [Self; Self::COUNT] = [
+        Self::Temperature,
+        Self::Humidity,
+        Self::Pressure,
+        Self::Co2,
+        Self::Voc,
+        Self::RadonShortTerm,
+        Self::RadonLongTerm,
+        Self::Illuminance,
+        Self::Battery,
+        Self::Pm1,
+        Self::Pm2_5,
+        Self::Pm10,
+        Self::HeatIndex,
+    ];
+
+    /// For the exports
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Temperature => "temperature",
+            Self::Humidity => "humidity",
+            Self::Pressure => "pressure",
+            Self::Co2 => "co2",
+            Self::Voc => "voc",
+            Self::RadonShortTerm => "radon_short",
+            Self::RadonLongTerm => "radon_long",
+            Self::Illuminance => "illuminance",
+            Self::Battery => "battery",
+            Self::Pm1 => "pm1",
+            Self::Pm2_5 => "pm2_5",
+            Self::Pm10 => "pm10",
+            Self::HeatIndex => "heat_index",
+        }
+    }
+
+    /// For the screen
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Temperature => "Temperature",
+            Self::Humidity => "Humidity",
+            Self::Pressure => "Pressure",
+            Self::Co2 => "CO2",
+            Self::Voc => "VOC",
+            Self::RadonShortTerm => "Radon 1day",
+            Self::RadonLongTerm => "Radon long",
+            Self::Illuminance => "Light",
+            Self::Battery => "Battery",
+            Self::Pm1 => "PM1",
+            Self::Pm2_5 => "PM2.5",
+            Self::Pm10 => "PM10",
+            Self::HeatIndex => "Feels like",
+        }
+    }
+
+    /// Unit the quantity is kept in
+    pub fn unit(self) -> Unit {
+        match self {
+            Self::Temperature | Self::HeatIndex => Unit::Celsius,
+            Self::Humidity | Self::Illuminance | Self::Battery => Unit::Percent,
+            Self::Pressure => Unit::Hectopascal,
+            Self::Co2 => Unit::PartsPerMillion,
+            Self::Voc => Unit::PartsPerBillion,
+            Self::RadonShortTerm | Self::RadonLongTerm => Unit::BecquerelPerCubicMeter,
+            Self::Pm1 | Self::Pm2_5 | Self::Pm10 => Unit::MicrogramPerCubicMeter,
+        }
+    }
+
+    /// Decimal places worth showing in [`Self::unit`]
+    pub fn precision(self) -> usize {
+        match self {
+            Self::Temperature | Self::Humidity | Self::Pressure | Self::HeatIndex => 1,
+            _ => 0,
+        }
+    }
+}
+
+/// Units the quantities are kept in, see [`UnitProfile`] for the ones they can be shown in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Unit {
+    Celsius,
+    Percent,
+    Hectopascal,
+    PartsPerMillion,
+    PartsPerBillion,
+    BecquerelPerCubicMeter,
+    MicrogramPerCubicMeter,
+}
+
+impl Unit {
+    pub fn symbol(self) -> &'static str {
+        match self {
+            Self::Celsius => "C",
+            Self::Percent => "%",
+            Self::Hectopascal => "hPa",
+            Self::PartsPerMillion => "ppm",
+            Self::PartsPerBillion => "ppb",
+            Self::BecquerelPerCubicMeter => "Bq/m3",
+            Self::MicrogramPerCubicMeter => "ug/m3",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+pub struct Measurement {
+    pub quantity: Quantity,
+    pub value: f32,
+    pub unit: Unit,
+    /// Decimal places worth showing
+    pub precision: usize,
+    /// When the source took it, see [`crate::platform::WakeAlarm::now`]. `None` when unknown,
+    /// e.g. for the readings from the history
+    pub timestamp: Option<Duration>,
+}
+
+impl Measurement {
+    /// In the unit and with the precision of the `quantity`
+    pub fn new(quantity: Quantity, value: f32) -> Self {
+        Self {
+            quantity,
+            value,
+            unit: quantity.unit(),
+            precision: quantity.precision(),
+            timestamp: None,
+        }
+    }
+
+    /// Formats the value with its unit, converted to the `units` for the quantities they cover
+    pub fn display_with<'a>(&'a self, units: &'a UnitProfile) -> MeasurementDisplay<'a> {
+        MeasurementDisplay {
+            measurement: self,
+            units,
+        }
+    }
+}
+
+impl fmt::Display for Measurement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{0:.1$} {2}",
+            self.value,
+            self.precision,
+            self.unit.symbol()
+        )
+    }
+}
+
+pub struct MeasurementDisplay<'a> {
+    measurement: &'a Measurement,
+    units: &'a UnitProfile,
+}
+
+impl fmt::Display for MeasurementDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (m, units) = (self.measurement, self.units);
+        match m.unit {
+            Unit::Celsius => write!(f, "{}", units.temperature(m.value)),
+            Unit::Hectopascal => write!(f, "{}", units.pressure(m.value)),
+            Unit::BecquerelPerCubicMeter => write!(f, "{}", units.radon(m.value as u16)),
+            _ => m.fmt(f),
+        }
+    }
+}
+
+/// At most one measurement of each [`Quantity`], in the order they were added
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MeasurementSet {
+    measurements: Vec<Measurement, { Quantity::COUNT }>,
+}
+
+impl MeasurementSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces the measurement of the same quantity, if any
+    pub fn insert(&mut self, measurement: Measurement) {
+        match self
+            .measurements
+            .iter_mut()
+            .find(|m| m.quantity == measurement.quantity)
+        {
+            Some(existing) => *existing = measurement,
+            // There's room for every quantity
+            None => {
+                let _ = self.measurements.push(measurement);
+            }
+        }
+    }
+
+    pub fn get(&self, quantity: Quantity) -> Option<&Measurement> {
+        self.measurements.iter().find(|m| m.quantity == quantity)
+    }
+
+    pub fn value(&self, quantity: Quantity) -> Option<f32> {
+        self.get(quantity).map(|m| m.value)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Measurement> {
+        self.measurements.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.measurements.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.measurements.is_empty()
+    }
+
+    /// Adds the measurements of `other`, which replace those of the same quantities
+    pub fn merge(&mut self, other: &Self) {
+        for measurement in other.iter() {
+            self.insert(*measurement);
+        }
+    }
+
+    /// Sets the time of the measurements that don't have one
+    pub fn with_timestamp(mut self, timestamp: Duration) -> Self {
+        for measurement in self.measurements.iter_mut() {
+            measurement.timestamp.get_or_insert(timestamp);
+        }
+        self
+    }
+
+    /// Formats the measurements for the dashboard, a line each, using the given units
+    pub fn display_with<'a>(&'a self, units: &'a UnitProfile) -> MeasurementSetDisplay<'a> {
+        MeasurementSetDisplay {
+            set: self,
+            units,
+            comfort: None,
+        }
+    }
+}
+
+pub struct MeasurementSetDisplay<'a> {
+    set: &'a MeasurementSet,
+    units: &'a UnitProfile,
+    comfort: Option<&'a ComfortMetrics>,
+}
+
+impl<'a> MeasurementSetDisplay<'a> {
+    /// Adds the derived metrics and shows the sea level pressure when known
+    pub fn with_comfort(self, comfort: &'a ComfortMetrics) -> Self {
+        Self {
+            comfort: Some(comfort),
+            ..self
+        }
+    }
+}
+
+impl fmt::Display for MeasurementSetDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (set, units) = (self.set, self.units);
+        let mut lines = 0;
+        for m in set.iter() {
+            match m.quantity {
+                // Next to the humidity and the short term radon
+                Quantity::HeatIndex | Quantity::RadonLongTerm => continue,
+                // Not worth a line, or on the side lines of the dashboard
+                Quantity::Illuminance | Quantity::Pm1 | Quantity::Pm2_5 | Quantity::Pm10 => {
+                    continue
+                }
+                _ => (),
+            }
+            if lines > 0 {
+                f.write_char('\n')?;
+            }
+            lines += 1;
+            write!(f, "{}: ", m.quantity.label())?;
+            match m.quantity {
+                Quantity::Humidity => {
+                    write!(f, "{}", m.display_with(units))?;
+                    if let Some(heat_index) = set.value(Quantity::HeatIndex) {
+                        write!(f, " Feels {}", units.temperature(heat_index))?;
+                    }
+                }
+                Quantity::Pressure => match self.comfort.and_then(|c| c.sea_level_pressure) {
+                    Some(pressure) => write!(f, "{} MSL", units.pressure(pressure))?,
+                    None => write!(f, "{}", m.display_with(units))?,
+                },
+                Quantity::RadonShortTerm => {
+                    let radon = units.radon;
+                    let precision = radon.precision();
+                    write!(f, "{:.*} Long: ", precision, radon.from_bq_m3(m.value))?;
+                    // The long term average needs a few days of measurements
+                    match set.value(Quantity::RadonLongTerm) {
+                        Some(long) => write!(f, "{:.*}", precision, radon.from_bq_m3(long))?,
+                        None => f.write_str("-")?,
+                    }
+                    write!(f, " {}", radon.symbol())?;
+                }
+                _ => write!(f, "{}", m.display_with(units))?,
+            }
+        }
+        if let Some(comfort) = self.comfort {
+            write!(
+                f,
+                "\nDew {} AH {:.1}g/m3 Mould {}",
+                units.temperature(comfort.dew_point),
+                comfort.absolute_humidity,
+                comfort.mould_risk.name()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl From<&AirMetrics> for MeasurementSet {
+    fn from(metrics: &AirMetrics) -> Self {
+        let mut set = Self::new();
+        let mut add = |quantity, value: Option<f32>| {
+            if let Some(value) = value {
+                set.insert(Measurement::new(quantity, value));
+            }
+        };
+        add(Quantity::Humidity, Some(metrics.humidity));
+        add(Quantity::Temperature, Some(metrics.temperature));
+        add(Quantity::Pressure, metrics.pressure);
+        add(Quantity::Co2, metrics.co2_level.map(f32::from));
+        add(Quantity::Voc, metrics.voc_level.map(f32::from));
+        add(Quantity::RadonShortTerm, metrics.radon_short.map(f32::from));
+        add(Quantity::RadonLongTerm, metrics.radon_long.map(f32::from));
+        add(Quantity::Illuminance, metrics.illuminance);
+        add(Quantity::Battery, metrics.battery.map(f32::from));
+        set
+    }
+}
+
+impl From<&PmReading> for MeasurementSet {
+    fn from(reading: &PmReading) -> Self {
+        let mut set = Self::new();
+        set.insert(Measurement::new(Quantity::Pm1, reading.pm1));
+        set.insert(Measurement::new(Quantity::Pm2_5, reading.pm2_5));
+        set.insert(Measurement::new(Quantity::Pm10, reading.pm10));
+        set
+    }
+}
+
+/// The quantities [`AirMetrics`] has room for, the others are dropped
+impl TryFrom<&MeasurementSet> for AirMetrics {
+    type Error = ParseMetricsError;
+
+    fn try_from(set: &MeasurementSet) -> Result<Self, ParseMetricsError> {
+        let integer = |quantity| set.value(quantity).map(|v| v as u16);
+        Ok(Self {
+            // The Wave Plus layout
+            version: 1,
+            humidity: set
+                .value(Quantity::Humidity)
+                .ok_or(ParseMetricsError::MissingReadings)?,
+            illuminance: set.value(Quantity::Illuminance),
+            radon_short: integer(Quantity::RadonShortTerm),
+            radon_long: integer(Quantity::RadonLongTerm),
+            temperature: set
+                .value(Quantity::Temperature)
+                .ok_or(ParseMetricsError::MissingReadings)?,
+            pressure: set.value(Quantity::Pressure),
+            co2_level: integer(Quantity::Co2),
+            voc_level: integer(Quantity::Voc),
+            battery: set.value(Quantity::Battery).map(|v| v as u8),
+            update_interval: None,
+        })
+    }
+}
+
+impl From<&RuuviData> for MeasurementSet {
+    fn from(data: &RuuviData) -> Self {
+        let mut set = Self::new();
+        let mut add = |quantity, value: Option<f32>| {
+            if let Some(value) = value {
+                set.insert(Measurement::new(quantity, value));
+            }
+        };
+        add(Quantity::Temperature, data.temperature);
+        add(Quantity::Humidity, data.humidity);
+        add(Quantity::Pressure, data.pressure);
+        set
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn air_metrics_convert_both_ways() {
+        let metrics = AirMetrics {
+            version: 1,
+            humidity: 41.5,
+            illuminance: None,
+            radon_short: Some(48),
+            radon_long: Some(61),
+            temperature: 22.37,
+            pressure: Some(1003.2),
+            co2_level: Some(812),
+            voc_level: None,
+            battery: Some(87),
+            update_interval: None,
+        };
+        let set = MeasurementSet::from(&metrics);
+        assert_eq!(set.len(), 7);
+        assert_eq!(set.value(Quantity::Co2), Some(812.0));
+        assert_eq!(set.get(Quantity::Voc), None);
+        let back = AirMetrics::try_from(&set).unwrap();
+        assert_eq!(back.to_bytes(), metrics.to_bytes());
+        assert_eq!(back.battery, Some(87));
+    }
+
+    #[test]
+    fn air_metrics_need_the_temperature_and_humidity() {
+        let mut set = MeasurementSet::new();
+        set.insert(Measurement::new(Quantity::Temperature, 21.0));
+        assert_eq!(
+            AirMetrics::try_from(&set).unwrap_err(),
+            ParseMetricsError::MissingReadings
+        );
+    }
+}
@@ -4,60 +4,247 @@ use core::fmt;
 pub struct AirMetrics {
     pub version: u8,
     pub humidity: f32,
-    pub illuminance: f32,
-    pub radon_short: u16,
-    pub radon_long: u16,
+    /// The illuminance and radon are only measured by the Airthings devices
+    pub illuminance: Option<f32>,
+    pub radon_short: Option<u16>,
+    pub radon_long: Option<u16>,
     pub temperature: f32,
-    pub pressure: f32,
-    pub co2_level: u16,
-    pub voc_level: u16,
+    /// The pressure and CO2 are measured by the Wave Plus and the Aranet4, the VOC by the Wave Plus
+    pub pressure: Option<f32>,
+    pub co2_level: Option<u16>,
+    pub voc_level: Option<u16>,
+    /// Percent, Aranet4 only
+    pub battery: Option<u8>,
+    /// Seconds between the measurements of the device, Aranet4 only
+    pub update_interval: Option<u16>,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
 pub enum ParseMetricsError {
-    InsufficientBytes,
-    UnsupportedPacketVersion,
+    /// Only `len` bytes, fewer than [`AirMetrics::PACKET_LEN`]
+    InsufficientBytes {
+        len: usize,
+    },
+    UnsupportedPacketVersion {
+        version: u8,
+    },
+    /// A broadcast without the temperature or the humidity
+    MissingReadings,
+    /// Nothing measured at all
+    NoReadings,
 }
 
+impl fmt::Display for ParseMetricsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InsufficientBytes { len } => {
+                write!(f, "got {} bytes, expected {}", len, AirMetrics::PACKET_LEN)
+            }
+            Self::UnsupportedPacketVersion { version } => {
+                write!(f, "unsupported packet version {}", version)
+            }
+            Self::MissingReadings => f.write_str("no temperature or humidity"),
+            Self::NoReadings => f.write_str("no readings"),
+        }
+    }
+}
+
+/// Value of the 16 bit fields the device couldn't measure
+const UNAVAILABLE: u16 = u16::MAX;
+
 impl AirMetrics {
+    pub const PACKET_LEN: usize = 16;
+    /// The Wave 2 sends 20 bytes, only the first ones are known
+    pub const WAVE2_PACKET_LEN: usize = 20;
+    pub const ARANET4_PACKET_LEN: usize = 13;
+
+    /// Parses the Wave Plus packet. Fields at 0xFFFF, e.g. during the sensor warm up, are `None`
     pub fn from_bytes(bytes: &[u8]) -> Result<Self, ParseMetricsError> {
         // struct format: <BBBxHHHHHHxxxx
-        if bytes.len() < 16 {
-            Err(ParseMetricsError::InsufficientBytes)
+        let mut metrics = Self::from_common_bytes(bytes, Self::PACKET_LEN)?;
+        let field = |i: usize| {
+            Some(u16::from_le_bytes([bytes[i], bytes[i + 1]])).filter(|v| *v != UNAVAILABLE)
+        };
+        metrics.pressure = field(10).map(|p| p as f32 / 50.0);
+        metrics.co2_level = field(12);
+        metrics.voc_level = field(14);
+        Ok(metrics)
+    }
+
+    /// Parses the Wave 2 (Wave Radon gen 2) packet, laid out like the start of the Wave Plus one
+    pub fn from_wave2_bytes(bytes: &[u8]) -> Result<Self, ParseMetricsError> {
+        // struct format: <BBBxHHHxxxxxxxxxx
+        Self::from_common_bytes(bytes, Self::WAVE2_PACKET_LEN)
+    }
+
+    /// Parses the readings of the Aranet4, laid out like its current readings characteristic in
+    /// the broadcasts. They have no version byte
+    pub fn from_aranet4_bytes(bytes: &[u8]) -> Result<Self, ParseMetricsError> {
+        // struct format: <HHHBBBHH, the last ones are the status and the age of the readings
+        if bytes.len() < Self::ARANET4_PACKET_LEN {
+            return Err(ParseMetricsError::InsufficientBytes { len: bytes.len() });
+        }
+        let field = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+        Ok(Self {
+            // The Wave Plus layout
+            version: 1,
+            humidity: bytes[6] as f32,
+            illuminance: None,
+            radon_short: None,
+            radon_long: None,
+            temperature: field(2) as f32 / 20.0,
+            pressure: Some(field(4) as f32 / 10.0),
+            // The top bit is set until the first measurement after a power up
+            co2_level: Some(field(0)).filter(|co2| co2 & 0x8000 == 0),
+            voc_level: None,
+            battery: Some(bytes[7]),
+            update_interval: Some(field(9)),
+        })
+    }
+
+    /// Humidity, illuminance, radon and temperature, shared by the Airthings packets
+    fn from_common_bytes(bytes: &[u8], len: usize) -> Result<Self, ParseMetricsError> {
+        if bytes.len() < len {
+            Err(ParseMetricsError::InsufficientBytes { len: bytes.len() })
         } else if bytes[0] != 1 {
-            Err(ParseMetricsError::UnsupportedPacketVersion)
+            Err(ParseMetricsError::UnsupportedPacketVersion { version: bytes[0] })
         } else {
+            let field = |i: usize| {
+                Some(u16::from_le_bytes([bytes[i], bytes[i + 1]])).filter(|v| *v != UNAVAILABLE)
+            };
             Ok(Self {
                 version: bytes[0],
                 humidity: bytes[1] as f32 / 2.0,
-                illuminance: bytes[2] as f32 / 255.0 * 100.0,
-                radon_short: u16::from_le_bytes([bytes[4], bytes[5]]),
-                radon_long: u16::from_le_bytes([bytes[6], bytes[7]]),
+                illuminance: Some(bytes[2] as f32 / 255.0 * 100.0),
+                radon_short: field(4),
+                radon_long: field(6),
                 temperature: u16::from_le_bytes([bytes[8], bytes[9]]) as f32 / 100.0,
-                pressure: u16::from_le_bytes([bytes[10], bytes[11]]) as f32 / 50.0,
-                co2_level: u16::from_le_bytes([bytes[12], bytes[13]]),
-                voc_level: u16::from_le_bytes([bytes[14], bytes[15]]),
+                pressure: None,
+                co2_level: None,
+                voc_level: None,
+                battery: None,
+                update_interval: None,
             })
         }
     }
+
+    /// Inverse of [`AirMetrics::from_bytes`], missing fields are written as 0xFFFF.
+    /// A missing illuminance is written as 0, the battery and update interval are dropped
+    pub fn to_bytes(&self) -> [u8; Self::PACKET_LEN] {
+        let mut bytes = [0; Self::PACKET_LEN];
+        bytes[0] = self.version;
+        bytes[1] = (self.humidity * 2.0 + 0.5) as u8;
+        bytes[2] = self
+            .illuminance
+            .map_or(0, |i| (i / 100.0 * 255.0 + 0.5) as u8);
+        bytes[4..6].copy_from_slice(&self.radon_short.unwrap_or(UNAVAILABLE).to_le_bytes());
+        bytes[6..8].copy_from_slice(&self.radon_long.unwrap_or(UNAVAILABLE).to_le_bytes());
+        bytes[8..10].copy_from_slice(&((self.temperature * 100.0 + 0.5) as u16).to_le_bytes());
+        let pressure = self.pressure.map(|p| (p * 50.0 + 0.5) as u16);
+        bytes[10..12].copy_from_slice(&pressure.unwrap_or(UNAVAILABLE).to_le_bytes());
+        bytes[12..14].copy_from_slice(&self.co2_level.unwrap_or(UNAVAILABLE).to_le_bytes());
+        bytes[14..16].copy_from_slice(&self.voc_level.unwrap_or(UNAVAILABLE).to_le_bytes());
+        bytes
+    }
 }
-impl fmt::Display for AirMetrics {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "Humidity: {0:.1} %\n\
-            Temperature: {1:.1} C\n\
-            Pressure: {2:.1} hPa\n\
-            CO2: {3} ppm\n\
-            VOC: {4} ppb\n\
-            Radon 1day: {5} Long: {6}",
-            self.humidity,
-            self.temperature,
-            self.pressure,
-            self.co2_level,
-            self.voc_level,
-            self.radon_short,
-            self.radon_long
-        )
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    proptest! {
+        #[test]
+        fn wave_plus_packets_round_trip(mut bytes: [u8; AirMetrics::PACKET_LEN]) {
+            bytes[0] = 1;
+            // Padding
+            bytes[3] = 0;
+            let metrics = AirMetrics::from_bytes(&bytes).unwrap();
+            prop_assert_eq!(metrics.to_bytes(), bytes);
+        }
+
+        #[test]
+        fn short_packets_are_rejected(bytes in prop::collection::vec(any::<u8>(), 0..AirMetrics::PACKET_LEN)) {
+            prop_assert_eq!(
+                AirMetrics::from_bytes(&bytes).unwrap_err(),
+                ParseMetricsError::InsufficientBytes { len: bytes.len() }
+            );
+        }
+    }
+
+    /// 45.5 %, 50 % light, 48 and 61 Bq/m³, 22.37 °C, then the unknown bytes
+    const WAVE2_PACKET: [u8; AirMetrics::WAVE2_PACKET_LEN] = [
+        0x01, 0x5b, 0x80, 0x00, 0x30, 0x00, 0x3d, 0x00, 0xbd, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn wave2_packet_is_decoded() {
+        let metrics = AirMetrics::from_wave2_bytes(&WAVE2_PACKET).unwrap();
+        assert_eq!(metrics.humidity, 45.5);
+        assert!((metrics.illuminance.unwrap() - 50.2).abs() < 0.1);
+        assert_eq!(metrics.radon_short, Some(48));
+        assert_eq!(metrics.radon_long, Some(61));
+        assert_eq!(metrics.temperature, 22.37);
+        assert_eq!(metrics.pressure, None);
+        assert_eq!(metrics.co2_level, None);
+        assert_eq!(metrics.voc_level, None);
+    }
+
+    #[test]
+    fn wave2_radon_is_unknown_during_the_warm_up() {
+        let mut bytes = WAVE2_PACKET;
+        bytes[4..8].copy_from_slice(&[0xff; 4]);
+        let metrics = AirMetrics::from_wave2_bytes(&bytes).unwrap();
+        assert_eq!(metrics.radon_short, None);
+        assert_eq!(metrics.radon_long, None);
+        assert_eq!(metrics.temperature, 22.37);
+    }
+
+    #[test]
+    fn wave2_packet_is_checked() {
+        assert_eq!(
+            AirMetrics::from_wave2_bytes(&WAVE2_PACKET[..AirMetrics::PACKET_LEN]).unwrap_err(),
+            ParseMetricsError::InsufficientBytes {
+                len: AirMetrics::PACKET_LEN
+            }
+        );
+        let mut bytes = WAVE2_PACKET;
+        bytes[0] = 2;
+        assert_eq!(
+            AirMetrics::from_wave2_bytes(&bytes).unwrap_err(),
+            ParseMetricsError::UnsupportedPacketVersion { version: 2 }
+        );
+    }
+
+    /// 812 ppm, 22.35 °C, 1003.2 hPa, 41 %, 87 % battery, green status, every 300 s, 42 s ago
+    const ARANET4_READINGS: [u8; AirMetrics::ARANET4_PACKET_LEN] = [
+        0x2c, 0x03, 0xbf, 0x01, 0x30, 0x27, 0x29, 0x57, 0x01, 0x2c, 0x01, 0x2a, 0x00,
+    ];
+
+    #[test]
+    fn aranet4_readings_are_decoded() {
+        let metrics = AirMetrics::from_aranet4_bytes(&ARANET4_READINGS).unwrap();
+        assert_eq!(metrics.co2_level, Some(812));
+        assert_eq!(metrics.temperature, 22.35);
+        assert_eq!(metrics.pressure, Some(1003.2));
+        assert_eq!(metrics.humidity, 41.0);
+        assert_eq!(metrics.battery, Some(87));
+        assert_eq!(metrics.update_interval, Some(300));
+        assert_eq!(metrics.radon_short, None);
+        assert_eq!(
+            AirMetrics::from_aranet4_bytes(&ARANET4_READINGS[..12]).unwrap_err(),
+            ParseMetricsError::InsufficientBytes { len: 12 }
+        );
+    }
+
+    #[test]
+    fn aranet4_co2_is_unknown_until_the_first_measurement() {
+        let mut bytes = ARANET4_READINGS;
+        bytes[1] |= 0x80;
+        let metrics = AirMetrics::from_aranet4_bytes(&bytes).unwrap();
+        assert_eq!(metrics.co2_level, None);
+        assert_eq!(metrics.temperature, 22.35);
     }
 }
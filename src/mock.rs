@@ -0,0 +1,285 @@
+//! Host implementations of the [`crate::platform`] traits
+use core::convert::Infallible;
+use core::time::Duration;
+use embedded_hal_async::delay::DelayNs;
+use embedded_hal_async::i2c::{self, Operation};
+use heapless::{Deque, Vec};
+
+use crate::framebuffer::Framebuffer;
+use crate::measurement::MeasurementSet;
+use crate::platform::{
+    Button, Buttons, Clock, Display, Log, MetricsSource, Slot, StatusLed, Storage, WakeAlarm,
+};
+
+/// Replays the given responses, one per fetch. Repeats the last one when exhausted
+pub struct ScriptedSource<'a, E> {
+    responses: &'a [Result<MeasurementSet, E>],
+    position: usize,
+}
+
+impl<'a, E> ScriptedSource<'a, E> {
+    pub fn new(responses: &'a [Result<MeasurementSet, E>]) -> Self {
+        assert!(!responses.is_empty());
+        Self {
+            responses,
+            position: 0,
+        }
+    }
+}
+
+impl<E: Clone + core::fmt::Debug + core::fmt::Display> MetricsSource for ScriptedSource<'_, E> {
+    type Error = E;
+
+    async fn fetch(&mut self) -> Result<MeasurementSet, E> {
+        let response = self.responses[self.position.min(self.responses.len() - 1)].clone();
+        self.position += 1;
+        response
+    }
+}
+
+/// Remembers the last requested wake up, tells the time it's set to
+#[derive(Default)]
+pub struct RecordingAlarm {
+    pub requested: Option<Duration>,
+    pub now: Duration,
+}
+
+impl WakeAlarm for RecordingAlarm {
+    type Error = Infallible;
+
+    async fn wake_up_in(&mut self, duration: Duration) -> Result<(), Infallible> {
+        self.requested = Some(duration);
+        Ok(())
+    }
+
+    async fn now(&mut self) -> Result<Duration, Infallible> {
+        Ok(self.now)
+    }
+}
+
+/// Records the brightness changes with how long they were held, returning right away.
+/// The oldest ones are dropped past 64
+#[derive(Default)]
+pub struct RecordingLed {
+    pub brightness: u8,
+    pub steps: Vec<(u8, Duration), 64>,
+}
+
+impl StatusLed for RecordingLed {
+    fn set_brightness(&mut self, percent: u8) {
+        self.brightness = percent;
+    }
+
+    async fn hold(&mut self, duration: Duration) {
+        if self.steps.is_full() {
+            self.steps.remove(0);
+        }
+        let _ = self.steps.push((self.brightness, duration));
+    }
+}
+
+impl Display for Framebuffer {
+    type RefreshError = Infallible;
+
+    async fn refresh(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+}
+
+/// Advances by `step` every time it's read
+pub struct SteppingClock {
+    now: core::cell::Cell<Duration>,
+    pub step: Duration,
+}
+
+impl SteppingClock {
+    pub fn new(step: Duration) -> Self {
+        Self {
+            now: core::cell::Cell::new(Duration::ZERO),
+            step,
+        }
+    }
+}
+
+impl Clock for SteppingClock {
+    fn now(&self) -> Duration {
+        let now = self.now.get();
+        self.now.set(now + self.step);
+        now
+    }
+}
+
+/// Buttons held down for the whole run
+#[derive(Default)]
+pub struct HeldButtons {
+    pub held: &'static [Button],
+}
+
+impl Buttons for HeldButtons {
+    fn is_pressed(&self, button: Button) -> bool {
+        self.held.contains(&button)
+    }
+}
+
+/// RAM backed storage with one fixed size record per slot and up to 64 records per log
+pub struct MemoryStorage<const N: usize> {
+    records: [([u8; N], usize); Slot::COUNT],
+    logs: [Deque<[u8; N], 64>; Log::COUNT],
+}
+
+impl<const N: usize> MemoryStorage<N> {
+    pub fn new() -> Self {
+        Self {
+            records: [([0; N], 0); Slot::COUNT],
+            logs: [const { Deque::new() }; Log::COUNT],
+        }
+    }
+}
+
+impl<const N: usize> Default for MemoryStorage<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug)]
+pub struct RecordTooLarge;
+
+impl<const N: usize> Storage for MemoryStorage<N> {
+    type Error = RecordTooLarge;
+
+    async fn load(&mut self, slot: Slot, buf: &mut [u8]) -> Result<usize, RecordTooLarge> {
+        let (data, len) = &self.records[slot as usize];
+        let target = buf.get_mut(..*len).ok_or(RecordTooLarge)?;
+        target.copy_from_slice(&data[..*len]);
+        Ok(*len)
+    }
+
+    async fn store(&mut self, slot: Slot, data: &[u8]) -> Result<(), RecordTooLarge> {
+        let (record, len) = &mut self.records[slot as usize];
+        record
+            .get_mut(..data.len())
+            .ok_or(RecordTooLarge)?
+            .copy_from_slice(data);
+        *len = data.len();
+        Ok(())
+    }
+
+    async fn append(&mut self, log: Log, record: &[u8]) -> Result<(), RecordTooLarge> {
+        let mut entry = [0; N];
+        entry
+            .get_mut(..record.len())
+            .ok_or(RecordTooLarge)?
+            .copy_from_slice(record);
+        let log = &mut self.logs[log as usize];
+        if log.is_full() {
+            log.pop_front();
+        }
+        let _ = log.push_back(entry);
+        Ok(())
+    }
+
+    async fn read_record(
+        &mut self,
+        log: Log,
+        index: usize,
+        buf: &mut [u8],
+    ) -> Result<bool, RecordTooLarge> {
+        let size = log.record_size();
+        let Some(entry) = self.logs[log as usize].iter().nth(index) else {
+            return Ok(false);
+        };
+        buf.get_mut(..size)
+            .ok_or(RecordTooLarge)?
+            .copy_from_slice(&entry[..size]);
+        Ok(true)
+    }
+}
+
+/// Expected I2C transfer, see [`ReplayI2c`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum I2cTransfer<'a> {
+    Write {
+        address: u8,
+        bytes: &'a [u8],
+    },
+    /// `bytes` are returned to the driver
+    Read {
+        address: u8,
+        bytes: &'a [u8],
+    },
+}
+
+/// Replays recorded I2C transfers, panicking when the driver does anything else
+pub struct ReplayI2c<'a> {
+    transfers: &'a [I2cTransfer<'a>],
+    position: usize,
+}
+
+impl<'a> ReplayI2c<'a> {
+    pub fn new(transfers: &'a [I2cTransfer<'a>]) -> Self {
+        Self {
+            transfers,
+            position: 0,
+        }
+    }
+
+    /// Whether every transfer happened
+    pub fn done(&self) -> bool {
+        self.position == self.transfers.len()
+    }
+}
+
+impl i2c::ErrorType for ReplayI2c<'_> {
+    type Error = Infallible;
+}
+
+impl i2c::I2c for ReplayI2c<'_> {
+    async fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Infallible> {
+        for operation in operations {
+            let expected = self.transfers.get(self.position);
+            self.position += 1;
+            match (operation, expected) {
+                (
+                    Operation::Write(bytes),
+                    Some(I2cTransfer::Write {
+                        address: a,
+                        bytes: b,
+                    }),
+                ) => {
+                    assert_eq!((address, &bytes[..]), (*a, *b));
+                }
+                (
+                    Operation::Read(buf),
+                    Some(I2cTransfer::Read {
+                        address: a,
+                        bytes: b,
+                    }),
+                ) => {
+                    assert_eq!((address, buf.len()), (*a, b.len()));
+                    buf.copy_from_slice(b);
+                }
+                (operation, expected) => {
+                    panic!("unexpected {:?}, expected {:?}", operation, expected)
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns right away, adding up the time it was asked to wait
+#[derive(Default)]
+pub struct RecordingDelay {
+    pub total: Duration,
+}
+
+impl DelayNs for RecordingDelay {
+    async fn delay_ns(&mut self, ns: u32) {
+        self.total += Duration::from_nanos(ns as u64);
+    }
+}
@@ -0,0 +1,129 @@
+//! PIN guarding the provisioning writes, see [`crate::provisioning`].
+//!
+//! A new PIN is drawn for each provisioning session and shown on the badge, in digits and in
+//! a QR code. A client writes it first to unlock the settings, and again after reconnecting.
+use core::fmt;
+
+pub const PIN_LEN: usize = 6;
+/// Wrong PINs accepted per session, the provisioning mode ends after that
+pub const MAX_ATTEMPTS: u8 = 5;
+
+/// Decimal digits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Pin([u8; PIN_LEN]);
+
+impl Pin {
+    /// From a random number, e.g. of the ring oscillator
+    pub fn new(random: u32) -> Self {
+        let mut digits = [0; PIN_LEN];
+        let mut value = random % 10u32.pow(PIN_LEN as u32);
+        for digit in digits.iter_mut().rev() {
+            *digit = b'0' + (value % 10) as u8;
+            value /= 10;
+        }
+        Self(digits)
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only ASCII digits
+        core::str::from_utf8(&self.0).unwrap_or_default()
+    }
+}
+
+impl fmt::Display for Pin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum UnlockError {
+    WrongPin,
+    /// [`MAX_ATTEMPTS`] wrong PINs were written
+    TooManyAttempts,
+}
+
+/// Whether the connected client wrote the PIN
+#[derive(Debug)]
+pub struct PairingLock {
+    pin: Pin,
+    unlocked: bool,
+    wrong_attempts: u8,
+}
+
+impl PairingLock {
+    pub fn new(pin: Pin) -> Self {
+        Self {
+            pin,
+            unlocked: false,
+            wrong_attempts: 0,
+        }
+    }
+
+    pub fn pin(&self) -> &Pin {
+        &self.pin
+    }
+
+    pub fn is_unlocked(&self) -> bool {
+        self.unlocked
+    }
+
+    /// Checks the written `attempt`, zero padded like the other characteristic values
+    pub fn unlock(&mut self, attempt: &[u8]) -> Result<(), UnlockError> {
+        if self.wrong_attempts >= MAX_ATTEMPTS {
+            return Err(UnlockError::TooManyAttempts);
+        }
+        let len = attempt
+            .iter()
+            .position(|c| *c == 0)
+            .unwrap_or(attempt.len());
+        if attempt[..len] == self.pin.0 {
+            self.unlocked = true;
+            return Ok(());
+        }
+        self.unlocked = false;
+        self.wrong_attempts += 1;
+        if self.wrong_attempts >= MAX_ATTEMPTS {
+            Err(UnlockError::TooManyAttempts)
+        } else {
+            Err(UnlockError::WrongPin)
+        }
+    }
+
+    /// When the client disconnects
+    pub fn lock(&mut self) {
+        self.unlocked = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pins_have_six_digits() {
+        assert_eq!(Pin::new(42).as_str(), "000042");
+        assert_eq!(Pin::new(u32::MAX).as_str(), "967295");
+    }
+
+    #[test]
+    fn right_pin_unlocks_until_locked() {
+        let mut lock = PairingLock::new(Pin::new(123_456));
+        assert!(!lock.is_unlocked());
+        assert_eq!(lock.unlock(b"123456\0\0"), Ok(()));
+        assert!(lock.is_unlocked());
+        lock.lock();
+        assert!(!lock.is_unlocked());
+    }
+
+    #[test]
+    fn wrong_pins_run_out() {
+        let mut lock = PairingLock::new(Pin::new(123_456));
+        for _ in 1..MAX_ATTEMPTS {
+            assert_eq!(lock.unlock(b"12345"), Err(UnlockError::WrongPin));
+        }
+        assert_eq!(lock.unlock(b"000000"), Err(UnlockError::TooManyAttempts));
+        assert_eq!(lock.unlock(b"123456"), Err(UnlockError::TooManyAttempts));
+        assert!(!lock.is_unlocked());
+    }
+}
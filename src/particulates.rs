@@ -0,0 +1,569 @@
+//! Particulate matter sensors on the Qw/ST connector: the Plantower PMSA003I and the
+//! Sensirion SEN5x.
+//!
+//! Their fan has to run for a while before the readings settle. [`WithParticulates`] starts it
+//! before fetching the other metrics, so most of the warm up happens during the BLE fetch.
+#![allow(async_fn_in_trait)]
+
+use core::fmt::{self, Debug};
+use core::time::Duration;
+use embedded_hal_async::delay::DelayNs;
+use embedded_hal_async::i2c::I2c;
+use heapless::Vec;
+
+use crate::link::LinkQuality;
+use crate::measurement::MeasurementSet;
+use crate::platform::{Clock, MetricsSource};
+use crate::scd4x::{crc8, Command};
+use crate::timing::PhaseTimings;
+
+pub const PMSA003I_ADDRESS: u8 = 0x12;
+pub const PMSA003I_FRAME_LEN: usize = 32;
+/// Starting every frame
+const PMSA003I_START: [u8; 2] = *b"BM";
+
+pub const SEN5X_ADDRESS: u8 = 0x69;
+
+const START_MEASUREMENT: Command = Command::new(0x0021, 50);
+const STOP_MEASUREMENT: Command = Command::new(0x0104, 200);
+const READ_DATA_READY: Command = Command::new(0x0202, 20);
+const READ_MEASURED_PM_VALUES: Command = Command::new(0x0413, 20);
+
+/// Sensor models, see [`PmSensor`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum PmSensorModel {
+    Pmsa003i = 0,
+    Sen5x = 1,
+}
+
+impl PmSensorModel {
+    pub const ALL: [Self; 2] = [Self::Pmsa003i, Self::Sen5x];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Pmsa003i => "pmsa003i",
+            Self::Sen5x => "sen5x",
+        }
+    }
+}
+
+/// Particles per cm3 of a size bin
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+pub struct ParticleCount {
+    /// µm
+    pub size: f32,
+    pub per_cm3: f32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PmReading {
+    /// µg/m3
+    pub pm1: f32,
+    pub pm2_5: f32,
+    pub pm10: f32,
+    /// Binned the way of each sensor: the PMSA003I counts the particles larger than the size,
+    /// the SEN5x those up to it
+    pub counts: Vec<ParticleCount, 6>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum ParticulateError<E> {
+    I2c(E),
+    /// Not starting with the start characters or of the wrong length
+    BadFrame,
+    /// The frame checksum or a word CRC didn't match
+    Checksum,
+    /// The measurement wasn't done in time
+    NotReady,
+}
+
+impl<E: embedded_hal_async::i2c::Error> fmt::Display for ParticulateError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::I2c(e) => write!(f, "PM sensor I2C error: {}", e.kind()),
+            Self::BadFrame => f.write_str("PM sensor sent a bad frame"),
+            Self::Checksum => f.write_str("PM sensor checksum mismatch"),
+            Self::NotReady => f.write_str("PM measurement not ready"),
+        }
+    }
+}
+
+/// Parses a PMSA003I frame: the start characters, the length of the rest, the mass
+/// concentrations of the standard particle then in the atmospheric environment, the counts per
+/// 0.1 L, the version, an error code and the sum of the bytes before it
+pub fn parse_pmsa003i_frame<E>(bytes: &[u8]) -> Result<PmReading, ParticulateError<E>> {
+    let Some(frame) = bytes.get(..PMSA003I_FRAME_LEN) else {
+        return Err(ParticulateError::BadFrame);
+    };
+    let word = |i: usize| u16::from_be_bytes([frame[i], frame[i + 1]]);
+    if frame[..2] != PMSA003I_START || word(2) as usize != PMSA003I_FRAME_LEN - 4 {
+        return Err(ParticulateError::BadFrame);
+    }
+    let sum = frame[..30].iter().map(|b| *b as u16).sum::<u16>();
+    if sum != word(30) {
+        return Err(ParticulateError::Checksum);
+    }
+    let counts = [0.3, 0.5, 1.0, 2.5, 5.0, 10.0]
+        .iter()
+        .enumerate()
+        .map(|(i, size)| ParticleCount {
+            size: *size,
+            per_cm3: word(16 + i * 2) as f32 / 100.0,
+        })
+        .collect();
+    Ok(PmReading {
+        pm1: word(10) as f32,
+        pm2_5: word(12) as f32,
+        pm10: word(14) as f32,
+        counts,
+    })
+}
+
+/// Parses the SEN5x measured PM values: the mass concentrations of PM1, PM2.5, PM4 and PM10,
+/// the counts of the particles up to 0.5, 1, 2.5, 4 and 10 µm, all scaled by 10, then the
+/// typical particle size. Each word is followed by its CRC
+pub fn parse_sen5x_pm_values<E>(bytes: &[u8]) -> Result<PmReading, ParticulateError<E>> {
+    let words: [u16; 10] = parse_words(bytes)?;
+    let scaled = |i: usize| words[i] as f32 / 10.0;
+    let counts = [0.5, 1.0, 2.5, 4.0, 10.0]
+        .iter()
+        .enumerate()
+        .map(|(i, size)| ParticleCount {
+            size: *size,
+            per_cm3: scaled(4 + i),
+        })
+        .collect();
+    Ok(PmReading {
+        pm1: scaled(0),
+        pm2_5: scaled(1),
+        pm10: scaled(3),
+        counts,
+    })
+}
+
+/// Big endian words, each followed by its CRC
+fn parse_words<const N: usize, E>(bytes: &[u8]) -> Result<[u16; N], ParticulateError<E>> {
+    let Some(bytes) = bytes.get(..N * 3) else {
+        return Err(ParticulateError::BadFrame);
+    };
+    let mut words = [0; N];
+    for (word, chunk) in words.iter_mut().zip(bytes.chunks_exact(3)) {
+        if crc8(&chunk[..2]) != chunk[2] {
+            return Err(ParticulateError::Checksum);
+        }
+        *word = u16::from_be_bytes([chunk[0], chunk[1]]);
+    }
+    Ok(words)
+}
+
+/// A particulate matter sensor
+pub trait ParticulateSensor {
+    type Error: Debug + fmt::Display;
+
+    /// From [`Self::start`] until the readings are settled
+    const WARM_UP: Duration;
+
+    /// Starts the fan and the measurements
+    async fn start(&mut self) -> Result<(), Self::Error>;
+
+    async fn read(&mut self) -> Result<PmReading, Self::Error>;
+
+    /// Stops the fan until the next [`Self::start`]
+    async fn stop(&mut self) -> Result<(), Self::Error>;
+}
+
+/// Measures all the time while powered, so it warms up from the power on
+pub struct Pmsa003i<I> {
+    i2c: I,
+}
+
+impl<I: I2c> Pmsa003i<I> {
+    pub fn new(i2c: I) -> Self {
+        Self { i2c }
+    }
+}
+
+impl<I: I2c> ParticulateSensor for Pmsa003i<I> {
+    type Error = ParticulateError<I::Error>;
+
+    const WARM_UP: Duration = Duration::from_secs(30);
+
+    async fn start(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    async fn read(&mut self) -> Result<PmReading, Self::Error> {
+        let mut frame = [0; PMSA003I_FRAME_LEN];
+        self.i2c
+            .read(PMSA003I_ADDRESS, &mut frame)
+            .await
+            .map_err(ParticulateError::I2c)?;
+        parse_pmsa003i_frame(&frame)
+    }
+
+    /// Only its SET pin stops it, not wired to the connector
+    async fn stop(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// The SEN54 and SEN55 measure the humidity, temperature and VOC too, left to the other sensors
+pub struct Sen5x<I, D> {
+    i2c: I,
+    delay: D,
+}
+
+impl<I: I2c, D: DelayNs> Sen5x<I, D> {
+    pub fn new(i2c: I, delay: D) -> Self {
+        Self { i2c, delay }
+    }
+
+    /// Sends the command, then waits for it to be executed
+    async fn write(&mut self, command: Command) -> Result<(), ParticulateError<I::Error>> {
+        self.i2c
+            .write(SEN5X_ADDRESS, &command.code.to_be_bytes())
+            .await
+            .map_err(ParticulateError::I2c)?;
+        self.delay.delay_ms(command.duration_ms).await;
+        Ok(())
+    }
+
+    /// Reply of `N` words to the command
+    async fn read_reply<const N: usize>(
+        &mut self,
+        command: Command,
+        bytes: &mut [u8],
+    ) -> Result<(), ParticulateError<I::Error>> {
+        self.write(command).await?;
+        self.i2c
+            .read(SEN5X_ADDRESS, &mut bytes[..N * 3])
+            .await
+            .map_err(ParticulateError::I2c)
+    }
+}
+
+impl<I: I2c, D: DelayNs> ParticulateSensor for Sen5x<I, D> {
+    type Error = ParticulateError<I::Error>;
+
+    /// The fan needs a few seconds to spin up, the counts a while more to settle
+    const WARM_UP: Duration = Duration::from_secs(30);
+
+    async fn start(&mut self) -> Result<(), Self::Error> {
+        self.write(START_MEASUREMENT).await
+    }
+
+    async fn read(&mut self) -> Result<PmReading, Self::Error> {
+        let mut bytes = [0; 30];
+        self.read_reply::<1>(READ_DATA_READY, &mut bytes).await?;
+        let [ready] = parse_words(&bytes)?;
+        if ready & 0xff == 0 {
+            return Err(ParticulateError::NotReady);
+        }
+        self.read_reply::<10>(READ_MEASURED_PM_VALUES, &mut bytes)
+            .await?;
+        parse_sen5x_pm_values(&bytes)
+    }
+
+    async fn stop(&mut self) -> Result<(), Self::Error> {
+        self.write(STOP_MEASUREMENT).await
+    }
+}
+
+/// One of the sensors, picked by the config
+pub enum PmSensor<I, D> {
+    Pmsa003i(Pmsa003i<I>),
+    Sen5x(Sen5x<I, D>),
+}
+
+impl<I: I2c, D: DelayNs> PmSensor<I, D> {
+    pub fn new(model: PmSensorModel, i2c: I, delay: D) -> Self {
+        match model {
+            PmSensorModel::Pmsa003i => Self::Pmsa003i(Pmsa003i::new(i2c)),
+            PmSensorModel::Sen5x => Self::Sen5x(Sen5x::new(i2c, delay)),
+        }
+    }
+}
+
+impl<I: I2c, D: DelayNs> ParticulateSensor for PmSensor<I, D> {
+    type Error = ParticulateError<I::Error>;
+
+    /// The longest of the two
+    const WARM_UP: Duration = Duration::from_secs(30);
+
+    async fn start(&mut self) -> Result<(), Self::Error> {
+        match self {
+            Self::Pmsa003i(sensor) => sensor.start().await,
+            Self::Sen5x(sensor) => sensor.start().await,
+        }
+    }
+
+    async fn read(&mut self) -> Result<PmReading, Self::Error> {
+        match self {
+            Self::Pmsa003i(sensor) => sensor.read().await,
+            Self::Sen5x(sensor) => sensor.read().await,
+        }
+    }
+
+    async fn stop(&mut self) -> Result<(), Self::Error> {
+        match self {
+            Self::Pmsa003i(sensor) => sensor.stop().await,
+            Self::Sen5x(sensor) => sensor.stop().await,
+        }
+    }
+}
+
+/// Adds the readings of a particulate matter `sensor` to the measurements of the `inner` source,
+/// see [`MetricsSource::particulates`]. They are skipped when the fetch failed, the error is shown.
+/// Without a sensor it only fetches the `inner` metrics
+pub struct WithParticulates<S, P, C, D> {
+    inner: S,
+    sensor: Option<P>,
+    clock: C,
+    delay: D,
+    reading: Option<PmReading>,
+}
+
+impl<S, P, C, D> WithParticulates<S, P, C, D> {
+    pub fn new(inner: S, sensor: Option<P>, clock: C, delay: D) -> Self {
+        Self {
+            inner,
+            sensor,
+            clock,
+            delay,
+            reading: None,
+        }
+    }
+
+    pub fn inner_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+}
+
+impl<S, P, C, D> MetricsSource for WithParticulates<S, P, C, D>
+where
+    S: MetricsSource,
+    P: ParticulateSensor,
+    C: Clock,
+    D: DelayNs,
+{
+    type Error = S::Error;
+
+    async fn fetch(&mut self) -> Result<MeasurementSet, S::Error> {
+        self.reading = None;
+        let Some(sensor) = &mut self.sensor else {
+            return self.inner.fetch().await;
+        };
+        let started = self.clock.now();
+        let warming_up = sensor.start().await;
+        if let Err(e) = &warming_up {
+            defmt::warn!("PM sensor start failed: {}", defmt::Display2Format(e));
+        }
+        let mut fetched = self.inner.fetch().await;
+        if let (Ok(measurements), Ok(())) = (&mut fetched, &warming_up) {
+            let warm = self.clock.now().saturating_sub(started);
+            if let Some(left) = P::WARM_UP.checked_sub(warm) {
+                self.delay.delay_ms(left.as_millis() as u32).await;
+            }
+            match sensor.read().await {
+                Ok(reading) => {
+                    measurements.merge(&MeasurementSet::from(&reading));
+                    self.reading = Some(reading);
+                }
+                Err(e) => defmt::warn!("PM read failed: {}", defmt::Display2Format(&e)),
+            }
+        }
+        if let Err(e) = sensor.stop().await {
+            defmt::warn!("PM sensor stop failed: {}", defmt::Display2Format(&e));
+        }
+        fetched
+    }
+
+    fn fill_timings(&self, timings: &mut PhaseTimings) {
+        self.inner.fill_timings(timings);
+    }
+
+    fn link_quality(&self) -> Option<LinkQuality> {
+        self.inner.link_quality()
+    }
+
+    fn outdoor(&self) -> Option<MeasurementSet> {
+        self.inner.outdoor()
+    }
+
+    fn particulates(&self) -> Option<PmReading> {
+        self.reading.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::convert::Infallible;
+    use embassy_futures::block_on;
+
+    use super::*;
+    use crate::mock::{I2cTransfer, RecordingDelay, ReplayI2c, ScriptedSource, SteppingClock};
+
+    use crate::measurement::{Measurement, Quantity};
+
+    /// From a CO2 only sensor
+    fn co2(ppm: f32) -> MeasurementSet {
+        let mut set = MeasurementSet::new();
+        set.insert(Measurement::new(Quantity::Co2, ppm));
+        set
+    }
+
+    /// Frame with the given standard particle and atmospheric concentrations and counts
+    fn pmsa003i_frame(words: [u16; 12]) -> [u8; PMSA003I_FRAME_LEN] {
+        let mut frame = [0; PMSA003I_FRAME_LEN];
+        frame[..2].copy_from_slice(&PMSA003I_START);
+        frame[2..4].copy_from_slice(&28u16.to_be_bytes());
+        for (i, word) in words.iter().enumerate() {
+            frame[4 + i * 2..6 + i * 2].copy_from_slice(&word.to_be_bytes());
+        }
+        let sum = frame[..30].iter().map(|b| *b as u16).sum::<u16>();
+        frame[30..].copy_from_slice(&sum.to_be_bytes());
+        frame
+    }
+
+    /// Words each followed by its CRC
+    fn sen5x_reply<const N: usize>(words: [u16; N]) -> Vec<u8, 30> {
+        let mut bytes = Vec::new();
+        for word in words {
+            bytes.extend_from_slice(&word.to_be_bytes()).unwrap();
+            bytes.push(crc8(&word.to_be_bytes())).unwrap();
+        }
+        bytes
+    }
+
+    fn read_pmsa003i(frame: &[u8]) -> Result<PmReading, ParticulateError<Infallible>> {
+        let transfers = [I2cTransfer::Read {
+            address: PMSA003I_ADDRESS,
+            bytes: frame,
+        }];
+        block_on(Pmsa003i::new(ReplayI2c::new(&transfers)).read())
+    }
+
+    fn read_sen5x(ready: &[u8], values: &[u8]) -> Result<PmReading, ParticulateError<Infallible>> {
+        let transfers = [
+            I2cTransfer::Write {
+                address: SEN5X_ADDRESS,
+                bytes: &[0x02, 0x02],
+            },
+            I2cTransfer::Read {
+                address: SEN5X_ADDRESS,
+                bytes: ready,
+            },
+            I2cTransfer::Write {
+                address: SEN5X_ADDRESS,
+                bytes: &[0x04, 0x13],
+            },
+            I2cTransfer::Read {
+                address: SEN5X_ADDRESS,
+                bytes: values,
+            },
+        ];
+        let transfers = if values.is_empty() {
+            &transfers[..2]
+        } else {
+            &transfers[..]
+        };
+        let mut sensor = Sen5x::new(ReplayI2c::new(transfers), RecordingDelay::default());
+        let reading = block_on(sensor.read());
+        assert!(sensor.i2c.done());
+        reading
+    }
+
+    #[test]
+    fn pmsa003i_reads_the_atmospheric_concentrations() {
+        let frame = pmsa003i_frame([4, 7, 9, 5, 8, 11, 1250, 380, 60, 4, 1, 0]);
+        let reading = read_pmsa003i(&frame).unwrap();
+        assert_eq!((reading.pm1, reading.pm2_5, reading.pm10), (5.0, 8.0, 11.0));
+        assert_eq!(reading.counts.len(), 6);
+        assert_eq!(
+            reading.counts[0],
+            ParticleCount {
+                size: 0.3,
+                per_cm3: 12.5
+            }
+        );
+        assert_eq!(reading.counts[5].per_cm3, 0.0);
+    }
+
+    #[test]
+    fn pmsa003i_bad_frames_are_rejected() {
+        let mut frame = pmsa003i_frame([4, 7, 9, 5, 8, 11, 1250, 380, 60, 4, 1, 0]);
+        frame[12] ^= 1;
+        assert_eq!(read_pmsa003i(&frame), Err(ParticulateError::Checksum));
+        frame[0] = 0;
+        assert_eq!(read_pmsa003i(&frame), Err(ParticulateError::BadFrame));
+    }
+
+    #[test]
+    fn sen5x_reads_the_pm_values() {
+        let values = sen5x_reply([52, 81, 95, 104, 3210, 3870, 3920, 3925, 3926, 6]);
+        let reading = read_sen5x(&sen5x_reply([1]), &values).unwrap();
+        assert_eq!((reading.pm1, reading.pm2_5, reading.pm10), (5.2, 8.1, 10.4));
+        assert_eq!(
+            reading.counts[0],
+            ParticleCount {
+                size: 0.5,
+                per_cm3: 321.0
+            }
+        );
+    }
+
+    #[test]
+    fn sen5x_crc_mismatches_are_rejected() {
+        let mut values = sen5x_reply([52, 81, 95, 104, 3210, 3870, 3920, 3925, 3926, 6]);
+        values[4] ^= 1;
+        assert_eq!(
+            read_sen5x(&sen5x_reply([1]), &values),
+            Err(ParticulateError::Checksum)
+        );
+    }
+
+    #[test]
+    fn sen5x_unfinished_measurements_are_not_read() {
+        assert_eq!(
+            read_sen5x(&sen5x_reply([0]), &[]),
+            Err(ParticulateError::NotReady)
+        );
+    }
+
+    #[test]
+    fn warm_up_left_after_the_fetch_is_waited_out() {
+        let frame = pmsa003i_frame([4, 7, 9, 5, 8, 11, 1250, 380, 60, 4, 1, 0]);
+        let transfers = [I2cTransfer::Read {
+            address: PMSA003I_ADDRESS,
+            bytes: &frame,
+        }];
+        let responses = [Ok::<_, Infallible>(co2(812.0))];
+        let mut source = WithParticulates::new(
+            ScriptedSource::new(&responses),
+            Some(Pmsa003i::new(ReplayI2c::new(&transfers))),
+            SteppingClock::new(Duration::from_secs(12)),
+            RecordingDelay::default(),
+        );
+        let measurements = block_on(source.fetch()).unwrap();
+        assert_eq!(source.delay.total, Duration::from_secs(18));
+        assert_eq!(source.particulates().map(|r| r.pm2_5), Some(8.0));
+        assert_eq!(measurements.value(Quantity::Co2), Some(812.0));
+        assert_eq!(measurements.value(Quantity::Pm2_5), Some(8.0));
+    }
+
+    #[test]
+    fn without_a_sensor_only_the_inner_source_is_fetched() {
+        let responses = [Ok::<_, Infallible>(co2(812.0))];
+        let mut source = WithParticulates::<_, Pmsa003i<ReplayI2c>, _, _>::new(
+            ScriptedSource::new(&responses),
+            None,
+            SteppingClock::new(Duration::from_secs(12)),
+            RecordingDelay::default(),
+        );
+        let measurements = block_on(source.fetch()).unwrap();
+        assert_eq!(source.delay.total, Duration::ZERO);
+        assert_eq!(source.particulates(), None);
+        assert_eq!(measurements, co2(812.0));
+    }
+}
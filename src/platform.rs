@@ -0,0 +1,180 @@
+//! Hardware abstraction used by the application logic, so it can run both on the
+//! Badger and on the host.
+#![allow(async_fn_in_trait)]
+
+use core::fmt::{self, Debug};
+use core::time::Duration;
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::mutex::Mutex;
+use embedded_graphics::{pixelcolor::BinaryColor, prelude::*};
+
+use crate::history::Reading;
+use crate::link::LinkQuality;
+use crate::measurement::MeasurementSet;
+use crate::particulates::PmReading;
+use crate::timing::PhaseTimings;
+
+/// Something that can provide the current air metrics, any subset of the quantities
+pub trait MetricsSource {
+    /// Shown on the error screen with its [`fmt::Display`] impl
+    type Error: Debug + fmt::Display;
+
+    async fn fetch(&mut self) -> Result<MeasurementSet, Self::Error>;
+
+    /// Copies the durations of the phases of the last fetch, for sources measuring them
+    fn fill_timings(&self, _timings: &mut PhaseTimings) {}
+
+    /// Signal strength of the last successful fetch, for radio sources
+    fn link_quality(&self) -> Option<LinkQuality> {
+        None
+    }
+
+    /// Outdoor conditions from a sensor seen nearby, see [`crate::profile::SensorProfile::is_outdoor`],
+    /// for sources scanning the advertisements
+    fn outdoor(&self) -> Option<MeasurementSet> {
+        None
+    }
+
+    /// Particulate matter measured along with the last successful fetch, for sources with a
+    /// PM sensor, see [`crate::particulates::WithParticulates`]
+    fn particulates(&self) -> Option<PmReading> {
+        None
+    }
+}
+
+/// Monotonic time since the boot
+pub trait Clock {
+    fn now(&self) -> Duration;
+}
+
+/// Clock able to wake the device up after the power has been cut
+pub trait WakeAlarm {
+    type Error: Debug;
+
+    async fn wake_up_in(&mut self, duration: Duration) -> Result<(), Self::Error>;
+
+    /// Time since an epoch recent enough for the seconds to fit in a `u32`, e.g. the UNIX one.
+    /// Unlike [`Clock`] it keeps running through the power-offs
+    async fn now(&mut self) -> Result<Duration, Self::Error>;
+}
+
+/// Drawing surface that has to be explicitly pushed to the panel
+pub trait Display: DrawTarget<Color = BinaryColor> {
+    type RefreshError: Debug;
+
+    async fn refresh(&mut self) -> Result<(), Self::RefreshError>;
+
+    /// Puts the panel into its lowest power state before the power is cut. It has to be reset
+    /// and set up again to be refreshed after that
+    async fn power_down(&mut self) -> Result<(), Self::RefreshError> {
+        Ok(())
+    }
+}
+
+/// Dimmable status LED, see [`crate::led`]
+pub trait StatusLed {
+    /// Percent, 0 is off
+    fn set_brightness(&mut self, percent: u8);
+
+    /// Waits keeping the LED as it is, to time the patterns
+    async fn hold(&mut self, duration: Duration);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Button {
+    Up,
+    Down,
+    A,
+    B,
+    C,
+}
+
+impl Button {
+    pub const ALL: [Self; 5] = [Self::Up, Self::Down, Self::A, Self::B, Self::C];
+}
+
+pub trait Buttons {
+    fn is_pressed(&self, button: Button) -> bool;
+}
+
+/// Independent records kept in the persistent storage
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Slot {
+    Config,
+    /// See [`crate::gatt_cache`]
+    GattCache,
+    /// See [`crate::alert::AlertStates`]
+    Alerts,
+}
+
+impl Slot {
+    pub const COUNT: usize = 3;
+}
+
+/// Append-only logs of fixed size records. The oldest records are dropped when a log is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Log {
+    /// Past readings, see [`crate::history`]
+    History,
+    /// Cycle [`PhaseTimings`], see [`crate::timing`]
+    Timings,
+}
+
+impl Log {
+    pub const COUNT: usize = 2;
+
+    pub const fn record_size(self) -> usize {
+        match self {
+            Self::History => Reading::PACKET_LEN,
+            Self::Timings => PhaseTimings::PACKET_LEN,
+        }
+    }
+}
+
+/// Storage that survives the power-off between wake cycles
+pub trait Storage {
+    type Error: Debug;
+
+    /// Reads the record into `buf` returning its length. Empty records have length 0
+    async fn load(&mut self, slot: Slot, buf: &mut [u8]) -> Result<usize, Self::Error>;
+
+    async fn store(&mut self, slot: Slot, data: &[u8]) -> Result<(), Self::Error>;
+
+    /// Adds a record of [`Log::record_size`] bytes to the log
+    async fn append(&mut self, log: Log, record: &[u8]) -> Result<(), Self::Error>;
+
+    /// Reads the `index`-th record counting from the oldest one.
+    /// Returns `false` when there is no such record
+    async fn read_record(
+        &mut self,
+        log: Log,
+        index: usize,
+        buf: &mut [u8],
+    ) -> Result<bool, Self::Error>;
+}
+
+/// Storage shared by concurrent tasks, locked for each operation
+impl<M: RawMutex, S: Storage> Storage for &Mutex<M, S> {
+    type Error = S::Error;
+
+    async fn load(&mut self, slot: Slot, buf: &mut [u8]) -> Result<usize, S::Error> {
+        self.lock().await.load(slot, buf).await
+    }
+
+    async fn store(&mut self, slot: Slot, data: &[u8]) -> Result<(), S::Error> {
+        self.lock().await.store(slot, data).await
+    }
+
+    async fn append(&mut self, log: Log, record: &[u8]) -> Result<(), S::Error> {
+        self.lock().await.append(log, record).await
+    }
+
+    async fn read_record(
+        &mut self,
+        log: Log,
+        index: usize,
+        buf: &mut [u8],
+    ) -> Result<bool, S::Error> {
+        self.lock().await.read_record(log, index, buf).await
+    }
+}
@@ -0,0 +1,376 @@
+//! Supported sensors: how they are recognized from their advertisements, where their metrics
+//! are read over GATT if they are, and how the metrics are packed.
+//!
+//! Supporting a new sensor is a [`SensorProfile`] impl added to [`PROFILES`]
+use core::fmt;
+
+use crate::adv::{
+    airthings_serial, manufacturer_data, service_data, AIRTHINGS_COMPANY_ID, SAF_TEHNIKA_COMPANY_ID,
+};
+use crate::measurement::MeasurementSet;
+use crate::metrics::{AirMetrics, ParseMetricsError};
+use crate::ruuvi::{RuuviData, RUUVI_COMPANY_ID};
+use crate::xiaomi::{ThermometerData, BTHOME_UUID, ENVIRONMENTAL_SENSING_UUID};
+
+/// GATT attribute UUID, independent of the BLE stack
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum GattUuid {
+    Short(u16),
+    /// Little endian, the byte order on the air
+    Long([u8; 16]),
+}
+
+/// Where a sensor keeps its metrics
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct GattReadPlan {
+    pub service: GattUuid,
+    pub characteristic: GattUuid,
+}
+
+/// A supported sensor model.
+///
+/// The metrics decoded from either source are a [`MeasurementSet`] of the quantities the model
+/// measures
+pub trait SensorProfile: Sync {
+    fn name(&self) -> &'static str;
+
+    /// The data identifying this model in a fixed advertisement `payload`, passed to the other
+    /// methods. `None` for the advertisements of other devices
+    fn match_adv<'a>(&self, payload: &'a [u8]) -> Option<&'a [u8]>;
+
+    /// Readings broadcast in the matched `data`, for the models broadcasting them
+    fn decode_adv(&self, _data: &[u8]) -> Option<MeasurementSet> {
+        None
+    }
+
+    /// Shown as the outdoor conditions under the metrics, never read as the main sensor
+    fn is_outdoor(&self) -> bool {
+        false
+    }
+
+    /// Serial number advertised in the matched `data`
+    fn serial(&self, _data: &[u8]) -> Option<u32> {
+        None
+    }
+
+    /// `None` for the models that are only listened to, their metrics come from
+    /// [`Self::decode_adv`]
+    fn read_plan(&self) -> Option<GattReadPlan> {
+        None
+    }
+
+    /// Decodes the characteristic of the [`Self::read_plan`]
+    fn decode_read(&self, _bytes: &[u8]) -> Result<MeasurementSet, ParseMetricsError> {
+        Err(ParseMetricsError::MissingReadings)
+    }
+}
+
+impl fmt::Debug for dyn SensorProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Every supported model, tried in order. More specific matches come first
+pub static PROFILES: [&dyn SensorProfile; 6] =
+    [&Wave2, &WavePlus, &Aranet4, &Lywsd03mmc, &BtHome, &RuuviTag];
+
+/// The first model in [`PROFILES`] matching the advertisement `payload`, with the matched data
+pub fn find_sensor(payload: &[u8]) -> Option<(&'static dyn SensorProfile, &[u8])> {
+    PROFILES
+        .iter()
+        .find_map(|profile| profile.match_adv(payload).map(|data| (*profile, data)))
+}
+
+/// Airthings models are told apart by the first 4 digits of the serial number
+const WAVE2_SERIAL_PREFIX: u32 = 2950;
+
+fn is_wave2(company_data: &[u8]) -> bool {
+    airthings_serial(company_data).map(|s| s / 1_000_000) == Some(WAVE2_SERIAL_PREFIX)
+}
+
+/// Also used for the unknown Airthings models, assumed to talk like it. The Airthings
+/// advertisements only carry the serial number, the readings are always read over GATT
+pub struct WavePlus;
+
+impl SensorProfile for WavePlus {
+    fn name(&self) -> &'static str {
+        "Wave Plus"
+    }
+
+    fn match_adv<'a>(&self, payload: &'a [u8]) -> Option<&'a [u8]> {
+        manufacturer_data(payload, AIRTHINGS_COMPANY_ID).filter(|data| !is_wave2(data))
+    }
+
+    fn serial(&self, data: &[u8]) -> Option<u32> {
+        airthings_serial(data)
+    }
+
+    fn read_plan(&self) -> Option<GattReadPlan> {
+        Some(GattReadPlan {
+            // b42e1c08-ade7-11e4-89d3-123b93f75cba
+            service: GattUuid::Long([
+                0xba, 0x5c, 0xf7, 0x93, 0x3b, 0x12, 0xd3, 0x89, 0xe4, 0x11, 0xe7, 0xad, 0x08, 0x1c,
+                0x2e, 0xb4,
+            ]),
+            // b42e2a68-ade7-11e4-89d3-123b93f75cba
+            characteristic: GattUuid::Long([
+                0xba, 0x5c, 0xf7, 0x93, 0x3b, 0x12, 0xd3, 0x89, 0xe4, 0x11, 0xe7, 0xad, 0x68, 0x2a,
+                0x2e, 0xb4,
+            ]),
+        })
+    }
+
+    fn decode_read(&self, bytes: &[u8]) -> Result<MeasurementSet, ParseMetricsError> {
+        AirMetrics::from_bytes(bytes).map(|metrics| MeasurementSet::from(&metrics))
+    }
+}
+
+/// Wave Radon gen 2, measures the radon, temperature and humidity only
+pub struct Wave2;
+
+impl SensorProfile for Wave2 {
+    fn name(&self) -> &'static str {
+        "Wave 2"
+    }
+
+    fn match_adv<'a>(&self, payload: &'a [u8]) -> Option<&'a [u8]> {
+        manufacturer_data(payload, AIRTHINGS_COMPANY_ID).filter(|data| is_wave2(data))
+    }
+
+    fn serial(&self, data: &[u8]) -> Option<u32> {
+        airthings_serial(data)
+    }
+
+    fn read_plan(&self) -> Option<GattReadPlan> {
+        Some(GattReadPlan {
+            // b42e4a8e-ade7-11e4-89d3-123b93f75cba
+            service: GattUuid::Long([
+                0xba, 0x5c, 0xf7, 0x93, 0x3b, 0x12, 0xd3, 0x89, 0xe4, 0x11, 0xe7, 0xad, 0x8e, 0x4a,
+                0x2e, 0xb4,
+            ]),
+            // b42e4dcc-ade7-11e4-89d3-123b93f75cba
+            characteristic: GattUuid::Long([
+                0xba, 0x5c, 0xf7, 0x93, 0x3b, 0x12, 0xd3, 0x89, 0xe4, 0x11, 0xe7, 0xad, 0xcc, 0x4d,
+                0x2e, 0xb4,
+            ]),
+        })
+    }
+
+    fn decode_read(&self, bytes: &[u8]) -> Result<MeasurementSet, ParseMetricsError> {
+        AirMetrics::from_wave2_bytes(bytes).map(|metrics| MeasurementSet::from(&metrics))
+    }
+}
+
+/// Aranet4, read from its broadcasts only. They carry the readings once the Smart Home
+/// integrations are enabled in the Aranet app. The current readings characteristic
+/// (f0cd3001-95da-4f4b-9ac8-aa55d312af0c) needs a bonded link, and the badge doesn't pair
+pub struct Aranet4;
+
+/// Version and flags before the readings in the manufacturer data
+const ARANET4_ADV_HEADER_LEN: usize = 8;
+
+impl SensorProfile for Aranet4 {
+    fn name(&self) -> &'static str {
+        "Aranet4"
+    }
+
+    fn match_adv<'a>(&self, payload: &'a [u8]) -> Option<&'a [u8]> {
+        manufacturer_data(payload, SAF_TEHNIKA_COMPANY_ID)
+    }
+
+    /// `None` while the integrations are disabled, the data ends with the header then
+    fn decode_adv(&self, data: &[u8]) -> Option<MeasurementSet> {
+        let metrics = AirMetrics::from_aranet4_bytes(data.get(ARANET4_ADV_HEADER_LEN..)?).ok()?;
+        Some(MeasurementSet::from(&metrics))
+    }
+}
+
+/// Xiaomi LYWSD03MMC with the ATC1441 or pvvx firmware, read from its broadcasts only.
+/// The Environmental Sensing service data is only recognized in their layouts
+pub struct Lywsd03mmc;
+
+impl SensorProfile for Lywsd03mmc {
+    fn name(&self) -> &'static str {
+        "LYWSD03MMC"
+    }
+
+    fn match_adv<'a>(&self, payload: &'a [u8]) -> Option<&'a [u8]> {
+        service_data(payload, ENVIRONMENTAL_SENSING_UUID)
+            .filter(|data| ThermometerData::from_service_data(data).is_ok())
+    }
+
+    fn decode_adv(&self, data: &[u8]) -> Option<MeasurementSet> {
+        let data = ThermometerData::from_service_data(data).ok()?;
+        Some(MeasurementSet::from(&AirMetrics::from(data)))
+    }
+}
+
+/// Any thermometer broadcasting in the BTHome v2 format, e.g. a LYWSD03MMC set up so by pvvx
+pub struct BtHome;
+
+impl SensorProfile for BtHome {
+    fn name(&self) -> &'static str {
+        "BTHome"
+    }
+
+    fn match_adv<'a>(&self, payload: &'a [u8]) -> Option<&'a [u8]> {
+        service_data(payload, BTHOME_UUID)
+    }
+
+    fn decode_adv(&self, data: &[u8]) -> Option<MeasurementSet> {
+        let data = ThermometerData::from_bthome_bytes(data).ok()?;
+        Some(MeasurementSet::from(&AirMetrics::from(data)))
+    }
+}
+
+/// RuuviTag broadcasting in the RAWv2 format, shown as the outdoor conditions
+pub struct RuuviTag;
+
+impl SensorProfile for RuuviTag {
+    fn name(&self) -> &'static str {
+        "RuuviTag"
+    }
+
+    fn match_adv<'a>(&self, payload: &'a [u8]) -> Option<&'a [u8]> {
+        manufacturer_data(payload, RUUVI_COMPANY_ID)
+    }
+
+    fn decode_adv(&self, data: &[u8]) -> Option<MeasurementSet> {
+        let data = RuuviData::from_bytes(data)
+            .inspect_err(|e| defmt::debug!("Unsupported RuuviTag broadcast: {:?}", e))
+            .ok()?;
+        Some(MeasurementSet::from(&data))
+    }
+
+    fn is_outdoor(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::measurement::Quantity;
+
+    /// Flags, then the manufacturer data of Airthings with the serial and 12 more bytes
+    fn airthings_adv(serial: u32) -> std::vec::Vec<u8> {
+        let mut payload = std::vec![0x02, 0x01, 0x06, 0x13, 0xff, 0x34, 0x03];
+        payload.extend_from_slice(&serial.to_le_bytes());
+        // Starts like a metrics packet, but it isn't one
+        payload.extend_from_slice(&[
+            0x01, 0x52, 0x00, 0x00, 0x09, 0x00, 0x0e, 0x00, 0x00, 0, 0, 0,
+        ]);
+        payload
+    }
+
+    #[test]
+    fn airthings_advertisements_carry_no_readings() {
+        let payload = airthings_adv(2_930_012_345);
+        let (profile, data) = find_sensor(&payload).unwrap();
+        assert_eq!(profile.name(), "Wave Plus");
+        assert_eq!(profile.serial(data), Some(2_930_012_345));
+        assert!(profile.decode_adv(data).is_none());
+        assert!(profile.read_plan().is_some());
+    }
+
+    #[test]
+    fn wave2_is_told_apart_by_the_serial() {
+        let payload = airthings_adv(2_950_000_042);
+        let (profile, data) = find_sensor(&payload).unwrap();
+        assert_eq!(profile.name(), "Wave 2");
+        assert_eq!(profile.serial(data), Some(2_950_000_042));
+        assert!(profile.decode_adv(data).is_none());
+    }
+
+    /// Manufacturer data of SAF Tehnika: the flags with the integrations enabled, the firmware
+    /// version and the readings, when given
+    fn aranet4_adv(readings: &[u8]) -> std::vec::Vec<u8> {
+        let mut payload = std::vec![0x02, 0x01, 0x06];
+        payload.push(3 + 8 + readings.len() as u8);
+        payload.extend_from_slice(&[0xff, 0x02, 0x07]);
+        payload.extend_from_slice(&[0x22, 0x13, 0x00, 0x01, 0x01, 0x00, 0x0c, 0x0f]);
+        payload.extend_from_slice(readings);
+        payload
+    }
+
+    #[test]
+    fn aranet4_readings_are_taken_from_the_broadcasts() {
+        // 812 ppm, 22.35 °C, 1003.2 hPa, 41 %, 87 % battery, every 300 s
+        let payload = aranet4_adv(&[
+            0x2c, 0x03, 0xbf, 0x01, 0x30, 0x27, 0x29, 0x57, 0x01, 0x2c, 0x01, 0x2a, 0x00,
+        ]);
+        let (profile, data) = find_sensor(&payload).unwrap();
+        assert_eq!(profile.name(), "Aranet4");
+        assert!(profile.read_plan().is_none());
+        let metrics = profile.decode_adv(data).unwrap();
+        assert_eq!(metrics.value(Quantity::Co2), Some(812.0));
+        assert_eq!(metrics.value(Quantity::Temperature), Some(22.35));
+        assert_eq!(metrics.value(Quantity::Battery), Some(87.0));
+    }
+
+    #[test]
+    fn aranet4_without_the_integrations_has_no_readings() {
+        let payload = aranet4_adv(&[]);
+        let (profile, data) = find_sensor(&payload).unwrap();
+        assert_eq!(profile.name(), "Aranet4");
+        assert!(profile.decode_adv(data).is_none());
+    }
+
+    #[test]
+    fn atc1441_thermometer_is_found() {
+        // 22.5 °C, 45 %, 80 % battery at 3000 mV, frame 7
+        let payload = [
+            0x02, 0x01, 0x06, 0x10, 0x16, 0x1a, 0x18, 0xa4, 0xc1, 0x38, 0x01, 0x02, 0x03, 0x00,
+            0xe1, 0x2d, 0x50, 0x0b, 0xb8, 0x07,
+        ];
+        let (profile, data) = find_sensor(&payload).unwrap();
+        assert_eq!(profile.name(), "LYWSD03MMC");
+        assert!(!profile.is_outdoor());
+        let metrics = profile.decode_adv(data).unwrap();
+        assert_eq!(metrics.value(Quantity::Temperature), Some(22.5));
+        assert_eq!(metrics.value(Quantity::Humidity), Some(45.0));
+        assert_eq!(metrics.value(Quantity::Battery), Some(80.0));
+    }
+
+    #[test]
+    fn bthome_thermometer_is_found() {
+        // 21.5 °C, 45 %, 90 % battery
+        let payload = [
+            0x02, 0x01, 0x06, 0x0c, 0x16, 0xd2, 0xfc, 0x40, 0x02, 0x66, 0x08, 0x03, 0x94, 0x11,
+            0x01, 0x5a,
+        ];
+        let (profile, data) = find_sensor(&payload).unwrap();
+        assert_eq!(profile.name(), "BTHome");
+        let metrics = profile.decode_adv(data).unwrap();
+        assert_eq!(metrics.value(Quantity::Temperature), Some(21.5));
+        assert_eq!(metrics.value(Quantity::Humidity), Some(45.0));
+        assert_eq!(metrics.value(Quantity::Battery), Some(90.0));
+    }
+
+    #[test]
+    fn ruuvi_tag_is_an_outdoor_sensor() {
+        let mut payload = std::vec![0x02, 0x01, 0x06, 0x1b, 0xff, 0x99, 0x04];
+        // The valid data test vector of the RAWv2 format
+        payload.extend_from_slice(&[
+            0x05, 0x12, 0xfc, 0x53, 0x94, 0xc3, 0x7c, 0x00, 0x04, 0xff, 0xfc, 0x04, 0x0c, 0xac,
+            0x36, 0x42, 0x00, 0xcd, 0xcb, 0xb8, 0x33, 0x4c, 0x88, 0x4f,
+        ]);
+        let (profile, data) = find_sensor(&payload).unwrap();
+        assert_eq!(profile.name(), "RuuviTag");
+        assert!(profile.is_outdoor());
+        assert!(profile.read_plan().is_none());
+        let metrics = profile.decode_adv(data).unwrap();
+        assert_eq!(metrics.len(), 3);
+        assert!((metrics.value(Quantity::Temperature).unwrap() - 24.3).abs() < 1e-3);
+        assert!((metrics.value(Quantity::Pressure).unwrap() - 1000.44).abs() < 1e-2);
+    }
+
+    #[test]
+    fn other_devices_are_not_found() {
+        assert!(find_sensor(&[]).is_none());
+        // Flags and the complete local name
+        let payload = [0x02, 0x01, 0x06, 0x06, 0x09, b't', b'r', b'a', b'w', b'm'];
+        assert!(find_sensor(&payload).is_none());
+    }
+}
@@ -0,0 +1,220 @@
+//! Provisioning mode: a GATT server a phone can write the settings to.
+//!
+//! Every characteristic takes a UTF-8 value, shorter values are zero padded.
+//! The settings are only taken once the [`Pin`] shown on the screen was written to the PIN
+//! characteristic, on every connection. Values are validated and saved right away, the
+//! result of the last write can be read from (or subscribed to on) the status characteristic.
+use core::cell::RefCell;
+use embassy_futures::select::select;
+use heapless::Vec;
+use trouble_host::prelude::*;
+
+use crate::ble::BleSession;
+use crate::config::{Config, ConfigKey};
+use crate::pairing::{PairingLock, Pin, UnlockError, PIN_LEN};
+use crate::platform::Storage;
+
+pub const DEVICE_NAME: &str = "trawm";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum Status {
+    Idle = 0,
+    Saved = 1,
+    InvalidValue = 2,
+    UnknownKey = 3,
+    StorageError = 4,
+    /// The PIN wasn't written on this connection, the value was ignored
+    Locked = 5,
+    WrongPin = 6,
+    Unlocked = 7,
+}
+
+#[gatt_server]
+struct Server {
+    config: ConfigService,
+}
+
+// UUIDs are "trawm" in ASCII followed by the characteristic number
+#[gatt_service(uuid = "74726177-6d00-4000-8000-000000000000")]
+struct ConfigService {
+    #[characteristic(uuid = "74726177-6d01-4000-8000-000000000000", write)]
+    wifi_ssid: [u8; 32],
+    #[characteristic(uuid = "74726177-6d02-4000-8000-000000000000", write)]
+    wifi_passphrase: [u8; 63],
+    #[characteristic(uuid = "74726177-6d03-4000-8000-000000000000", write)]
+    mqtt_broker: [u8; 64],
+    #[characteristic(uuid = "74726177-6d04-4000-8000-000000000000", write)]
+    airthings_serial: [u8; 10],
+    /// Any other setting as `key=value`, see [`ConfigKey::name`]
+    #[characteristic(uuid = "74726177-6d05-4000-8000-000000000000", write)]
+    setting: [u8; 64],
+    #[characteristic(uuid = "74726177-6d06-4000-8000-000000000000", read, notify)]
+    status: u8,
+    /// The digits shown on the screen
+    #[characteristic(uuid = "74726177-6d07-4000-8000-000000000000", write)]
+    pin: [u8; PIN_LEN],
+}
+
+/// Runs the GATT server until `timeout` passes without any writes, or too many wrong PINs
+/// were written
+pub async fn run<S: Storage>(
+    session: &mut BleSession,
+    config: &mut Config,
+    storage: &mut S,
+    pin: Pin,
+    timeout: embassy_time::Duration,
+) {
+    let server = Server::new_with_config(
+        session.stack(),
+        GapConfig::Peripheral(PeripheralConfig {
+            name: DEVICE_NAME,
+            appearance: &appearance::GENERIC_SENSOR,
+        }),
+    )
+    .unwrap();
+    let lock = RefCell::new(PairingLock::new(pin));
+
+    let advertise = async {
+        loop {
+            if let Err(e) = advertise(session.peripheral()).await {
+                defmt::error!("Advertising failed: {:?}", e);
+            }
+            // The next client has to write the PIN again
+            lock.borrow_mut().lock();
+        }
+    };
+    let serve = async {
+        loop {
+            let Ok(event) = embassy_time::with_timeout(timeout, server.next()).await else {
+                defmt::info!("Provisioning timed out");
+                return;
+            };
+            match event {
+                Ok(GattEvent::Write { handle, .. }) if handle == server.config.pin.handle => {
+                    let attempt = server.get(&server.config.pin).unwrap_or_default();
+                    let status = match lock.borrow_mut().unlock(&attempt) {
+                        Ok(()) => Status::Unlocked,
+                        Err(UnlockError::WrongPin) => Status::WrongPin,
+                        Err(UnlockError::TooManyAttempts) => {
+                            defmt::warn!("Too many wrong PINs, leaving provisioning");
+                            let _ = server
+                                .notify(&server.config.status, &(Status::WrongPin as u8))
+                                .await;
+                            return;
+                        }
+                    };
+                    defmt::info!("Provisioning PIN: {:?}", status);
+                    let _ = server.notify(&server.config.status, &(status as u8)).await;
+                }
+                Ok(GattEvent::Write { .. }) if !lock.borrow().is_unlocked() => {
+                    defmt::warn!("Provisioning write before the PIN");
+                    let _ = server
+                        .notify(&server.config.status, &(Status::Locked as u8))
+                        .await;
+                }
+                Ok(GattEvent::Write { handle, .. }) => {
+                    let status = match handle_write(&server, handle, config) {
+                        Ok(()) => match config.save(storage).await {
+                            Ok(()) => Status::Saved,
+                            Err(e) => {
+                                defmt::error!("Config save failed: {:?}", defmt::Debug2Format(&e));
+                                Status::StorageError
+                            }
+                        },
+                        Err(status) => status,
+                    };
+                    defmt::info!("Provisioning write: {:?}", status);
+                    let _ = server.notify(&server.config.status, &(status as u8)).await;
+                }
+                Ok(_) => (),
+                Err(e) => defmt::error!("GATT error: {:?}", e),
+            }
+        }
+    };
+    select(serve, advertise).await;
+}
+
+async fn advertise<C: Controller>(
+    peripheral: &mut Peripheral<'_, C>,
+) -> Result<(), BleHostError<C::Error>> {
+    let mut adv_data = [0; 31];
+    let len = AdStructure::encode_slice(
+        &[
+            AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED),
+            AdStructure::CompleteLocalName(DEVICE_NAME.as_bytes()),
+        ],
+        &mut adv_data[..],
+    )?;
+    let conn = peripheral
+        .advertise(
+            &Default::default(),
+            Advertisement::ConnectableScannableUndirected {
+                adv_data: &adv_data[..len],
+                scan_data: &[],
+            },
+        )
+        .await?;
+    defmt::info!("Provisioning client connected");
+    while conn.is_connected() {
+        if let ConnectionEvent::Disconnected { reason } = conn.next().await {
+            defmt::info!("Provisioning client disconnected: {:?}", reason);
+        }
+    }
+    Ok(())
+}
+
+fn handle_write<C: Controller>(
+    server: &Server<'_, '_, C>,
+    handle: u16,
+    config: &mut Config,
+) -> Result<(), Status> {
+    let service = &server.config;
+    let (key, raw) = if handle == service.wifi_ssid.handle {
+        (ConfigKey::WifiSsid, read(server, &service.wifi_ssid)?)
+    } else if handle == service.wifi_passphrase.handle {
+        (
+            ConfigKey::WifiPassphrase,
+            read(server, &service.wifi_passphrase)?,
+        )
+    } else if handle == service.mqtt_broker.handle {
+        (ConfigKey::MqttBroker, read(server, &service.mqtt_broker)?)
+    } else if handle == service.airthings_serial.handle {
+        (
+            ConfigKey::AirthingsSerial,
+            read(server, &service.airthings_serial)?,
+        )
+    } else if handle == service.setting.handle {
+        let raw = read(server, &service.setting)?;
+        let setting = as_str(&raw).ok_or(Status::InvalidValue)?;
+        let (name, value) = setting.split_once('=').ok_or(Status::InvalidValue)?;
+        let key = ConfigKey::from_name(name).ok_or(Status::UnknownKey)?;
+        return set(config, key, value);
+    } else {
+        return Err(Status::UnknownKey);
+    };
+    set(config, key, as_str(&raw).ok_or(Status::InvalidValue)?)
+}
+
+fn read<C: Controller, const N: usize>(
+    server: &Server<'_, '_, C>,
+    characteristic: &Characteristic<[u8; N]>,
+) -> Result<Vec<u8, 64>, Status> {
+    let value = server
+        .get(characteristic)
+        .map_err(|_| Status::InvalidValue)?;
+    Vec::from_slice(&value).map_err(|_| Status::InvalidValue)
+}
+
+fn set(config: &mut Config, key: ConfigKey, value: &str) -> Result<(), Status> {
+    config.set(key, value).map_err(|e| {
+        defmt::warn!("Rejected provisioning value: {:?}", e);
+        Status::InvalidValue
+    })
+}
+
+/// Characteristic value without the zero padding
+fn as_str(raw: &[u8]) -> Option<&str> {
+    let len = raw.iter().position(|c| *c == 0).unwrap_or(raw.len());
+    core::str::from_utf8(&raw[..len]).ok()
+}
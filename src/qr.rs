@@ -0,0 +1,256 @@
+//! Minimal QR code encoder for the provisioning screen: version 2 (25×25 modules), error
+//! correction level M, byte mode and mask 0. That's enough for the short texts shown there.
+//!
+//! Follows ISO/IEC 18004, any reader decodes it although the mask isn't the optimal one.
+
+/// Modules per side
+pub const SIZE: usize = 25;
+/// Bytes that fit in the byte mode
+pub const CAPACITY: usize = 26;
+
+const DATA_CODEWORDS: usize = 28;
+const ECC_CODEWORDS: usize = 16;
+/// Center of the only alignment pattern of version 2
+const ALIGNMENT: usize = 18;
+/// Error correction level M, in the format information
+const ECC_LEVEL_BITS: u32 = 0b00;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QrCode {
+    /// One bit per module, set when dark, the bit `x` of the row `y`
+    rows: [u32; SIZE],
+    /// Finder, timing and alignment patterns and the format information, left out from the
+    /// data and the mask
+    function: [u32; SIZE],
+}
+
+impl QrCode {
+    /// `None` when `data` is longer than [`CAPACITY`]
+    pub fn encode(data: &[u8]) -> Option<Self> {
+        if data.len() > CAPACITY {
+            return None;
+        }
+        let mut qr = Self {
+            rows: [0; SIZE],
+            function: [0; SIZE],
+        };
+        qr.draw_function_patterns();
+        let codewords = codewords(data);
+        qr.draw_codewords(&codewords);
+        qr.apply_mask();
+        qr.draw_format_bits();
+        Some(qr)
+    }
+
+    /// Whether the module at the column `x` and the row `y` is dark
+    pub fn module(&self, x: usize, y: usize) -> bool {
+        self.rows[y] >> x & 1 != 0
+    }
+
+    fn set(&mut self, x: usize, y: usize, dark: bool) {
+        if dark {
+            self.rows[y] |= 1 << x;
+        } else {
+            self.rows[y] &= !(1 << x);
+        }
+    }
+
+    fn set_function(&mut self, x: usize, y: usize, dark: bool) {
+        self.set(x, y, dark);
+        self.function[y] |= 1 << x;
+    }
+
+    fn is_function(&self, x: usize, y: usize) -> bool {
+        self.function[y] >> x & 1 != 0
+    }
+
+    fn draw_function_patterns(&mut self) {
+        for i in 0..SIZE {
+            self.set_function(6, i, i % 2 == 0);
+            self.set_function(i, 6, i % 2 == 0);
+        }
+        for (x, y) in [(3, 3), (SIZE - 4, 3), (3, SIZE - 4)] {
+            self.draw_finder_pattern(x, y);
+        }
+        for dy in -2..=2_i32 {
+            for dx in -2..=2_i32 {
+                let (x, y) = (ALIGNMENT as i32 + dx, ALIGNMENT as i32 + dy);
+                self.set_function(x as usize, y as usize, dx.abs().max(dy.abs()) != 1);
+            }
+        }
+        // Reserved, drawn once the data is masked
+        self.draw_format_bits();
+    }
+
+    /// Centered at `x`, `y`, with its separator
+    fn draw_finder_pattern(&mut self, x: usize, y: usize) {
+        for dy in -4..=4_i32 {
+            for dx in -4..=4_i32 {
+                let (xx, yy) = (x as i32 + dx, y as i32 + dy);
+                if (0..SIZE as i32).contains(&xx) && (0..SIZE as i32).contains(&yy) {
+                    let distance = dx.abs().max(dy.abs());
+                    self.set_function(xx as usize, yy as usize, distance != 2 && distance != 4);
+                }
+            }
+        }
+    }
+
+    /// Two copies of the 15 bit format information, and the dark module
+    fn draw_format_bits(&mut self) {
+        // Mask 0
+        let data = ECC_LEVEL_BITS << 3;
+        let mut remainder = data;
+        for _ in 0..10 {
+            remainder = (remainder << 1) ^ ((remainder >> 9) * 0x537);
+        }
+        let bits = (data << 10 | remainder) ^ 0x5412;
+        let bit = |i: usize| bits >> i & 1 != 0;
+        for i in 0..6 {
+            self.set_function(8, i, bit(i));
+        }
+        self.set_function(8, 7, bit(6));
+        self.set_function(8, 8, bit(7));
+        self.set_function(7, 8, bit(8));
+        for i in 9..15 {
+            self.set_function(14 - i, 8, bit(i));
+        }
+        for i in 0..8 {
+            self.set_function(SIZE - 1 - i, 8, bit(i));
+        }
+        for i in 8..15 {
+            self.set_function(8, SIZE - 15 + i, bit(i));
+        }
+        self.set_function(8, SIZE - 8, true);
+    }
+
+    /// Zigzags up and down pairs of columns from the bottom right corner
+    fn draw_codewords(&mut self, codewords: &[u8]) {
+        let mut bit = 0;
+        let mut right = SIZE - 1;
+        loop {
+            // The vertical timing pattern takes a whole column
+            if right == 6 {
+                right = 5;
+            }
+            let upward = (right + 1) & 2 == 0;
+            for vertical in 0..SIZE {
+                let y = if upward {
+                    SIZE - 1 - vertical
+                } else {
+                    vertical
+                };
+                for x in [right, right - 1] {
+                    if !self.is_function(x, y) && bit < codewords.len() * 8 {
+                        let dark = codewords[bit / 8] >> (7 - bit % 8) & 1 != 0;
+                        self.set(x, y, dark);
+                        bit += 1;
+                    }
+                }
+            }
+            if right < 3 {
+                break;
+            }
+            right -= 2;
+        }
+    }
+
+    fn apply_mask(&mut self) {
+        for y in 0..SIZE {
+            for x in 0..SIZE {
+                if (x + y) % 2 == 0 && !self.is_function(x, y) {
+                    self.set(x, y, !self.module(x, y));
+                }
+            }
+        }
+    }
+}
+
+/// Byte mode segment padded to the capacity, followed by its error correction codewords
+fn codewords(data: &[u8]) -> [u8; DATA_CODEWORDS + ECC_CODEWORDS] {
+    let mut codewords = [0; DATA_CODEWORDS + ECC_CODEWORDS];
+    // Mode indicator 0100, the 8 bit length, the data, then the terminator 0000
+    codewords[0] = 0x40 | (data.len() >> 4) as u8;
+    let mut previous = data.len() as u8;
+    for (i, byte) in data.iter().enumerate() {
+        codewords[i + 1] = previous << 4 | byte >> 4;
+        previous = *byte;
+    }
+    codewords[data.len() + 1] = previous << 4;
+    for (i, pad) in codewords[data.len() + 2..DATA_CODEWORDS]
+        .iter_mut()
+        .enumerate()
+    {
+        *pad = if i % 2 == 0 { 0xec } else { 0x11 };
+    }
+    let (data, ecc) = codewords.split_at_mut(DATA_CODEWORDS);
+    reed_solomon_remainder(data, ecc);
+    codewords
+}
+
+/// Remainder of `data` divided by the generator polynomial of degree `ecc.len()`
+fn reed_solomon_remainder(data: &[u8], ecc: &mut [u8]) {
+    let mut divisor = [0; ECC_CODEWORDS];
+    divisor[ECC_CODEWORDS - 1] = 1;
+    let mut root = 1;
+    for _ in 0..ECC_CODEWORDS {
+        for j in 0..ECC_CODEWORDS {
+            divisor[j] = gf_multiply(divisor[j], root);
+            if j + 1 < ECC_CODEWORDS {
+                divisor[j] ^= divisor[j + 1];
+            }
+        }
+        root = gf_multiply(root, 0x02);
+    }
+    ecc.fill(0);
+    for byte in data {
+        let factor = byte ^ ecc[0];
+        ecc.copy_within(1.., 0);
+        ecc[ECC_CODEWORDS - 1] = 0;
+        for (e, d) in ecc.iter_mut().zip(divisor) {
+            *e ^= gf_multiply(d, factor);
+        }
+    }
+}
+
+/// Product in GF(2^8) modulo x^8 + x^4 + x^3 + x^2 + 1
+fn gf_multiply(x: u8, y: u8) -> u8 {
+    let mut z: u8 = 0;
+    for i in (0..8).rev() {
+        z = (z << 1) ^ ((z >> 7) * 0x1d);
+        z ^= ((y >> i) & 1) * x;
+    }
+    z
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn too_long_data_is_rejected() {
+        assert!(QrCode::encode(&[b'a'; CAPACITY]).is_some());
+        assert!(QrCode::encode(&[b'a'; CAPACITY + 1]).is_none());
+    }
+
+    /// Rows generated by the qrcodegen library with the same version, level and mask
+    #[test]
+    fn matches_the_reference_encoder() {
+        let expected = [
+            0x1fc5e7f, 0x1043141, 0x1749e5d, 0x175385d, 0x1752b5d, 0x105d641, 0x1fd557f, 0x0009000,
+            0x0910e55, 0x1ada1b4, 0x1efd1fc, 0x18b7ca7, 0x12cc0ce, 0x10ccb82, 0x1a6256d, 0x0888c22,
+            0x19f14d1, 0x151bd00, 0x1f55c7f, 0x0b17241, 0x0bfc95d, 0x0f3c85d, 0x112a15d, 0x0a08c41,
+            0x1b1157f,
+        ];
+        assert_eq!(QrCode::encode(b"trawm:123456").unwrap().rows, expected);
+    }
+
+    #[test]
+    fn finder_patterns_are_in_the_corners() {
+        let qr = QrCode::encode(b"trawm:123456").unwrap();
+        for (x, y) in [(0, 0), (SIZE - 7, 0), (0, SIZE - 7)] {
+            // Dark ring, light ring, dark center
+            assert!(qr.module(x, y) && qr.module(x + 6, y + 6));
+            assert!(!qr.module(x + 1, y + 1) && qr.module(x + 2, y + 2));
+        }
+    }
+}
@@ -0,0 +1,168 @@
+//! RuuviTag broadcasts in the RAWv2 (data format 5) layout, see
+//! <https://docs.ruuvi.com/communication/bluetooth-advertisements/data-format-5-rawv2>
+use crate::metrics::ParseMetricsError;
+
+/// Bluetooth SIG company identifier of Ruuvi Innovations
+pub const RUUVI_COMPANY_ID: u16 = 0x0499;
+
+const DATA_FORMAT: u8 = 5;
+const PACKET_LEN: usize = 24;
+
+/// Decoded broadcast. Values the tag marks as not available are `None`
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+pub struct RuuviData {
+    /// °C
+    pub temperature: Option<f32>,
+    /// Percent
+    pub humidity: Option<f32>,
+    /// hPa
+    pub pressure: Option<f32>,
+    /// X, Y and Z in G
+    pub acceleration: [Option<f32>; 3],
+    pub battery_mv: Option<u16>,
+    /// dBm
+    pub tx_power: Option<i8>,
+    /// Incremented by the motion interrupts of the accelerometer
+    pub movement_counter: Option<u8>,
+    /// Incremented with each measurement, to tell the repeated broadcasts apart
+    pub sequence: Option<u16>,
+    pub mac: Option<[u8; 6]>,
+}
+
+impl RuuviData {
+    /// Parses the manufacturer specific data following the company identifier
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ParseMetricsError> {
+        match bytes.first() {
+            Some(&DATA_FORMAT) if bytes.len() >= PACKET_LEN => (),
+            Some(&DATA_FORMAT) | None => {
+                return Err(ParseMetricsError::InsufficientBytes { len: bytes.len() })
+            }
+            Some(&version) => return Err(ParseMetricsError::UnsupportedPacketVersion { version }),
+        }
+        let unsigned = |i: usize| u16::from_be_bytes([bytes[i], bytes[i + 1]]);
+        let signed = |i: usize| Some(unsigned(i) as i16).filter(|v| *v != i16::MIN);
+        let available = |i: usize| Some(unsigned(i)).filter(|v| *v != u16::MAX);
+        let acceleration = |i: usize| signed(i).map(|mg| mg as f32 / 1000.0);
+
+        let power = unsigned(13);
+        let voltage = power >> 5;
+        let tx_power = power & 0x1f;
+        let mut mac = [0; 6];
+        mac.copy_from_slice(&bytes[18..24]);
+        Ok(Self {
+            temperature: signed(1).map(|t| t as f32 * 0.005),
+            humidity: available(3).map(|h| h as f32 * 0.0025),
+            pressure: available(5).map(|p| (p as f32 + 50_000.0) / 100.0),
+            acceleration: [acceleration(7), acceleration(9), acceleration(11)],
+            battery_mv: Some(voltage + 1600).filter(|_| voltage != 0x7ff),
+            tx_power: Some(tx_power as i8 * 2 - 40).filter(|_| tx_power != 0x1f),
+            movement_counter: Some(bytes[15]).filter(|m| *m != u8::MAX),
+            sequence: available(16),
+            mac: Some(mac).filter(|m| *m != [0xff; 6]),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(hex: &str) -> RuuviData {
+        let bytes: std::vec::Vec<u8> = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect();
+        RuuviData::from_bytes(&bytes).unwrap()
+    }
+
+    fn assert_near(value: Option<f32>, expected: f32) {
+        let value = value.unwrap();
+        assert!(
+            (value - expected).abs() < 1e-3,
+            "{} is not {}",
+            value,
+            expected
+        );
+    }
+
+    const MAC: [u8; 6] = [0xcb, 0xb8, 0x33, 0x4c, 0x88, 0x4f];
+
+    /// Test vectors of the format documentation
+    #[test]
+    fn valid_data() {
+        let data = decode("0512FC5394C37C0004FFFC040CAC364200CDCBB8334C884F");
+        assert_near(data.temperature, 24.3);
+        assert_near(data.pressure, 1000.44);
+        assert_near(data.humidity, 53.49);
+        assert_near(data.acceleration[0], 0.004);
+        assert_near(data.acceleration[1], -0.004);
+        assert_near(data.acceleration[2], 1.036);
+        assert_eq!(data.battery_mv, Some(2977));
+        assert_eq!(data.tx_power, Some(4));
+        assert_eq!(data.movement_counter, Some(66));
+        assert_eq!(data.sequence, Some(205));
+        assert_eq!(data.mac, Some(MAC));
+    }
+
+    #[test]
+    fn maximum_values() {
+        let data = decode("057FFFFFFEFFFE7FFF7FFF7FFFFFDEFEFFFECBB8334C884F");
+        assert_near(data.temperature, 163.835);
+        assert_near(data.pressure, 1155.34);
+        assert_near(data.humidity, 163.835);
+        for acceleration in data.acceleration {
+            assert_near(acceleration, 32.767);
+        }
+        assert_eq!(data.battery_mv, Some(3646));
+        assert_eq!(data.tx_power, Some(20));
+        assert_eq!(data.movement_counter, Some(254));
+        assert_eq!(data.sequence, Some(65534));
+        assert_eq!(data.mac, Some(MAC));
+    }
+
+    #[test]
+    fn minimum_values() {
+        let data = decode("058001000000008001800180010000000000CBB8334C884F");
+        assert_near(data.temperature, -163.835);
+        assert_near(data.pressure, 500.0);
+        assert_near(data.humidity, 0.0);
+        for acceleration in data.acceleration {
+            assert_near(acceleration, -32.767);
+        }
+        assert_eq!(data.battery_mv, Some(1600));
+        assert_eq!(data.tx_power, Some(-40));
+        assert_eq!(data.movement_counter, Some(0));
+        assert_eq!(data.sequence, Some(0));
+    }
+
+    #[test]
+    fn invalid_values() {
+        let data = decode("058000FFFFFFFF800080008000FFFFFFFFFFFFFFFFFFFFFF");
+        assert_eq!(
+            data,
+            RuuviData {
+                temperature: None,
+                humidity: None,
+                pressure: None,
+                acceleration: [None; 3],
+                battery_mv: None,
+                tx_power: None,
+                movement_counter: None,
+                sequence: None,
+                mac: None,
+            }
+        );
+    }
+
+    #[test]
+    fn other_formats_are_rejected() {
+        assert_eq!(
+            RuuviData::from_bytes(&[3, 0x29, 0x1a]).unwrap_err(),
+            ParseMetricsError::UnsupportedPacketVersion { version: 3 }
+        );
+        assert_eq!(
+            RuuviData::from_bytes(&[5, 0x12]).unwrap_err(),
+            ParseMetricsError::InsufficientBytes { len: 2 }
+        );
+    }
+}
@@ -0,0 +1,224 @@
+//! Every screen drawn with sample readings, rendered by the simulator and compared with the
+//! golden images by the snapshot tests
+use core::convert::Infallible;
+use core::fmt;
+
+use crate::alert::{Alert, AlertRule};
+use crate::comfort::ComfortMetrics;
+use crate::framebuffer::Framebuffer;
+use crate::link::LinkQuality;
+use crate::measurement::{Measurement, MeasurementSet, Quantity};
+use crate::metrics::AirMetrics;
+use crate::pairing::Pin;
+use crate::particulates::PmReading;
+use crate::ruuvi::RuuviData;
+use crate::screens;
+use crate::units::UnitProfile;
+
+const SAMPLE_METRICS: AirMetrics = AirMetrics {
+    version: 1,
+    humidity: 41.5,
+    illuminance: Some(12.0),
+    radon_short: Some(48),
+    radon_long: Some(61),
+    temperature: 22.37,
+    pressure: Some(1003.2),
+    co2_level: Some(812),
+    voc_level: Some(95),
+    battery: None,
+    update_interval: None,
+};
+
+/// Radon, temperature and humidity only
+const SAMPLE_WAVE2_METRICS: AirMetrics = AirMetrics {
+    pressure: None,
+    co2_level: None,
+    voc_level: None,
+    ..SAMPLE_METRICS
+};
+
+/// No radon nor VOC, but the battery level
+const SAMPLE_ARANET4_METRICS: AirMetrics = AirMetrics {
+    illuminance: None,
+    radon_short: None,
+    radon_long: None,
+    voc_level: None,
+    battery: Some(87),
+    update_interval: Some(300),
+    ..SAMPLE_METRICS
+};
+
+/// Temperature, humidity and battery only, as broadcast by a LYWSD03MMC
+const SAMPLE_THERMOMETER_METRICS: AirMetrics = AirMetrics {
+    illuminance: None,
+    radon_short: None,
+    radon_long: None,
+    pressure: None,
+    co2_level: None,
+    voc_level: None,
+    battery: Some(64),
+    ..SAMPLE_METRICS
+};
+
+const SAMPLE_OUTDOOR: RuuviData = RuuviData {
+    temperature: Some(-4.5),
+    humidity: Some(87.3),
+    pressure: Some(1001.8),
+    acceleration: [Some(0.004), Some(-0.004), Some(1.036)],
+    battery_mv: Some(2977),
+    tx_power: Some(4),
+    movement_counter: Some(66),
+    sequence: Some(205),
+    mac: None,
+};
+
+const SAMPLE_LINK: LinkQuality = LinkQuality {
+    adv_rssi: -67,
+    conn_rssi: Some(-64),
+};
+
+const MARGINAL_LINK: LinkQuality = LinkQuality {
+    adv_rssi: -88,
+    conn_rssi: None,
+};
+
+/// CO2 over a working day, in ppm
+const SAMPLE_CO2_HISTORY: [f32; 24] = [
+    612.0, 598.0, 590.0, 604.0, 655.0, 720.0, 810.0, 905.0, 990.0, 1080.0, 1150.0, 1210.0, 1190.0,
+    980.0, 870.0, 940.0, 1020.0, 1110.0, 1240.0, 1320.0, 1180.0, 950.0, 780.0, 690.0,
+];
+
+#[derive(Debug)]
+enum SampleError {
+    TimedOut,
+}
+
+impl fmt::Display for SampleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TimedOut => f.write_str("Timed out"),
+        }
+    }
+}
+
+/// A screen drawn with the sample readings
+pub struct Screen {
+    /// Of the image file, without the extension
+    pub name: &'static str,
+    pub draw: fn(&mut Framebuffer) -> Result<(), Infallible>,
+}
+
+pub const SCREENS: [Screen; 11] = [
+    Screen {
+        name: "dashboard",
+        draw: dashboard,
+    },
+    Screen {
+        name: "dashboard_us",
+        draw: dashboard_us,
+    },
+    Screen {
+        name: "dashboard_wave2",
+        draw: |fb| sensor_dashboard(fb, &SAMPLE_WAVE2_METRICS),
+    },
+    Screen {
+        name: "dashboard_aranet4",
+        draw: |fb| sensor_dashboard(fb, &SAMPLE_ARANET4_METRICS),
+    },
+    Screen {
+        name: "dashboard_lywsd03mmc",
+        draw: |fb| sensor_dashboard(fb, &SAMPLE_THERMOMETER_METRICS),
+    },
+    Screen {
+        name: "alerts",
+        draw: alerts,
+    },
+    Screen {
+        name: "error",
+        draw: |fb| screens::draw_error(fb, &SampleError::TimedOut),
+    },
+    Screen {
+        name: "provisioning",
+        draw: |fb| screens::draw_provisioning(fb, "trawm", &Pin::new(482_913)),
+    },
+    Screen {
+        name: "menu",
+        draw: |fb| {
+            let items = ["Refresh now", "Interval 30 s", "Units metric", "CO2 graph"];
+            screens::draw_menu(fb, "Desk mode", &items, 1)
+        },
+    },
+    Screen {
+        name: "graph",
+        draw: |fb| screens::draw_graph(fb, Quantity::Co2, &SAMPLE_CO2_HISTORY),
+    },
+    Screen {
+        name: "graph_empty",
+        draw: |fb| screens::draw_graph(fb, Quantity::Co2, &[]),
+    },
+];
+
+fn dashboard(fb: &mut Framebuffer) -> Result<(), Infallible> {
+    let particulates = PmReading {
+        pm1: 4.0,
+        pm2_5: 7.0,
+        pm10: 11.0,
+        counts: heapless::Vec::new(),
+    };
+    let comfort = ComfortMetrics::new(&SAMPLE_METRICS, Some(100));
+    let mut measurements = measurements(&SAMPLE_METRICS, &comfort);
+    measurements.merge(&MeasurementSet::from(&particulates));
+    screens::draw_dashboard(
+        fb,
+        &measurements,
+        Some(&comfort),
+        &UnitProfile::METRIC,
+        Some(&SAMPLE_LINK),
+        Some(&MeasurementSet::from(&SAMPLE_OUTDOOR)),
+    )
+}
+
+fn dashboard_us(fb: &mut Framebuffer) -> Result<(), Infallible> {
+    let comfort = ComfortMetrics::new(&SAMPLE_METRICS, Some(100));
+    screens::draw_dashboard(
+        fb,
+        &measurements(&SAMPLE_METRICS, &comfort),
+        Some(&comfort),
+        &UnitProfile::US,
+        Some(&MARGINAL_LINK),
+        None,
+    )
+}
+
+/// The dashboard of the sensors measuring only some of the metrics
+fn sensor_dashboard(fb: &mut Framebuffer, metrics: &AirMetrics) -> Result<(), Infallible> {
+    let comfort = ComfortMetrics::new(metrics, Some(100));
+    screens::draw_dashboard(
+        fb,
+        &measurements(metrics, &comfort),
+        Some(&comfort),
+        &UnitProfile::METRIC,
+        Some(&SAMPLE_LINK),
+        None,
+    )
+}
+
+/// Like in the cycles: the `metrics` with the heat index
+fn measurements(metrics: &AirMetrics, comfort: &ComfortMetrics) -> MeasurementSet {
+    let mut measurements = MeasurementSet::from(metrics);
+    measurements.insert(Measurement::new(Quantity::HeatIndex, comfort.heat_index));
+    measurements
+}
+
+fn alerts(fb: &mut Framebuffer) -> Result<(), Infallible> {
+    let alerts = [
+        ("co2 > 1400 100 600", Quantity::Co2, 1450.0),
+        ("humidity < 30 5", Quantity::Humidity, 27.4),
+    ]
+    .map(|(rule, quantity, value)| Alert {
+        // The rules above are valid
+        rule: AlertRule::parse(rule).unwrap(),
+        measurement: Measurement::new(quantity, value),
+    });
+    screens::draw_alerts(fb, &alerts, &UnitProfile::METRIC)
+}
@@ -0,0 +1,485 @@
+//! Sensirion SCD4x CO2 sensor on the Qw/ST connector, see
+//! <https://sensirion.com/media/documents/48C4B7FB/66E05452/CD_DS_SCD4x_Datasheet_D1.pdf>
+//!
+//! Driven in the single shot mode of the SCD41: the sensor idles between the measurements,
+//! which suits the wake cycle. It loses its power with the badge, so the settings changed here
+//! only last until then unless persisted.
+use core::fmt;
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::mutex::Mutex;
+use embedded_hal_async::delay::DelayNs;
+use embedded_hal_async::i2c::I2c;
+
+use crate::measurement::{Measurement, MeasurementSet, Quantity};
+use crate::platform::MetricsSource;
+
+pub const ADDRESS: u8 = 0x62;
+
+/// Command code and how long it takes, the result can be read after that.
+/// The other Sensirion sensors take the same kind of commands
+#[derive(Clone, Copy)]
+pub(crate) struct Command {
+    pub(crate) code: u16,
+    pub(crate) duration_ms: u32,
+}
+
+const READ_MEASUREMENT: Command = Command::new(0xec05, 1);
+const GET_DATA_READY_STATUS: Command = Command::new(0xe4b8, 1);
+const SET_AUTOMATIC_SELF_CALIBRATION: Command = Command::new(0x2416, 1);
+const GET_AUTOMATIC_SELF_CALIBRATION: Command = Command::new(0x2313, 1);
+const PERFORM_FORCED_RECALIBRATION: Command = Command::new(0x362f, 400);
+const PERSIST_SETTINGS: Command = Command::new(0x3615, 800);
+const GET_SERIAL_NUMBER: Command = Command::new(0x3682, 1);
+const MEASURE_SINGLE_SHOT: Command = Command::new(0x219d, 5000);
+const START_PERIODIC_MEASUREMENT: Command = Command::new(0x21b1, 1);
+const STOP_PERIODIC_MEASUREMENT: Command = Command::new(0x3f86, 500);
+const POWER_DOWN: Command = Command::new(0x36e0, 1);
+const WAKE_UP: Command = Command::new(0x36f6, 30);
+
+impl Command {
+    pub(crate) const fn new(code: u16, duration_ms: u32) -> Self {
+        Self { code, duration_ms }
+    }
+}
+
+/// Returned by the forced recalibration when it failed, e.g. the sensor wasn't running long enough
+const RECALIBRATION_FAILED: u16 = 0xffff;
+
+/// Periodic measurement the forced recalibration needs before it
+const RECALIBRATION_WARM_UP_MS: u32 = 3 * 60 * 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Scd4xError<E> {
+    I2c(E),
+    /// A word read didn't match its CRC
+    Crc,
+    /// The measurement wasn't done in time
+    NotReady,
+    RecalibrationFailed,
+}
+
+impl<E: embedded_hal_async::i2c::Error> fmt::Display for Scd4xError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::I2c(e) => write!(f, "SCD4x I2C error: {}", e.kind()),
+            Self::Crc => f.write_str("SCD4x CRC mismatch"),
+            Self::NotReady => f.write_str("SCD4x measurement not ready"),
+            Self::RecalibrationFailed => f.write_str("SCD4x recalibration failed"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+pub struct Scd4xReading {
+    /// ppm
+    pub co2: u16,
+    /// °C
+    pub temperature: f32,
+    /// Percent
+    pub humidity: f32,
+}
+
+impl From<Scd4xReading> for MeasurementSet {
+    fn from(reading: Scd4xReading) -> Self {
+        let mut set = Self::new();
+        set.insert(Measurement::new(Quantity::Co2, reading.co2.into()));
+        set.insert(Measurement::new(Quantity::Temperature, reading.temperature));
+        set.insert(Measurement::new(Quantity::Humidity, reading.humidity));
+        set
+    }
+}
+
+/// CRC-8 of the Sensirion sensors: polynomial 0x31, initialized to 0xFF
+pub fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0xff, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x31
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+pub struct Scd4x<I, D> {
+    i2c: I,
+    delay: D,
+}
+
+impl<I: I2c, D: DelayNs> Scd4x<I, D> {
+    pub fn new(i2c: I, delay: D) -> Self {
+        Self { i2c, delay }
+    }
+
+    pub fn release(self) -> (I, D) {
+        (self.i2c, self.delay)
+    }
+
+    /// Takes one measurement, about 5 seconds
+    pub async fn measure_single_shot(&mut self) -> Result<Scd4xReading, Scd4xError<I::Error>> {
+        self.write(MEASURE_SINGLE_SHOT, None).await?;
+        let [status] = self.read_words(GET_DATA_READY_STATUS).await?;
+        if status & 0x07ff == 0 {
+            return Err(Scd4xError::NotReady);
+        }
+        let [co2, temperature, humidity] = self.read_words(READ_MEASUREMENT).await?;
+        Ok(Scd4xReading {
+            co2,
+            temperature: -45.0 + 175.0 * temperature as f32 / 65535.0,
+            humidity: 100.0 * humidity as f32 / 65535.0,
+        })
+    }
+
+    /// Measures every 5 seconds until stopped, the other commands are refused meanwhile
+    pub async fn start_periodic_measurement(&mut self) -> Result<(), Scd4xError<I::Error>> {
+        self.write(START_PERIODIC_MEASUREMENT, None).await
+    }
+
+    pub async fn stop_periodic_measurement(&mut self) -> Result<(), Scd4xError<I::Error>> {
+        self.write(STOP_PERIODIC_MEASUREMENT, None).await
+    }
+
+    /// Whether the automatic self calibration is enabled, it is from the factory
+    pub async fn automatic_self_calibration(&mut self) -> Result<bool, Scd4xError<I::Error>> {
+        let [enabled] = self.read_words(GET_AUTOMATIC_SELF_CALIBRATION).await?;
+        Ok(enabled != 0)
+    }
+
+    /// The automatic self calibration assumes the sensor sees fresh air (about 400 ppm) at
+    /// least once a week. Disable it where that's not the case
+    pub async fn set_automatic_self_calibration(
+        &mut self,
+        enabled: bool,
+    ) -> Result<(), Scd4xError<I::Error>> {
+        self.write(SET_AUTOMATIC_SELF_CALIBRATION, Some(enabled as u16))
+            .await
+    }
+
+    /// Calibrates to the `target_ppm` the sensor is known to be in. It has to be idle, after
+    /// measuring in there periodically for 3 minutes, see [`Scd4xSource::recalibrate`].
+    /// Returns the correction applied in ppm
+    pub async fn forced_recalibration(
+        &mut self,
+        target_ppm: u16,
+    ) -> Result<i32, Scd4xError<I::Error>> {
+        self.write(PERFORM_FORCED_RECALIBRATION, Some(target_ppm))
+            .await?;
+        match self.read_reply().await? {
+            [RECALIBRATION_FAILED] => Err(Scd4xError::RecalibrationFailed),
+            [correction] => Ok(correction as i32 - 0x8000),
+        }
+    }
+
+    /// Saves the settings to the EEPROM so they survive the power off. It wears out after
+    /// 2000 writes, so only call it when they changed
+    pub async fn persist_settings(&mut self) -> Result<(), Scd4xError<I::Error>> {
+        self.write(PERSIST_SETTINGS, None).await
+    }
+
+    /// 48 bits
+    pub async fn serial_number(&mut self) -> Result<u64, Scd4xError<I::Error>> {
+        let [high, middle, low] = self.read_words(GET_SERIAL_NUMBER).await?;
+        Ok((high as u64) << 32 | (middle as u64) << 16 | low as u64)
+    }
+
+    /// Sleeps until [`Self::wake_up`]
+    pub async fn power_down(&mut self) -> Result<(), Scd4xError<I::Error>> {
+        self.write(POWER_DOWN, None).await
+    }
+
+    /// The sensor doesn't acknowledge this command, so it never fails
+    pub async fn wake_up(&mut self) {
+        let _ = self.i2c.write(ADDRESS, &WAKE_UP.code.to_be_bytes()).await;
+        self.delay.delay_ms(WAKE_UP.duration_ms).await;
+    }
+
+    /// Sends the command with its argument, then waits for it to be executed
+    async fn write(
+        &mut self,
+        command: Command,
+        argument: Option<u16>,
+    ) -> Result<(), Scd4xError<I::Error>> {
+        let mut bytes = [0; 5];
+        bytes[..2].copy_from_slice(&command.code.to_be_bytes());
+        let len = match argument {
+            Some(argument) => {
+                bytes[2..4].copy_from_slice(&argument.to_be_bytes());
+                bytes[4] = crc8(&bytes[2..4]);
+                5
+            }
+            None => 2,
+        };
+        self.i2c
+            .write(ADDRESS, &bytes[..len])
+            .await
+            .map_err(Scd4xError::I2c)?;
+        self.delay.delay_ms(command.duration_ms).await;
+        Ok(())
+    }
+
+    async fn read_words<const N: usize>(
+        &mut self,
+        command: Command,
+    ) -> Result<[u16; N], Scd4xError<I::Error>> {
+        self.write(command, None).await?;
+        self.read_reply().await
+    }
+
+    /// Words of the reply to the last command, each followed by its CRC
+    async fn read_reply<const N: usize>(&mut self) -> Result<[u16; N], Scd4xError<I::Error>> {
+        let mut bytes = [0; 9];
+        let bytes = &mut bytes[..N * 3];
+        self.i2c
+            .read(ADDRESS, bytes)
+            .await
+            .map_err(Scd4xError::I2c)?;
+        let mut words = [0; N];
+        for (word, chunk) in words.iter_mut().zip(bytes.chunks_exact(3)) {
+            if crc8(&chunk[..2]) != chunk[2] {
+                return Err(Scd4xError::Crc);
+            }
+            *word = u16::from_be_bytes([chunk[0], chunk[1]]);
+        }
+        Ok(words)
+    }
+}
+
+/// [`MetricsSource`] measuring with an SCD41 on the badge itself. The sensor is shared with
+/// the console commands calibrating it
+pub struct Scd4xSource<'a, M: RawMutex, I, D> {
+    sensor: &'a Mutex<M, Scd4x<I, D>>,
+}
+
+impl<'a, M: RawMutex, I: I2c, D: DelayNs> Scd4xSource<'a, M, I, D> {
+    pub fn new(sensor: &'a Mutex<M, Scd4x<I, D>>) -> Self {
+        Self { sensor }
+    }
+
+    /// Applies the automatic self calibration setting, persisting it only when it changed
+    pub async fn configure(
+        &mut self,
+        automatic_self_calibration: bool,
+    ) -> Result<(), Scd4xError<I::Error>> {
+        let mut sensor = self.sensor.lock().await;
+        sensor.wake_up().await;
+        let configured = async {
+            if sensor.automatic_self_calibration().await? != automatic_self_calibration {
+                sensor
+                    .set_automatic_self_calibration(automatic_self_calibration)
+                    .await?;
+                sensor.persist_settings().await?;
+            }
+            Ok(())
+        }
+        .await;
+        sensor.power_down().await?;
+        configured
+    }
+
+    /// Measures periodically for 3 minutes, then runs [`Scd4x::forced_recalibration`]. The
+    /// sensor has to stay in the `target_ppm` air all along
+    pub async fn recalibrate(&mut self, target_ppm: u16) -> Result<i32, Scd4xError<I::Error>> {
+        let mut sensor = self.sensor.lock().await;
+        sensor.wake_up().await;
+        let correction = async {
+            sensor.start_periodic_measurement().await?;
+            sensor.delay.delay_ms(RECALIBRATION_WARM_UP_MS).await;
+            sensor.stop_periodic_measurement().await?;
+            sensor.forced_recalibration(target_ppm).await
+        }
+        .await;
+        sensor.power_down().await?;
+        correction
+    }
+}
+
+impl<M: RawMutex, I: I2c, D: DelayNs> MetricsSource for Scd4xSource<'_, M, I, D> {
+    type Error = Scd4xError<I::Error>;
+
+    /// The first measurement after the sensor is woken up is off, so it's discarded. The sensor
+    /// is powered down even when they failed
+    async fn fetch(&mut self) -> Result<MeasurementSet, Self::Error> {
+        let mut sensor = self.sensor.lock().await;
+        sensor.wake_up().await;
+        let reading = async {
+            sensor.measure_single_shot().await?;
+            sensor.measure_single_shot().await
+        }
+        .await;
+        sensor.power_down().await?;
+        Ok(reading?.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+
+    use super::*;
+    use crate::mock::{I2cTransfer, RecordingDelay, ReplayI2c};
+
+    const WAKE_UP_WRITE: I2cTransfer = write(&[0x36, 0xf6]);
+    const POWER_DOWN_WRITE: I2cTransfer = write(&[0x36, 0xe0]);
+    const START_PERIODIC_WRITE: I2cTransfer = write(&[0x21, 0xb1]);
+    const STOP_PERIODIC_WRITE: I2cTransfer = write(&[0x3f, 0x86]);
+    /// One measurement of the datasheet example: 500 ppm, 25 °C and 37 %
+    const MEASUREMENT: [I2cTransfer; 5] = [
+        write(&[0x21, 0x9d]),
+        write(&[0xe4, 0xb8]),
+        read(&[0x80, 0x06, 0x04]),
+        write(&[0xec, 0x05]),
+        read(&[0x01, 0xf4, 0x33, 0x66, 0x67, 0xa2, 0x5e, 0xb9, 0x3c]),
+    ];
+
+    const fn write(bytes: &'static [u8]) -> I2cTransfer<'static> {
+        I2cTransfer::Write {
+            address: ADDRESS,
+            bytes,
+        }
+    }
+
+    const fn read(bytes: &'static [u8]) -> I2cTransfer<'static> {
+        I2cTransfer::Read {
+            address: ADDRESS,
+            bytes,
+        }
+    }
+
+    #[test]
+    fn crc_of_the_datasheet_example() {
+        assert_eq!(crc8(&[0xbe, 0xef]), 0x92);
+    }
+
+    #[test]
+    fn fetch_takes_a_second_single_shot() {
+        let mut transfers = heapless::Vec::<_, 12>::new();
+        transfers.push(WAKE_UP_WRITE).unwrap();
+        transfers.extend_from_slice(&MEASUREMENT).unwrap();
+        transfers.extend_from_slice(&MEASUREMENT).unwrap();
+        transfers.push(POWER_DOWN_WRITE).unwrap();
+        let sensor = Mutex::<NoopRawMutex, _>::new(Scd4x::new(
+            ReplayI2c::new(&transfers),
+            RecordingDelay::default(),
+        ));
+        let measurements = block_on(Scd4xSource::new(&sensor).fetch()).unwrap();
+        assert_eq!(measurements.value(Quantity::Co2), Some(500.0));
+        let temperature = measurements.value(Quantity::Temperature).unwrap();
+        assert!((temperature - 25.0).abs() < 0.01);
+        let humidity = measurements.value(Quantity::Humidity).unwrap();
+        assert!((humidity - 37.0).abs() < 0.01);
+        let (i2c, delay) = sensor.into_inner().release();
+        assert!(i2c.done());
+        assert!(delay.total.as_millis() >= 10_000);
+    }
+
+    #[test]
+    fn crc_mismatches_are_rejected() {
+        let transfers = [
+            write(&[0x21, 0x9d]),
+            write(&[0xe4, 0xb8]),
+            read(&[0x80, 0x06, 0x04]),
+            write(&[0xec, 0x05]),
+            read(&[0x01, 0xf4, 0x33, 0x66, 0x67, 0xa3, 0x5e, 0xb9, 0x3c]),
+        ];
+        let mut sensor = Scd4x::new(ReplayI2c::new(&transfers), RecordingDelay::default());
+        assert_eq!(block_on(sensor.measure_single_shot()), Err(Scd4xError::Crc));
+    }
+
+    #[test]
+    fn failed_fetch_powers_down() {
+        let transfers = [
+            WAKE_UP_WRITE,
+            write(&[0x21, 0x9d]),
+            write(&[0xe4, 0xb8]),
+            read(&[0x80, 0x00, 0xa2]),
+            POWER_DOWN_WRITE,
+        ];
+        let sensor = Mutex::<NoopRawMutex, _>::new(Scd4x::new(
+            ReplayI2c::new(&transfers),
+            RecordingDelay::default(),
+        ));
+        assert_eq!(
+            block_on(Scd4xSource::new(&sensor).fetch()),
+            Err(Scd4xError::NotReady)
+        );
+        assert!(sensor.into_inner().release().0.done());
+    }
+
+    #[test]
+    fn unfinished_measurements_are_not_read() {
+        let transfers = [
+            write(&[0x21, 0x9d]),
+            write(&[0xe4, 0xb8]),
+            read(&[0x80, 0x00, 0xa2]),
+        ];
+        let mut sensor = Scd4x::new(ReplayI2c::new(&transfers), RecordingDelay::default());
+        assert_eq!(
+            block_on(sensor.measure_single_shot()),
+            Err(Scd4xError::NotReady)
+        );
+        assert!(sensor.release().0.done());
+    }
+
+    #[test]
+    fn recalibration_reports_the_correction() {
+        let transfers = [
+            WAKE_UP_WRITE,
+            START_PERIODIC_WRITE,
+            STOP_PERIODIC_WRITE,
+            write(&[0x36, 0x2f, 0x01, 0x90, 0x4c]),
+            read(&[0x80, 0x14, 0x25]),
+            POWER_DOWN_WRITE,
+        ];
+        let sensor = Mutex::<NoopRawMutex, _>::new(Scd4x::new(
+            ReplayI2c::new(&transfers),
+            RecordingDelay::default(),
+        ));
+        assert_eq!(block_on(Scd4xSource::new(&sensor).recalibrate(400)), Ok(20));
+        let (i2c, delay) = sensor.into_inner().release();
+        assert!(i2c.done());
+        // Measuring for 3 minutes, then stopped for the 500 ms it takes
+        assert!(delay.total.as_millis() >= 180_500);
+    }
+
+    /// The sensor is powered down even when the recalibration failed
+    #[test]
+    fn failed_recalibration() {
+        let transfers = [
+            WAKE_UP_WRITE,
+            START_PERIODIC_WRITE,
+            STOP_PERIODIC_WRITE,
+            write(&[0x36, 0x2f, 0x01, 0x90, 0x4c]),
+            read(&[0xff, 0xff, 0xac]),
+            POWER_DOWN_WRITE,
+        ];
+        let sensor = Mutex::<NoopRawMutex, _>::new(Scd4x::new(
+            ReplayI2c::new(&transfers),
+            RecordingDelay::default(),
+        ));
+        assert_eq!(
+            block_on(Scd4xSource::new(&sensor).recalibrate(400)),
+            Err(Scd4xError::RecalibrationFailed)
+        );
+        assert!(sensor.into_inner().release().0.done());
+    }
+
+    #[test]
+    fn self_calibration_is_persisted_when_changed() {
+        let transfers = [
+            WAKE_UP_WRITE,
+            write(&[0x23, 0x13]),
+            read(&[0x00, 0x01, 0xb0]),
+            write(&[0x24, 0x16, 0x00, 0x00, 0x81]),
+            write(&[0x36, 0x15]),
+            POWER_DOWN_WRITE,
+        ];
+        let sensor = Mutex::<NoopRawMutex, _>::new(Scd4x::new(
+            ReplayI2c::new(&transfers),
+            RecordingDelay::default(),
+        ));
+        block_on(Scd4xSource::new(&sensor).configure(false)).unwrap();
+        assert!(sensor.into_inner().release().0.done());
+    }
+}
@@ -0,0 +1,398 @@
+use core::fmt::{self, Write};
+use embedded_graphics::{
+    mono_font::{ascii::*, MonoFont, MonoTextStyle},
+    pixelcolor::BinaryColor,
+    prelude::*,
+    primitives::{Line, PrimitiveStyle, Rectangle},
+    text::{Alignment, Baseline, Text, TextStyleBuilder},
+};
+use heapless::String;
+
+use crate::alert::Alert;
+use crate::comfort::ComfortMetrics;
+use crate::link::LinkQuality;
+use crate::measurement::{MeasurementSet, Quantity};
+use crate::pairing::Pin;
+use crate::qr::{self, QrCode};
+use crate::units::UnitProfile;
+
+/// Badger 2040 W e-ink panel size in landscape orientation
+pub const WIDTH: u32 = 296;
+pub const HEIGHT: u32 = 128;
+
+// Note we're using `Off` as the text color. The UC8151 driver treats Off as Black so that BMPs work as expected.
+const FOREGROUND: BinaryColor = BinaryColor::Off;
+const BACKGROUND: BinaryColor = BinaryColor::On;
+
+/// Of the full screen texts
+const TEXT_FONT: MonoFont = FONT_9X18_BOLD;
+/// Characters of [`TEXT_FONT`] fitting on a line
+const COLUMNS: usize = (WIDTH / TEXT_FONT.character_size.width) as usize;
+
+/// Main screen with the latest measurements, the signal strength in the top right corner,
+/// the outdoor conditions below it and the particulate matter under them
+pub fn draw_dashboard<D>(
+    target: &mut D,
+    measurements: &MeasurementSet,
+    comfort: Option<&ComfortMetrics>,
+    units: &UnitProfile,
+    link: Option<&LinkQuality>,
+    outdoor: Option<&MeasurementSet>,
+) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    let mut text: String<256> = String::new();
+    let display = measurements.display_with(units);
+    let _ = match comfort {
+        Some(comfort) => write!(text, "{}", display.with_comfort(comfort)),
+        None => write!(text, "{}", display),
+    };
+    draw_text(target, &text)?;
+    if let Some(link) = link {
+        draw_signal(target, link)?;
+    }
+    if let Some(outdoor) = outdoor {
+        draw_outdoor(target, outdoor, units)?;
+    }
+    draw_particulates(target, measurements)
+}
+
+/// Screen shown when the metrics couldn't be fetched
+pub fn draw_error<D, E>(target: &mut D, error: &E) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+    E: fmt::Display,
+{
+    let mut text: String<256> = String::new();
+    let _ = write!(text, "An error occurred:\n{}", error);
+    draw_text(target, &text)
+}
+
+/// Screen shown instead of the dashboard while alert rules fire, one line per alert
+/// such as "CO2 1450 ppm - ventilate"
+pub fn draw_alerts<D>(target: &mut D, alerts: &[Alert], units: &UnitProfile) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    let mut text: String<256> = String::new();
+    for alert in alerts {
+        let _ = writeln!(
+            text,
+            "{} {} - {}",
+            alert.rule.quantity.label(),
+            alert.measurement.display_with(units),
+            alert.rule.advice()
+        );
+    }
+    draw_text(target, &text)
+}
+
+/// Instructions shown while the provisioning GATT server is running, with the `pin` unlocking
+/// it in digits and in a QR code of `<device name>:<pin>` on the right
+pub fn draw_provisioning<D>(target: &mut D, device_name: &str, pin: &Pin) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    let mut text: String<256> = String::new();
+    let _ = write!(
+        text,
+        "Provisioning mode\n\
+        Connect to \"{}\"\n\
+        with a BLE app,\n\
+        write PIN {}\n\
+        then the settings.\n\
+        Reset when done",
+        device_name, pin
+    );
+    draw_text(target, &text)?;
+    let mut payload: String<{ qr::CAPACITY }> = String::new();
+    if write!(payload, "{}:{}", device_name, pin).is_ok() {
+        // Always fits, the device name is short
+        if let Some(code) = QrCode::encode(payload.as_bytes()) {
+            draw_qr(target, &code)?;
+        }
+    }
+    Ok(())
+}
+
+/// Right aligned and vertically centered, each module a square of 4 pixels. The panel is
+/// white around it, which is its quiet zone
+fn draw_qr<D>(target: &mut D, code: &QrCode) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    const QR_SCALE: u32 = 4;
+    let side = qr::SIZE as u32 * QR_SCALE;
+    let left = (WIDTH - side - 2 * QR_SCALE) as i32;
+    let top = ((HEIGHT - side) / 2) as i32;
+    let style = PrimitiveStyle::with_fill(FOREGROUND);
+    for y in 0..qr::SIZE {
+        for x in (0..qr::SIZE).filter(|x| code.module(*x, y)) {
+            let corner = Point::new(x as i32, y as i32) * QR_SCALE as i32;
+            Rectangle::new(Point::new(left, top) + corner, Size::new_equal(QR_SCALE))
+                .into_styled(style)
+                .draw(target)?;
+        }
+    }
+    Ok(())
+}
+
+/// List of `items` under the `title`, the `selected` one inverted. Scrolls to keep it in view
+pub fn draw_menu<D>(
+    target: &mut D,
+    title: &str,
+    items: &[&str],
+    selected: usize,
+) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    let line_height = TEXT_FONT.character_size.height;
+    // The title takes the first line
+    let visible = (HEIGHT / line_height - 1) as usize;
+    let first = (selected + 1).saturating_sub(visible);
+    target.clear(BACKGROUND)?;
+    let title_style = MonoTextStyle::new(&TEXT_FONT, FOREGROUND);
+    Text::with_baseline(title, Point::zero(), title_style, Baseline::Top).draw(target)?;
+    let underline = line_height as i32 - 2;
+    Line::new(
+        Point::new(0, underline),
+        Point::new(WIDTH as i32 - 1, underline),
+    )
+    .into_styled(PrimitiveStyle::with_stroke(FOREGROUND, 1))
+    .draw(target)?;
+    for (index, item) in items.iter().enumerate().skip(first).take(visible) {
+        let top = ((index - first + 1) as u32 * line_height) as i32;
+        let (text_color, fill) = if index == selected {
+            (BACKGROUND, FOREGROUND)
+        } else {
+            (FOREGROUND, BACKGROUND)
+        };
+        Rectangle::new(Point::new(0, top), Size::new(WIDTH, line_height))
+            .into_styled(PrimitiveStyle::with_fill(fill))
+            .draw(target)?;
+        let style = MonoTextStyle::new(&TEXT_FONT, text_color);
+        Text::with_baseline(item, Point::new(4, top), style, Baseline::Top).draw(target)?;
+    }
+    Ok(())
+}
+
+/// Line chart of the `values` of the `quantity` in its base unit, oldest first, scaled to their
+/// range with the bounds labelled on the left
+pub fn draw_graph<D>(target: &mut D, quantity: Quantity, values: &[f32]) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    const LABEL_WIDTH: i32 = 42;
+    const TOP: i32 = 20;
+    const BOTTOM: i32 = HEIGHT as i32 - 2;
+    target.clear(BACKGROUND)?;
+    let mut title: String<32> = String::new();
+    let _ = write!(title, "{}, {}", quantity.label(), quantity.unit().symbol());
+    let title_style = MonoTextStyle::new(&TEXT_FONT, FOREGROUND);
+    Text::with_baseline(&title, Point::zero(), title_style, Baseline::Top).draw(target)?;
+
+    let axis = PrimitiveStyle::with_stroke(FOREGROUND, 1);
+    Line::new(
+        Point::new(LABEL_WIDTH, TOP),
+        Point::new(LABEL_WIDTH, BOTTOM),
+    )
+    .into_styled(axis)
+    .draw(target)?;
+    let finite = || values.iter().copied().filter(|v| v.is_finite());
+    let (Some(min), Some(max)) = (finite().reduce(f32::min), finite().reduce(f32::max)) else {
+        return draw_side_line(target, "no readings", TOP);
+    };
+    let label_style = MonoTextStyle::new(&FONT_6X10, FOREGROUND);
+    let precision = quantity.precision();
+    for (value, y, baseline) in [(max, TOP, Baseline::Top), (min, BOTTOM, Baseline::Bottom)] {
+        let mut label: String<16> = String::new();
+        let _ = write!(label, "{:.1$}", value, precision);
+        Text::with_baseline(&label, Point::new(0, y), label_style, baseline).draw(target)?;
+    }
+    // A flat line is drawn in the middle
+    let range = if max > min { max - min } else { 2.0 };
+    let low = if max > min { min } else { min - 1.0 };
+    let left = LABEL_WIDTH + 2;
+    let width = WIDTH as i32 - 1 - left;
+    let steps = (values.len() as i32 - 1).max(1);
+    let point = |index: usize, value: f32| {
+        let x = left + index as i32 * width / steps;
+        let y = BOTTOM - ((value - low) / range * (BOTTOM - TOP) as f32) as i32;
+        Point::new(x, y)
+    };
+    let mut previous: Option<Point> = None;
+    for (index, value) in values.iter().enumerate() {
+        if !value.is_finite() {
+            previous = None;
+            continue;
+        }
+        let current = point(index, *value);
+        Line::new(previous.unwrap_or(current), current)
+            .into_styled(axis)
+            .draw(target)?;
+        previous = Some(current);
+    }
+    Ok(())
+}
+
+/// Bars growing to the right, filled up to [`LinkQuality::bars`], with a `!` when marginal.
+/// Fits next to the first line of the dashboard
+fn draw_signal<D>(target: &mut D, link: &LinkQuality) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    const BAR_WIDTH: u32 = 3;
+    const BAR_STEP: u32 = 4;
+    const BAR_MAX_HEIGHT: u32 = 12;
+    let left = (WIDTH - BAR_STEP * LinkQuality::MAX_BARS as u32) as i32;
+    let bottom = 2 + BAR_MAX_HEIGHT as i32;
+    for bar in 0..LinkQuality::MAX_BARS as u32 {
+        let height = BAR_MAX_HEIGHT * (bar + 1) / LinkQuality::MAX_BARS as u32;
+        let style = if (bar as u8) < link.bars() {
+            PrimitiveStyle::with_fill(FOREGROUND)
+        } else {
+            PrimitiveStyle::with_stroke(FOREGROUND, 1)
+        };
+        Rectangle::new(
+            Point::new(left + (bar * BAR_STEP) as i32, bottom - height as i32),
+            Size::new(BAR_WIDTH, height),
+        )
+        .into_styled(style)
+        .draw(target)?;
+    }
+    if link.is_marginal() {
+        let style = MonoTextStyle::new(&FONT_9X18_BOLD, FOREGROUND);
+        Text::with_baseline("!", Point::new(left - 10, 0), style, Baseline::Top).draw(target)?;
+    }
+    Ok(())
+}
+
+/// Small right aligned line next to the temperature, the longest dashboard lines are further down
+fn draw_outdoor<D>(
+    target: &mut D,
+    outdoor: &MeasurementSet,
+    units: &UnitProfile,
+) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    let mut text: String<32> = String::new();
+    let _ = text.push_str("Out");
+    if let Some(temperature) = outdoor.value(Quantity::Temperature) {
+        let _ = write!(text, " {}", units.temperature(temperature));
+    }
+    if let Some(humidity) = outdoor.value(Quantity::Humidity) {
+        let _ = write!(text, " {:.0}%", humidity);
+    }
+    draw_side_line(target, &text, 22)
+}
+
+/// PM2.5 and PM10 in µg/m3 when measured, two small lines below the outdoor conditions
+fn draw_particulates<D>(target: &mut D, measurements: &MeasurementSet) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    for (quantity, y) in [(Quantity::Pm2_5, 34), (Quantity::Pm10, 44)] {
+        if let Some(value) = measurements.value(quantity) {
+            let mut text: String<16> = String::new();
+            let _ = write!(text, "{} {:.0}", quantity.label(), value);
+            draw_side_line(target, &text, y)?;
+        }
+    }
+    Ok(())
+}
+
+/// Small line right aligned at `y`
+fn draw_side_line<D>(target: &mut D, text: &str, y: i32) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    let character_style = MonoTextStyle::new(&FONT_6X10, FOREGROUND);
+    let text_style = TextStyleBuilder::new()
+        .alignment(Alignment::Right)
+        .baseline(Baseline::Top)
+        .build();
+    Text::with_text_style(
+        text,
+        Point::new(WIDTH as i32 - 1, y),
+        character_style,
+        text_style,
+    )
+    .draw(target)?;
+    Ok(())
+}
+
+/// Lines of [`TEXT_FONT`] from the top left corner, wrapped to the screen width
+fn draw_text<D>(target: &mut D, text: &str) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    target.clear(BACKGROUND)?;
+    let character_style = MonoTextStyle::new(&TEXT_FONT, FOREGROUND);
+    let mut wrapped: String<320> = String::new();
+    wrap(text, COLUMNS, &mut wrapped);
+    Text::with_baseline(&wrapped, Point::zero(), character_style, Baseline::Top).draw(target)?;
+    Ok(())
+}
+
+/// Breaks the lines longer than `columns` characters between the words, or inside the words
+/// that don't fit on a line of their own. The font is monospaced, so that's what fits
+fn wrap<const N: usize>(text: &str, columns: usize, out: &mut String<N>) {
+    for (index, line) in text.split('\n').enumerate() {
+        if index > 0 {
+            let _ = out.push('\n');
+        }
+        let mut column = 0;
+        for word in line.split(' ') {
+            if column > 0 && column + 1 + word.chars().count() > columns {
+                let _ = out.push('\n');
+                column = 0;
+            } else if column > 0 {
+                let _ = out.push(' ');
+                column += 1;
+            }
+            for c in word.chars() {
+                if column == columns {
+                    let _ = out.push('\n');
+                    column = 0;
+                }
+                let _ = out.push(c);
+                column += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wrapped(text: &str, columns: usize) -> String<64> {
+        let mut out = String::new();
+        wrap(text, columns, &mut out);
+        out
+    }
+
+    #[test]
+    fn wrap_keeps_short_lines() {
+        assert_eq!(
+            wrapped("Humidity: 41.5%\nCO2: 812", 20),
+            "Humidity: 41.5%\nCO2: 812"
+        );
+    }
+
+    #[test]
+    fn wrap_breaks_between_words() {
+        assert_eq!(
+            wrapped("Connection problem at link", 12),
+            "Connection\nproblem at\nlink"
+        );
+    }
+
+    #[test]
+    fn wrap_splits_long_words() {
+        assert_eq!(wrapped("0123456789abc", 5), "01234\n56789\nabc");
+    }
+}
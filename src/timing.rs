@@ -0,0 +1,279 @@
+//! Where the awake time goes, and what it costs in battery charge
+use core::fmt;
+use core::time::Duration;
+
+use crate::platform::{Clock, Log, Storage};
+
+/// Parts of a wake cycle that are timed separately
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Phase {
+    DisplayInit,
+    /// Loading the cyw43 firmware and starting the host stack
+    RadioInit,
+    /// Until the Airthings device was found
+    Scan,
+    Connect,
+    /// GATT service and characteristic discovery
+    Discovery,
+    Read,
+    DisplayUpdate,
+}
+
+impl Phase {
+    pub const COUNT: usize = 7;
+    pub const ALL: [Self; Self::COUNT] = [
+        Self::DisplayInit,
+        Self::RadioInit,
+        Self::Scan,
+        Self::Connect,
+        Self::Discovery,
+        Self::Read,
+        Self::DisplayUpdate,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::DisplayInit => "display_init",
+            Self::RadioInit => "radio_init",
+            Self::Scan => "scan",
+            Self::Connect => "connect",
+            Self::Discovery => "discovery",
+            Self::Read => "read",
+            Self::DisplayUpdate => "display_update",
+        }
+    }
+
+    fn uses_radio(self) -> bool {
+        matches!(
+            self,
+            Self::RadioInit | Self::Scan | Self::Connect | Self::Discovery | Self::Read
+        )
+    }
+
+    fn uses_display(self) -> bool {
+        matches!(self, Self::DisplayInit | Self::DisplayUpdate)
+    }
+}
+
+/// Durations of the phases of one cycle in milliseconds. Phases that didn't run are 0
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, defmt::Format)]
+pub struct PhaseTimings {
+    phases: [u32; Phase::COUNT],
+    /// From the boot until the power was cut, including the untimed parts
+    pub awake_ms: u32,
+}
+
+impl PhaseTimings {
+    pub const PACKET_LEN: usize = (Phase::COUNT + 1) * 4;
+
+    pub fn get(&self, phase: Phase) -> u32 {
+        self.phases[phase as usize]
+    }
+
+    pub fn set(&mut self, phase: Phase, duration: Duration) {
+        self.phases[phase as usize] = duration.as_millis().try_into().unwrap_or(u32::MAX);
+    }
+
+    /// Copies the phases that ran in `other`
+    pub fn merge(&mut self, other: &Self) {
+        for (phase, ms) in self.phases.iter_mut().zip(other.phases) {
+            if ms != 0 {
+                *phase = ms;
+            }
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; Self::PACKET_LEN] {
+        let mut bytes = [0; Self::PACKET_LEN];
+        let values = self.phases.iter().chain([&self.awake_ms]);
+        for (chunk, value) in bytes.chunks_exact_mut(4).zip(values) {
+            chunk.copy_from_slice(&value.to_le_bytes());
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; Self::PACKET_LEN]) -> Self {
+        let mut values = bytes
+            .chunks_exact(4)
+            .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]));
+        let mut timings = Self::default();
+        for phase in timings.phases.iter_mut() {
+            *phase = values.next().unwrap_or(0);
+        }
+        timings.awake_ms = values.next().unwrap_or(0);
+        timings
+    }
+}
+
+/// Measures phases with a [`Clock`]
+pub struct Stopwatch<C: Clock> {
+    clock: C,
+    pub timings: PhaseTimings,
+}
+
+impl<C: Clock> Stopwatch<C> {
+    pub fn new(clock: C) -> Self {
+        Self {
+            clock,
+            timings: PhaseTimings::default(),
+        }
+    }
+
+    pub fn now(&self) -> Duration {
+        self.clock.now()
+    }
+
+    /// Records the phase as running from `started` until now
+    pub fn stop(&mut self, phase: Phase, started: Duration) {
+        let elapsed = self.clock.now().saturating_sub(started);
+        self.timings.set(phase, elapsed);
+    }
+}
+
+/// Current drawn by the parts of the Badger, see [`CurrentProfile::estimate`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct CurrentProfile {
+    /// Whenever awake
+    pub mcu_ma: u16,
+    /// On top of the MCU while the radio is in use
+    pub radio_ma: u16,
+    /// On top of the MCU while the e-ink panel is driven
+    pub display_ma: u16,
+    /// With the power cut, only the RTC running
+    pub sleep_ua: u16,
+}
+
+impl Default for CurrentProfile {
+    /// Rough figures for the Badger 2040 W on a 3.7 V battery
+    fn default() -> Self {
+        Self {
+            mcu_ma: 25,
+            radio_ma: 45,
+            display_ma: 6,
+            sleep_ua: 20,
+        }
+    }
+}
+
+impl CurrentProfile {
+    /// Charge used per day in mAh if every cycle took as long as `timings` and slept for `interval`
+    pub fn estimate(&self, timings: &PhaseTimings, interval: Duration) -> f32 {
+        let timed: u32 = timings.phases.iter().sum();
+        let awake_ms = timings.awake_ms.max(timed) as f32;
+        let mut ma_ms = awake_ms * self.mcu_ma as f32;
+        for phase in Phase::ALL {
+            let ms = timings.get(phase) as f32;
+            if phase.uses_radio() {
+                ma_ms += ms * self.radio_ma as f32;
+            } else if phase.uses_display() {
+                ma_ms += ms * self.display_ma as f32;
+            }
+        }
+        let sleep_ms = interval.as_millis() as f32;
+        ma_ms += sleep_ms * self.sleep_ua as f32 / 1000.0;
+        let cycles_per_day = 86_400_000.0 / (awake_ms + sleep_ms);
+        ma_ms * cycles_per_day / 3_600_000.0
+    }
+}
+
+pub async fn record<S: Storage>(storage: &mut S, timings: &PhaseTimings) -> Result<(), S::Error> {
+    storage.append(Log::Timings, &timings.to_bytes()).await
+}
+
+/// Reads the `index`-th timings counting from the oldest ones
+pub async fn read<S: Storage>(
+    storage: &mut S,
+    index: usize,
+) -> Result<Option<PhaseTimings>, S::Error> {
+    let mut buf = [0; PhaseTimings::PACKET_LEN];
+    if !storage.read_record(Log::Timings, index, &mut buf).await? {
+        return Ok(None);
+    }
+    Ok(Some(PhaseTimings::from_bytes(&buf)))
+}
+
+pub fn write_csv_header<W: fmt::Write>(out: &mut W) -> fmt::Result {
+    out.write_str("index")?;
+    for phase in Phase::ALL {
+        write!(out, ",{}_ms", phase.name())?;
+    }
+    out.write_str(",awake_ms,mah_per_day\r\n")
+}
+
+pub fn write_csv_row<W: fmt::Write>(
+    out: &mut W,
+    index: usize,
+    timings: &PhaseTimings,
+    mah_per_day: f32,
+) -> fmt::Result {
+    write!(out, "{}", index)?;
+    for phase in Phase::ALL {
+        write!(out, ",{}", timings.get(phase))?;
+    }
+    write!(out, ",{},{:.2}\r\n", timings.awake_ms, mah_per_day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::SteppingClock;
+
+    fn assert_near(value: f32, expected: f32) {
+        assert!(
+            (value - expected).abs() < 1e-3,
+            "{} is not {}",
+            value,
+            expected
+        );
+    }
+
+    #[test]
+    fn stopwatch_times_from_the_start() {
+        let mut stopwatch = Stopwatch::new(SteppingClock::new(Duration::from_millis(10)));
+        let started = stopwatch.now();
+        stopwatch.stop(Phase::Scan, started);
+        let started = stopwatch.now();
+        stopwatch.now();
+        stopwatch.stop(Phase::Read, started);
+        assert_eq!(stopwatch.timings.get(Phase::Scan), 10);
+        assert_eq!(stopwatch.timings.get(Phase::Read), 20);
+        assert_eq!(stopwatch.timings.get(Phase::Connect), 0);
+    }
+
+    #[test]
+    fn merge_keeps_the_phases_that_did_not_run() {
+        let mut timings = PhaseTimings::default();
+        timings.set(Phase::Scan, Duration::from_millis(300));
+        timings.set(Phase::Read, Duration::from_millis(50));
+        let mut other = PhaseTimings::default();
+        other.set(Phase::Read, Duration::from_millis(80));
+        timings.merge(&other);
+        assert_eq!(timings.get(Phase::Scan), 300);
+        assert_eq!(timings.get(Phase::Read), 80);
+    }
+
+    #[test]
+    fn sleeping_costs_the_sleep_current() {
+        let estimate =
+            CurrentProfile::default().estimate(&PhaseTimings::default(), Duration::from_secs(3600));
+        // 20 µA for 24 h
+        assert_near(estimate, 0.48);
+    }
+
+    #[test]
+    fn awake_phases_add_their_currents() {
+        let mut timings = PhaseTimings::default();
+        timings.set(Phase::Read, Duration::from_secs(1));
+        timings.set(Phase::DisplayUpdate, Duration::from_secs(2));
+        timings.awake_ms = 4000;
+        let estimate = CurrentProfile::default().estimate(&timings, Duration::from_secs(56));
+        // 1440 cycles of 4 s at 25 mA, 1 s at 45 mA more, 2 s at 6 mA more and 56 s at 20 µA
+        let per_cycle = 4.0 * 25.0 + 45.0 + 2.0 * 6.0 + 56.0 * 0.02;
+        assert_near(estimate, per_cycle * 1440.0 / 3600.0);
+        // The timed phases when the awake time wasn't recorded
+        timings.awake_ms = 0;
+        let shorter = CurrentProfile::default().estimate(&timings, Duration::from_secs(57));
+        let per_cycle = 3.0 * 25.0 + 45.0 + 2.0 * 6.0 + 57.0 * 0.02;
+        assert_near(shorter, per_cycle * 1440.0 / 3600.0);
+    }
+}
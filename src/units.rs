@@ -0,0 +1,212 @@
+//! Units the metrics can be shown in. Metrics are always kept in °C, hPa and Bq/m3
+//!
+//! Unit discriminants are stored in the config, so don't reorder the variants.
+use core::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum TemperatureUnit {
+    Celsius,
+    Fahrenheit,
+}
+
+impl TemperatureUnit {
+    pub fn from_celsius(self, celsius: f32) -> f32 {
+        match self {
+            Self::Celsius => celsius,
+            Self::Fahrenheit => celsius * 9.0 / 5.0 + 32.0,
+        }
+    }
+
+    pub fn symbol(self) -> &'static str {
+        match self {
+            Self::Celsius => "C",
+            Self::Fahrenheit => "F",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum PressureUnit {
+    Hectopascal,
+    Kilopascal,
+    InchOfMercury,
+    MillimeterOfMercury,
+}
+
+impl PressureUnit {
+    pub fn from_hpa(self, hpa: f32) -> f32 {
+        match self {
+            Self::Hectopascal => hpa,
+            Self::Kilopascal => hpa / 10.0,
+            Self::InchOfMercury => hpa * 0.029_53,
+            Self::MillimeterOfMercury => hpa * 0.750_062,
+        }
+    }
+
+    pub fn symbol(self) -> &'static str {
+        match self {
+            Self::Hectopascal => "hPa",
+            Self::Kilopascal => "kPa",
+            Self::InchOfMercury => "inHg",
+            Self::MillimeterOfMercury => "mmHg",
+        }
+    }
+
+    /// Decimal places worth showing
+    pub fn precision(self) -> usize {
+        match self {
+            Self::Hectopascal | Self::MillimeterOfMercury => 1,
+            Self::Kilopascal | Self::InchOfMercury => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum RadonUnit {
+    BecquerelPerCubicMeter,
+    PicocuriePerLiter,
+}
+
+impl RadonUnit {
+    pub fn from_bq_m3(self, bq_m3: f32) -> f32 {
+        match self {
+            Self::BecquerelPerCubicMeter => bq_m3,
+            // 1 pCi/L = 37 Bq/m3
+            Self::PicocuriePerLiter => bq_m3 / 37.0,
+        }
+    }
+
+    pub fn symbol(self) -> &'static str {
+        match self {
+            Self::BecquerelPerCubicMeter => "Bq/m3",
+            Self::PicocuriePerLiter => "pCi/L",
+        }
+    }
+
+    pub fn precision(self) -> usize {
+        match self {
+            Self::BecquerelPerCubicMeter => 0,
+            Self::PicocuriePerLiter => 1,
+        }
+    }
+}
+
+/// Units used to show the metrics. Any combination can be configured,
+/// [`UnitProfile::METRIC`] and [`UnitProfile::US`] are the common ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct UnitProfile {
+    pub temperature: TemperatureUnit,
+    pub pressure: PressureUnit,
+    pub radon: RadonUnit,
+}
+
+impl UnitProfile {
+    pub const METRIC: Self = Self {
+        temperature: TemperatureUnit::Celsius,
+        pressure: PressureUnit::Hectopascal,
+        radon: RadonUnit::BecquerelPerCubicMeter,
+    };
+    pub const US: Self = Self {
+        temperature: TemperatureUnit::Fahrenheit,
+        pressure: PressureUnit::InchOfMercury,
+        radon: RadonUnit::PicocuriePerLiter,
+    };
+
+    /// Temperature in °C formatted with the unit, e.g. "72.3 F"
+    pub fn temperature(&self, celsius: f32) -> impl fmt::Display {
+        Formatted {
+            value: self.temperature.from_celsius(celsius),
+            precision: 1,
+            symbol: self.temperature.symbol(),
+        }
+    }
+
+    /// Pressure in hPa formatted with the unit
+    pub fn pressure(&self, hpa: f32) -> impl fmt::Display {
+        Formatted {
+            value: self.pressure.from_hpa(hpa),
+            precision: self.pressure.precision(),
+            symbol: self.pressure.symbol(),
+        }
+    }
+
+    /// Radon concentration in Bq/m3 formatted with the unit
+    pub fn radon(&self, bq_m3: u16) -> impl fmt::Display {
+        Formatted {
+            value: self.radon.from_bq_m3(bq_m3 as f32),
+            precision: self.radon.precision(),
+            symbol: self.radon.symbol(),
+        }
+    }
+}
+
+impl Default for UnitProfile {
+    fn default() -> Self {
+        Self::METRIC
+    }
+}
+
+struct Formatted {
+    value: f32,
+    precision: usize,
+    symbol: &'static str,
+}
+
+impl fmt::Display for Formatted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{0:.1$} {2}", self.value, self.precision, self.symbol)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::fmt::Write;
+    use heapless::String;
+
+    use super::*;
+
+    fn formatted(value: impl fmt::Display) -> String<16> {
+        let mut out = String::new();
+        write!(out, "{}", value).unwrap();
+        out
+    }
+
+    #[test]
+    fn temperature_conversions() {
+        assert_eq!(TemperatureUnit::Fahrenheit.from_celsius(-40.0), -40.0);
+        assert_eq!(TemperatureUnit::Fahrenheit.from_celsius(100.0), 212.0);
+        assert_eq!(formatted(UnitProfile::US.temperature(22.4)), "72.3 F");
+        assert_eq!(formatted(UnitProfile::METRIC.temperature(22.37)), "22.4 C");
+    }
+
+    #[test]
+    fn pressure_conversions() {
+        let units = |pressure| UnitProfile {
+            pressure,
+            ..UnitProfile::METRIC
+        };
+        let hpa = 1000.0;
+        assert_eq!(
+            formatted(units(PressureUnit::Hectopascal).pressure(hpa)),
+            "1000.0 hPa"
+        );
+        assert_eq!(
+            formatted(units(PressureUnit::Kilopascal).pressure(hpa)),
+            "100.00 kPa"
+        );
+        assert_eq!(
+            formatted(units(PressureUnit::InchOfMercury).pressure(hpa)),
+            "29.53 inHg"
+        );
+        assert_eq!(
+            formatted(units(PressureUnit::MillimeterOfMercury).pressure(hpa)),
+            "750.1 mmHg"
+        );
+    }
+
+    #[test]
+    fn radon_conversions() {
+        assert_eq!(formatted(UnitProfile::US.radon(148)), "4.0 pCi/L");
+        assert_eq!(formatted(UnitProfile::METRIC.radon(148)), "148 Bq/m3");
+    }
+}
@@ -0,0 +1,277 @@
+//! USB CDC-ACM serial console, available while the Badger is on USB power.
+//!
+//! Commands are parsed by [`crate::console`], this module handles the line editing and runs them.
+use core::fmt::Write;
+use embassy_futures::join::join;
+use embassy_rp::peripherals::USB;
+use embassy_rp::usb::{Driver, InterruptHandler as USBInterruptHandler};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_time::Duration;
+use embassy_usb::class::cdc_acm::{CdcAcmClass, State};
+use embassy_usb::driver::EndpointError;
+use embassy_usb::Builder;
+use heapless::String;
+
+use crate::badger::BadgerScd4x;
+use crate::ble::{BleMetricsSource, BleSession};
+use crate::config::{Config, ConfigKey};
+use crate::console::{self, Command, ParseError};
+use crate::platform::{MetricsSource, Storage};
+use crate::scd4x::Scd4xSource;
+use crate::{history, timing};
+
+embassy_rp::bind_interrupts!(struct Irqs {
+    USBCTRL_IRQ => USBInterruptHandler<USB>;
+});
+
+const MAX_PACKET_SIZE: u16 = 64;
+const PROMPT: &str = "> ";
+
+/// What the console commands operate on, shared with the desk mode
+pub struct Console<'a, S: Storage> {
+    pub config: &'a Mutex<NoopRawMutex, Config>,
+    pub storage: &'a Mutex<NoopRawMutex, S>,
+    pub ble: &'a Mutex<NoopRawMutex, BleSession>,
+    pub scd4x: &'a Mutex<NoopRawMutex, BadgerScd4x>,
+    /// Sleep between the cycles on battery, see [`crate::app::CycleConfig::interval`]
+    pub interval: core::time::Duration,
+}
+
+/// Runs the console forever. Nothing happens until a host opens the serial port
+pub async fn run<S: Storage>(usb: USB, mut console: Console<'_, S>) -> ! {
+    let driver = Driver::new(usb, Irqs);
+    let mut usb_config = embassy_usb::Config::new(0x2e8a, 0x000a);
+    usb_config.manufacturer = Some("trawm");
+    usb_config.product = Some("trawm console");
+    usb_config.max_power = 100;
+    usb_config.max_packet_size_0 = MAX_PACKET_SIZE as u8;
+
+    let mut config_descriptor = [0; 256];
+    let mut bos_descriptor = [0; 256];
+    let mut control_buf = [0; 64];
+    let mut state = State::new();
+    let mut builder = Builder::new(
+        driver,
+        usb_config,
+        &mut config_descriptor,
+        &mut bos_descriptor,
+        &mut [],
+        &mut control_buf,
+    );
+    let mut class = CdcAcmClass::new(&mut builder, &mut state, MAX_PACKET_SIZE);
+    let mut device = builder.build();
+
+    let serve = async {
+        loop {
+            class.wait_connection().await;
+            defmt::info!("Console connected");
+            let _ = session(&mut class, &mut console).await;
+            defmt::info!("Console disconnected");
+        }
+    };
+    join(device.run(), serve).await;
+    unreachable!()
+}
+
+async fn session<'d, D: embassy_usb::driver::Driver<'d>, S: Storage>(
+    class: &mut CdcAcmClass<'d, D>,
+    console: &mut Console<'_, S>,
+) -> Result<(), EndpointError> {
+    write_str(class, "trawm console, type 'help' for the commands\r\n").await?;
+    write_str(class, PROMPT).await?;
+    let mut line: String<128> = String::new();
+    let mut packet = [0; MAX_PACKET_SIZE as usize];
+    loop {
+        let n = class.read_packet(&mut packet).await?;
+        for &c in &packet[..n] {
+            match c {
+                b'\r' | b'\n' => {
+                    write_str(class, "\r\n").await?;
+                    execute(class, console, &line).await?;
+                    line.clear();
+                    write_str(class, PROMPT).await?;
+                }
+                // Backspace and delete
+                0x08 | 0x7f => {
+                    if line.pop().is_some() {
+                        write_str(class, "\x08 \x08").await?;
+                    }
+                }
+                0x20..0x7f => {
+                    if line.push(c as char).is_ok() {
+                        class.write_packet(&[c]).await?;
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+async fn execute<'d, D: embassy_usb::driver::Driver<'d>, S: Storage>(
+    class: &mut CdcAcmClass<'d, D>,
+    console: &mut Console<'_, S>,
+    line: &str,
+) -> Result<(), EndpointError> {
+    let mut out: String<256> = String::new();
+    match console::parse(line) {
+        Ok(Command::Help) => return write_str(class, console::HELP).await,
+        Ok(Command::Get(Some(key))) => {
+            let _ = console.config.lock().await.get(key, &mut out);
+            let _ = out.push_str("\r\n");
+        }
+        Ok(Command::Get(None)) => {
+            let config = console.config.lock().await.clone();
+            for key in ConfigKey::ALL {
+                out.clear();
+                let _ = write!(out, "{} = ", key.name());
+                let _ = config.get(key, &mut out);
+                let _ = out.push_str("\r\n");
+                write_str(class, &out).await?;
+            }
+            return Ok(());
+        }
+        Ok(Command::Set(key, value)) => {
+            let mut config = console.config.lock().await;
+            let mut updated = config.clone();
+            match updated.set(key, value) {
+                Ok(()) => match updated.save(&mut console.storage).await {
+                    Ok(()) => {
+                        *config = updated;
+                        let _ = out.push_str("saved\r\n");
+                    }
+                    Err(e) => {
+                        defmt::error!("Config save failed: {:?}", defmt::Debug2Format(&e));
+                        let _ = out.push_str("storage error\r\n");
+                    }
+                },
+                Err(_) => {
+                    let _ = write!(out, "invalid value for {}\r\n", key.name());
+                }
+            }
+        }
+        Ok(Command::Scan) => {
+            write_str(class, "scanning...\r\n").await?;
+            let serial = console.config.lock().await.airthings_serial;
+            let found = console
+                .ble
+                .lock()
+                .await
+                .scan(Duration::from_secs(10), serial)
+                .await;
+            for device in &found {
+                out.clear();
+                let a = device.addr;
+                let _ = write!(
+                    out,
+                    "{:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X} {} dBm ",
+                    a[5], a[4], a[3], a[2], a[1], a[0], device.rssi
+                );
+                let _ = match device.serial {
+                    Some(serial) => write!(out, "serial {}", serial),
+                    None => write!(out, "serial unknown"),
+                };
+                let _ = write!(out, " {}\r\n", device.profile.name());
+                write_str(class, &out).await?;
+            }
+            out.clear();
+            let _ = write!(out, "{} found\r\n", found.len());
+        }
+        Ok(Command::Read) => {
+            write_str(class, "reading...\r\n").await?;
+            let (units, serial) = {
+                let config = console.config.lock().await;
+                (config.units, config.airthings_serial)
+            };
+            let mut source = BleMetricsSource::new(console.ble, Duration::from_secs(10));
+            source.set_serial(serial);
+            let result = source.fetch().await;
+            if let (Ok(_), Some(link)) = (&result, source.link_quality()) {
+                let _ = write!(out, "signal: {}\r\n", link);
+                write_str(class, &out).await?;
+                out.clear();
+            }
+            let _ = match result {
+                Ok(measurements) => write!(out, "{}\r\n", measurements.display_with(&units)),
+                Err(e) => write!(out, "failed: {}\r\n", e),
+            };
+        }
+        Ok(Command::HistoryDump) => {
+            let _ = history::write_csv_header(&mut out);
+            write_str(class, &out).await?;
+            for index in 0.. {
+                out.clear();
+                match history::read(&mut console.storage, index).await {
+                    Ok(Some(reading)) => {
+                        let _ = history::write_csv_row(&mut out, index, &reading);
+                        write_str(class, &out).await?;
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        defmt::error!("History read failed: {:?}", defmt::Debug2Format(&e));
+                        return write_str(class, "storage error\r\n").await;
+                    }
+                }
+            }
+            return Ok(());
+        }
+        Ok(Command::TimingsDump) => {
+            let currents = console.config.lock().await.currents;
+            let interval = console.interval;
+            let _ = timing::write_csv_header(&mut out);
+            write_str(class, &out).await?;
+            for index in 0.. {
+                out.clear();
+                match timing::read(&mut console.storage, index).await {
+                    Ok(Some(timings)) => {
+                        let mah = currents.estimate(&timings, interval);
+                        let _ = timing::write_csv_row(&mut out, index, &timings, mah);
+                        write_str(class, &out).await?;
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        defmt::error!("Timings read failed: {:?}", defmt::Debug2Format(&e));
+                        return write_str(class, "storage error\r\n").await;
+                    }
+                }
+            }
+            return Ok(());
+        }
+        Ok(Command::Scd4xSelfCalibration(enabled)) => {
+            let _ = match Scd4xSource::new(console.scd4x).configure(enabled).await {
+                Ok(()) => write!(out, "saved\r\n"),
+                Err(e) => write!(out, "failed: {}\r\n", e),
+            };
+        }
+        Ok(Command::Scd4xRecalibrate(ppm)) => {
+            write_str(class, "measuring for 3 minutes...\r\n").await?;
+            let _ = match Scd4xSource::new(console.scd4x).recalibrate(ppm).await {
+                Ok(correction) => write!(out, "corrected by {} ppm\r\n", correction),
+                Err(e) => write!(out, "failed: {}\r\n", e),
+            };
+        }
+        Ok(Command::Reboot) => cortex_m::peripheral::SCB::sys_reset(),
+        Ok(Command::Bootsel) => embassy_rp::rom_data::reset_to_usb_boot(0, 0),
+        Err(ParseError::Empty) => (),
+        Err(e) => {
+            let _ = write!(out, "{}\r\n", e);
+        }
+    }
+    write_str(class, &out).await
+}
+
+/// Writes the text in packet sized chunks
+async fn write_str<'d, D: embassy_usb::driver::Driver<'d>>(
+    class: &mut CdcAcmClass<'d, D>,
+    text: &str,
+) -> Result<(), EndpointError> {
+    for chunk in text.as_bytes().chunks(MAX_PACKET_SIZE as usize) {
+        class.write_packet(chunk).await?;
+    }
+    // A full last packet has to be followed by a short one to be delivered
+    if text.len() % MAX_PACKET_SIZE as usize == 0 && !text.is_empty() {
+        class.write_packet(&[]).await?;
+    }
+    Ok(())
+}
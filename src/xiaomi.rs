@@ -0,0 +1,147 @@
+//! Xiaomi LYWSD03MMC thermometers running the custom firmware of ATC1441 or pvvx, see
+//! <https://github.com/pvvx/ATC_MiThermometer#bluetooth-advertising-formats>.
+//! pvvx can broadcast in the BTHome v2 format instead, read for any other BTHome thermometer too
+use crate::metrics::{AirMetrics, ParseMetricsError};
+
+/// Environmental Sensing service, carrying the ATC1441 and pvvx custom formats
+pub const ENVIRONMENTAL_SENSING_UUID: u16 = 0x181a;
+/// <https://bthome.io/format/>
+pub const BTHOME_UUID: u16 = 0xfcd2;
+
+pub const ATC_PACKET_LEN: usize = 13;
+pub const PVVX_PACKET_LEN: usize = 15;
+
+/// Version 2, unencrypted, in the top bits of the device information byte
+const BTHOME_V2: u8 = 2 << 5;
+const BTHOME_ENCRYPTED: u8 = 1;
+
+/// Decoded broadcast, the fields some formats don't have are `None`
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+pub struct ThermometerData {
+    /// °C
+    pub temperature: f32,
+    /// Percent
+    pub humidity: f32,
+    /// Percent
+    pub battery: Option<u8>,
+    pub battery_mv: Option<u16>,
+    /// Incremented with each measurement, to tell the repeated broadcasts apart
+    pub counter: Option<u8>,
+}
+
+impl ThermometerData {
+    /// Parses the Environmental Sensing service data, telling the formats apart by their length
+    pub fn from_service_data(bytes: &[u8]) -> Result<Self, ParseMetricsError> {
+        match bytes.len() {
+            ATC_PACKET_LEN => Self::from_atc_bytes(bytes),
+            PVVX_PACKET_LEN.. => Self::from_pvvx_bytes(bytes),
+            len => Err(ParseMetricsError::InsufficientBytes { len }),
+        }
+    }
+
+    /// ATC1441 format: big endian MAC, temperature in 0.1 °C, humidity and battery in percent,
+    /// battery voltage and frame counter
+    pub fn from_atc_bytes(bytes: &[u8]) -> Result<Self, ParseMetricsError> {
+        if bytes.len() < ATC_PACKET_LEN {
+            return Err(ParseMetricsError::InsufficientBytes { len: bytes.len() });
+        }
+        Ok(Self {
+            temperature: i16::from_be_bytes([bytes[6], bytes[7]]) as f32 / 10.0,
+            humidity: bytes[8] as f32,
+            battery: Some(bytes[9]),
+            battery_mv: Some(u16::from_be_bytes([bytes[10], bytes[11]])),
+            counter: Some(bytes[12]),
+        })
+    }
+
+    /// pvvx custom format: little endian MAC, temperature and humidity in hundredths,
+    /// battery voltage and percent, measurement counter and flags
+    pub fn from_pvvx_bytes(bytes: &[u8]) -> Result<Self, ParseMetricsError> {
+        if bytes.len() < PVVX_PACKET_LEN {
+            return Err(ParseMetricsError::InsufficientBytes { len: bytes.len() });
+        }
+        Ok(Self {
+            temperature: i16::from_le_bytes([bytes[6], bytes[7]]) as f32 / 100.0,
+            humidity: u16::from_le_bytes([bytes[8], bytes[9]]) as f32 / 100.0,
+            battery: Some(bytes[12]),
+            battery_mv: Some(u16::from_le_bytes([bytes[10], bytes[11]])),
+            counter: Some(bytes[13]),
+        })
+    }
+
+    /// BTHome v2 service data: the device information byte, then objects made of an id and
+    /// a value whose size depends on the id. Unknown ids end the parsing, their size is unknown
+    pub fn from_bthome_bytes(bytes: &[u8]) -> Result<Self, ParseMetricsError> {
+        let Some((info, mut objects)) = bytes.split_first() else {
+            return Err(ParseMetricsError::InsufficientBytes { len: 0 });
+        };
+        if info & (0b111 << 5) != BTHOME_V2 || info & BTHOME_ENCRYPTED != 0 {
+            return Err(ParseMetricsError::UnsupportedPacketVersion { version: *info });
+        }
+        let (mut temperature, mut humidity) = (None, None);
+        let (mut battery, mut battery_mv, mut counter) = (None, None, None);
+        while let [id, rest @ ..] = objects {
+            let Some(len) = bthome_object_len(*id) else {
+                break;
+            };
+            let Some(value) = rest.get(..len) else {
+                break;
+            };
+            let int = |signed: bool| match (value, signed) {
+                ([lo, hi], false) => u16::from_le_bytes([*lo, *hi]) as f32,
+                ([lo, hi], true) => i16::from_le_bytes([*lo, *hi]) as f32,
+                _ => value[0] as f32,
+            };
+            match id {
+                0x00 => counter = Some(value[0]),
+                0x01 => battery = Some(value[0]),
+                0x02 => temperature = Some(int(true) / 100.0),
+                0x03 => humidity = Some(int(false) / 100.0),
+                0x0c => battery_mv = Some(int(false) as u16),
+                0x2e => humidity = Some(int(false)),
+                0x45 => temperature = Some(int(true) / 10.0),
+                _ => (),
+            }
+            objects = &rest[len..];
+        }
+        Ok(Self {
+            temperature: temperature.ok_or(ParseMetricsError::MissingReadings)?,
+            humidity: humidity.ok_or(ParseMetricsError::MissingReadings)?,
+            battery,
+            battery_mv,
+            counter,
+        })
+    }
+}
+
+/// Size of the value of the BTHome sensor objects a thermometer may send
+fn bthome_object_len(id: u8) -> Option<usize> {
+    match id {
+        // Packet id, battery, count, binary sensors, humidity and moisture in percent, buttons
+        0x00 | 0x01 | 0x09 | 0x0f..=0x11 | 0x2e | 0x2f | 0x3a => Some(1),
+        // Temperatures, humidity, mass, dew point, voltage, particulates, CO2, VOC, moisture
+        0x02 | 0x03 | 0x06..=0x08 | 0x0c..=0x0e | 0x12..=0x14 | 0x45 => Some(2),
+        // Pressure, illuminance, energy, power
+        0x04 | 0x05 | 0x0a | 0x0b => Some(3),
+        _ => None,
+    }
+}
+
+/// Kept like the Airthings readings, without the sensors the thermometers don't have
+impl From<ThermometerData> for AirMetrics {
+    fn from(data: ThermometerData) -> Self {
+        Self {
+            version: 1,
+            humidity: data.humidity,
+            illuminance: None,
+            radon_short: None,
+            radon_long: None,
+            temperature: data.temperature,
+            pressure: None,
+            co2_level: None,
+            voc_level: None,
+            battery: data.battery,
+            update_interval: None,
+        }
+    }
+}
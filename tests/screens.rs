@@ -0,0 +1,39 @@
+//! Snapshot tests comparing every screen of [`trawm::samples`] with the PBM images in
+//! `tests/golden`. After an intended layout change, check the simulator output and
+//! rerun with `UPDATE_GOLDENS=1` to replace them.
+
+use std::fs;
+use std::path::Path;
+
+use trawm::framebuffer::Framebuffer;
+use trawm::samples::SCREENS;
+use trawm::screens::{HEIGHT, WIDTH};
+
+fn pbm(fb: &Framebuffer) -> Vec<u8> {
+    let mut image = format!("P4\n{} {}\n", WIDTH, HEIGHT).into_bytes();
+    image.extend_from_slice(fb.as_pbm_data());
+    image
+}
+
+#[test]
+fn screens_match_goldens() {
+    let golden_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+    let update = std::env::var_os("UPDATE_GOLDENS").is_some();
+    let mut mismatched = Vec::new();
+    for screen in SCREENS {
+        let mut fb = Framebuffer::new();
+        let Ok(()) = (screen.draw)(&mut fb);
+        let rendered = pbm(&fb);
+        let path = golden_dir.join(screen.name).with_extension("pbm");
+        if update {
+            fs::write(&path, &rendered).unwrap();
+        } else if fs::read(&path).ok().as_ref() != Some(&rendered) {
+            mismatched.push(screen.name);
+        }
+    }
+    assert!(
+        mismatched.is_empty(),
+        "screens differ from the goldens: {:?}",
+        mismatched
+    );
+}